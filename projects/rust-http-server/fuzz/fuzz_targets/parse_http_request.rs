@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::io::BufReader;
+
+use libfuzzer_sys::fuzz_target;
+use rust_http_server::request::parse_http_request;
+
+fuzz_target!(|raw: &[u8]| {
+    let _ = parse_http_request(&mut BufReader::new(raw));
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_http_server::request::HttpRequestMetaData;
+
+fuzz_target!(|raw: &str| {
+    if let Ok(mut metadata) = HttpRequestMetaData::parse(raw) {
+        // The helpers handlers call on parsed metadata must not panic either.
+        let _ = metadata.content_length();
+        let _ = metadata.body_framing();
+        let _ = metadata.expects_continue();
+        let _ = metadata.query_param("q");
+        let _ = metadata.cookies();
+        let _ = metadata.preferred_type(&["text/html", "application/json"]);
+        metadata.normalize_target();
+    }
+});
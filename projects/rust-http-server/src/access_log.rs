@@ -0,0 +1,423 @@
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use log::error;
+use serde_json::json;
+
+use crate::{
+    common::{civil_from_days, unix_seconds, HttpMethod, HttpProtocol, HttpStatus},
+    request::HttpRequestMetaData,
+    request_id::REQUEST_ID_HEADER,
+};
+
+// =========================================================
+// ================== AccessLogFormat ======================
+// =========================================================
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccessLogFormat {
+    /// NCSA Common Log Format.
+    Common,
+    /// Common Log Format followed by the quoted referer and user-agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!("unknown access log format: {s}")),
+        }
+    }
+}
+
+// =========================================================
+// ================== AccessLogRecord ======================
+// =========================================================
+#[derive(Debug, Clone)]
+pub struct AccessLogRecord {
+    pub client: Option<SocketAddr>,
+    pub method: Option<HttpMethod>,
+    pub uri: Option<String>,
+    pub protocol: Option<HttpProtocol>,
+    pub status: HttpStatus,
+    pub size: usize,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub latency: Duration,
+    pub time: SystemTime,
+}
+
+impl AccessLogRecord {
+    /// Creates a record for a response to `metadata`. When the request could not be parsed
+    /// `metadata` is `None` and the request fields are logged as `-`.
+    pub fn new(
+        client: Option<SocketAddr>,
+        metadata: Option<&HttpRequestMetaData>,
+        status: HttpStatus,
+        size: usize,
+        latency: Duration,
+    ) -> Self {
+        AccessLogRecord {
+            client,
+            method: metadata.map(|m| m.method),
+            uri: metadata.map(|m| m.uri.clone()),
+            protocol: metadata.map(|m| m.protocol),
+            status,
+            size,
+            referer: metadata.and_then(|m| m.header("Referer")).map(String::from),
+            user_agent: metadata
+                .and_then(|m| m.header("User-Agent"))
+                .map(String::from),
+            request_id: metadata
+                .and_then(|m| m.header(REQUEST_ID_HEADER))
+                .map(String::from),
+            latency,
+            time: SystemTime::now(),
+        }
+    }
+
+    fn client_str(&self) -> String {
+        match self.client {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    fn request_line(&self) -> String {
+        match (&self.method, &self.uri, &self.protocol) {
+            (Some(m), Some(u), Some(p)) => format!("{} {} {}", m, u, p),
+            _ => "-".to_string(),
+        }
+    }
+
+    fn size_str(&self) -> String {
+        match self.size {
+            0 => "-".to_string(),
+            s => s.to_string(),
+        }
+    }
+
+    fn format_common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{}\" {} {}",
+            self.client_str(),
+            clf_timestamp(self.time),
+            escape_quoted(&self.request_line()),
+            self.status as u16,
+            self.size_str()
+        )
+    }
+
+    fn format_combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.format_common(),
+            escape_quoted(self.referer.as_deref().unwrap_or("-")),
+            escape_quoted(self.user_agent.as_deref().unwrap_or("-"))
+        )
+    }
+
+    fn format_json(&self) -> String {
+        json!({
+            "time": unix_seconds(self.time),
+            "client": self.client.map(|a| a.ip().to_string()),
+            "method": self.method.map(|m| m.to_string()),
+            "uri": self.uri,
+            "protocol": self.protocol.map(|p| p.to_string()),
+            "status": self.status as u16,
+            "size": self.size,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "request_id": self.request_id,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+        })
+        .to_string()
+    }
+
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.format_common(),
+            AccessLogFormat::Combined => self.format_combined(),
+            AccessLogFormat::Json => self.format_json(),
+        }
+    }
+}
+
+impl Display for AccessLogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format_combined())
+    }
+}
+
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Formats `time` as a CLF timestamp in UTC, e.g. `10/Oct/2000:13:55:36 +0000`.
+fn clf_timestamp(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = unix_seconds(time);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// =========================================================
+// ================= RotatingFileWriter ====================
+// =========================================================
+
+/// Appends to a file and rotates it once it grows past `max_bytes`. Rotated files are
+/// renamed to `<path>.1` .. `<path>.<max_files>`, the oldest one being dropped.
+pub struct RotatingFileWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFileWriter {
+    pub fn new<P: AsRef<Path>>(path: P, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFileWriter {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+// =========================================================
+// ===================== AccessLog =========================
+// =========================================================
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<RotatingFileWriter>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, writer: RotatingFileWriter) -> Self {
+        AccessLog {
+            format,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn log(&self, record: &AccessLogRecord) {
+        let line = record.format(self.format);
+        let mut writer = match self.writer.lock() {
+            Ok(w) => w,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writer.write_line(&line) {
+            error!("AccessLog: cannot write access log record -> error: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, time::UNIX_EPOCH};
+
+    fn test_record() -> AccessLogRecord {
+        let metadata = HttpRequestMetaData::parse(
+            "GET /index.html HTTP/1.1\n\
+            Host: localhost\n\
+            Referer: http://example.com/\n\
+            User-Agent: curl/8.0\n\
+            X-Request-ID: 01ARZ3NDEKTSV4RRFFQ69G5FAV",
+        )
+        .unwrap();
+        let mut record = AccessLogRecord::new(
+            Some("127.0.0.1:5000".parse().unwrap()),
+            Some(&metadata),
+            HttpStatus::Ok,
+            1234,
+            Duration::from_millis(5),
+        );
+        record.time = UNIX_EPOCH + Duration::from_secs(971182536);
+        record
+    }
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-http-server-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_access_log_format_from_str() {
+        assert_eq!(
+            "common".parse::<AccessLogFormat>().unwrap(),
+            AccessLogFormat::Common
+        );
+        assert_eq!(
+            "combined".parse::<AccessLogFormat>().unwrap(),
+            AccessLogFormat::Combined
+        );
+        assert_eq!(
+            "json".parse::<AccessLogFormat>().unwrap(),
+            AccessLogFormat::Json
+        );
+        assert!("xml".parse::<AccessLogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_clf_timestamp() {
+        let t = UNIX_EPOCH + Duration::from_secs(971182536);
+        assert_eq!(clf_timestamp(t), "10/Oct/2000:12:55:36 +0000");
+        assert_eq!(clf_timestamp(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[test]
+    fn test_access_log_record_common() {
+        assert_eq!(
+            test_record().format(AccessLogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] \"GET /index.html HTTP/1.1\" 200 1234"
+        );
+    }
+
+    #[test]
+    fn test_access_log_record_combined() {
+        assert_eq!(
+            test_record().format(AccessLogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] \"GET /index.html HTTP/1.1\" 200 1234 \
+            \"http://example.com/\" \"curl/8.0\""
+        );
+    }
+
+    #[test]
+    fn test_access_log_record_unparsed_request() {
+        let mut record = AccessLogRecord::new(
+            None,
+            None,
+            HttpStatus::BadRequest,
+            0,
+            Duration::from_millis(1),
+        );
+        record.time = UNIX_EPOCH;
+        assert_eq!(
+            record.format(AccessLogFormat::Combined),
+            "- - - [01/Jan/1970:00:00:00 +0000] \"-\" 400 - \"-\" \"-\""
+        );
+    }
+
+    #[test]
+    fn test_access_log_record_json() {
+        let line = test_record().format(AccessLogFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["client"], "127.0.0.1");
+        assert_eq!(value["method"], "GET");
+        assert_eq!(value["uri"], "/index.html");
+        assert_eq!(value["protocol"], "HTTP/1.1");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["size"], 1234);
+        assert_eq!(value["referer"], "http://example.com/");
+        assert_eq!(value["user_agent"], "curl/8.0");
+        assert_eq!(value["request_id"], "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert_eq!(value["latency_ms"], 5.0);
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn test_rotating_file_writer_rotates_by_size() {
+        let path = temp_log_path("access-rotate.log");
+        let mut writer = RotatingFileWriter::new(&path, 16, 2).unwrap();
+        for line in ["0123456789", "abcdefghij", "ABCDEFGHIJ", "klmnopqrst"] {
+            writer.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "klmnopqrst\n");
+        assert_eq!(
+            fs::read_to_string(writer.rotated_path(1)).unwrap(),
+            "ABCDEFGHIJ\n"
+        );
+        assert_eq!(
+            fs::read_to_string(writer.rotated_path(2)).unwrap(),
+            "abcdefghij\n"
+        );
+        assert!(!writer.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn test_access_log_writes_lines() {
+        let path = temp_log_path("access-lines.log");
+        let log = AccessLog::new(
+            AccessLogFormat::Common,
+            RotatingFileWriter::new(&path, 0, 0).unwrap(),
+        );
+        log.log(&test_record());
+        log.log(&test_record());
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.lines().all(|l| l.ends_with("200 1234")));
+    }
+}
@@ -0,0 +1,1005 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use log::debug;
+use md5::{Digest, Md5};
+use ring::{digest, hmac, signature};
+use rustls::pki_types::{pem::PemObject, SubjectPublicKeyInfoDer};
+use serde_json::Value;
+
+use crate::{
+    common::{HttpError, HttpServerContext, HttpStatus},
+    request::HttpRequest,
+    response::HttpResponse,
+    router::HttpRouterFunc,
+};
+
+/// Context keys holding the authenticated principal. Path parameter names cannot contain a
+/// dot, so they never collide with these.
+pub const PRINCIPAL_KEY: &str = "auth.principal";
+pub const AUTH_SCHEME_KEY: &str = "auth.scheme";
+pub const ROLES_KEY: &str = "auth.roles";
+pub const CLAIMS_KEY: &str = "auth.claims";
+
+/// Compares two byte strings in time independent of where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the credentials of the `Authorization` header when it uses `scheme`.
+pub(crate) fn authorization<'a>(req: &'a HttpRequest, scheme: &str) -> Option<&'a str> {
+    let (found, credentials) = req.metadata.header("Authorization")?.split_once(' ')?;
+    match found.eq_ignore_ascii_case(scheme) {
+        true => Some(credentials.trim()),
+        false => None,
+    }
+}
+
+// =========================================================
+// ==================== HttpPrincipal ======================
+// =========================================================
+/// The authenticated caller of a request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpPrincipal {
+    pub name: String,
+    /// The scheme that authenticated the caller: `basic`, `bearer` or `api-key`.
+    pub scheme: String,
+    pub roles: Vec<String>,
+    /// The claims of the token, for principals authenticated with a JWT.
+    pub claims: Option<Value>,
+}
+
+impl HttpPrincipal {
+    pub fn new(name: &str, scheme: &str) -> Self {
+        HttpPrincipal {
+            name: name.to_string(),
+            scheme: scheme.to_string(),
+            ..HttpPrincipal::default()
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Returns a copy of `params` carrying the principal, as passed to the handlers.
+    fn with_context(&self, params: &HttpServerContext) -> HttpServerContext {
+        let mut params = params.clone();
+        params.insert(PRINCIPAL_KEY.to_string(), self.name.clone());
+        params.insert(AUTH_SCHEME_KEY.to_string(), self.scheme.clone());
+        params.insert(
+            ROLES_KEY.to_string(),
+            serde_json::to_string(&self.roles).unwrap(),
+        );
+        if let Some(claims) = self.claims.as_ref() {
+            params.insert(CLAIMS_KEY.to_string(), claims.to_string());
+        }
+        params
+    }
+}
+
+/// Access to the principal authenticated by [`Auth`] from the handlers.
+pub trait HttpAuthContext {
+    fn principal(&self) -> Option<HttpPrincipal>;
+}
+
+impl HttpAuthContext for HttpServerContext {
+    fn principal(&self) -> Option<HttpPrincipal> {
+        Some(HttpPrincipal {
+            name: self.get(PRINCIPAL_KEY)?.clone(),
+            scheme: self.get(AUTH_SCHEME_KEY).cloned().unwrap_or_default(),
+            roles: self
+                .get(ROLES_KEY)
+                .and_then(|r| serde_json::from_str(r).ok())
+                .unwrap_or_default(),
+            claims: self
+                .get(CLAIMS_KEY)
+                .and_then(|c| serde_json::from_str(c).ok()),
+        })
+    }
+}
+
+// =========================================================
+// ==================== Authenticator ======================
+// =========================================================
+/// Why presented credentials were refused.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum AuthError {
+    #[error("malformed credentials: {0}")]
+    Malformed(String),
+    #[error("invalid credentials")]
+    Invalid,
+    #[error("token expired")]
+    Expired,
+    #[error("token rejected: {0}")]
+    Rejected(String),
+}
+
+/// A way of authenticating requests, such as HTTP Basic or API keys.
+pub trait Authenticator: Send + Sync {
+    /// Returns `Ok(None)` when the request carries no credentials for this authenticator.
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<HttpPrincipal>, AuthError>;
+    /// The `WWW-Authenticate` challenge, given the error when this authenticator refused
+    /// the credentials of the request.
+    fn challenge(&self, error: Option<&AuthError>) -> String;
+}
+
+// =========================================================
+// ====================== BasicAuth ========================
+// =========================================================
+const APR1_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// The Apache `$apr1$` variant of the MD5 crypt password hash.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    let mut ctx = Md5::new()
+        .chain_update(password)
+        .chain_update(b"$apr1$")
+        .chain_update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        ctx.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        match i & 1 {
+            1 => ctx.update([0u8]),
+            _ => ctx.update(&password[..1]),
+        }
+        i >>= 1;
+    }
+    let mut hash = ctx.finalize();
+    for round in 0..1000 {
+        let mut ctx = Md5::new();
+        match round & 1 {
+            1 => ctx.update(password),
+            _ => ctx.update(hash),
+        }
+        if round % 3 != 0 {
+            ctx.update(salt);
+        }
+        if round % 7 != 0 {
+            ctx.update(password);
+        }
+        match round & 1 {
+            1 => ctx.update(hash),
+            _ => ctx.update(password),
+        }
+        hash = ctx.finalize();
+    }
+    let mut encoded = String::new();
+    let mut push = |value: u32, chars: usize| {
+        for i in 0..chars {
+            encoded.push(APR1_ALPHABET[((value >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            ((hash[a] as u32) << 16) | ((hash[b] as u32) << 8) | hash[c] as u32,
+            4,
+        );
+    }
+    push(hash[11] as u32, 2);
+    format!("$apr1${}${encoded}", String::from_utf8_lossy(salt))
+}
+
+/// Checks `password` against an htpasswd hash: bcrypt, `$apr1$` MD5 or `{SHA}`.
+fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(salted) = hash.strip_prefix("$apr1$") {
+        let salt = salted.split('$').next().unwrap_or("");
+        constant_time_eq(
+            apr1(password.as_bytes(), salt.as_bytes()).as_bytes(),
+            hash.as_bytes(),
+        )
+    } else if let Some(encoded) = hash.strip_prefix("{SHA}") {
+        let expected = STANDARD.decode(encoded).unwrap_or_default();
+        let actual = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        constant_time_eq(actual.as_ref(), &expected)
+    } else {
+        false
+    }
+}
+
+/// HTTP Basic authentication against the users of an htpasswd file.
+#[derive(Clone, Debug)]
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, String>,
+}
+
+impl BasicAuth {
+    /// Parses htpasswd `user:hash` lines. Only bcrypt, `$apr1$` and `{SHA}` hashes are
+    /// supported, as `crypt(3)` and plain text passwords are not safe to accept.
+    pub fn parse_htpasswd(realm: &str, contents: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {}: expected `user:hash`", i + 1))?;
+            if !["$2a$", "$2b$", "$2y$", "$apr1$", "{SHA}"]
+                .iter()
+                .any(|prefix| hash.starts_with(prefix))
+            {
+                return Err(format!(
+                    "line {}: unsupported password hash for `{user}`",
+                    i + 1
+                ));
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(BasicAuth {
+            realm: realm.to_string(),
+            users,
+        })
+    }
+
+    pub fn from_htpasswd<P: AsRef<Path>>(realm: &str, path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::parse_htpasswd(realm, &contents).map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<HttpPrincipal>, AuthError> {
+        let credentials = match authorization(req, "Basic") {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let decoded = STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|d| String::from_utf8(d).ok())
+            .ok_or_else(|| AuthError::Malformed("invalid base64".to_string()))?;
+        let (user, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| AuthError::Malformed("expected `user:password`".to_string()))?;
+        match self.users.get(user) {
+            Some(hash) if verify_password(hash, password) => {
+                Ok(Some(HttpPrincipal::new(user, "basic")))
+            }
+            _ => Err(AuthError::Invalid),
+        }
+    }
+
+    fn challenge(&self, _: Option<&AuthError>) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+}
+
+// =========================================================
+// ======================= JwtAuth =========================
+// =========================================================
+/// The key verifying the signature of JSON web tokens.
+#[derive(Clone, Debug)]
+pub enum JwtKey {
+    Hs256(Vec<u8>),
+    /// The PKCS#1 `RSAPublicKey` of the signer.
+    Rs256(Vec<u8>),
+}
+
+/// Reads a DER element, returning its tag, its contents and the bytes following it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&len, mut input) = input.split_first()?;
+    let len = match len {
+        len if len < 0x80 => len as usize,
+        len => {
+            let count = (len & 0x7f) as usize;
+            if count == 0 || count > 4 || input.len() < count {
+                return None;
+            }
+            let (bytes, rest) = input.split_at(count);
+            input = rest;
+            bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize)
+        }
+    };
+    (input.len() >= len).then(|| (tag, &input[..len], &input[len..]))
+}
+
+impl JwtKey {
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtKey::Hs256(secret.to_vec())
+    }
+
+    /// Reads an RSA public key from a PEM `PUBLIC KEY` block, as written by
+    /// `openssl pkey -pubout`.
+    pub fn rs256_pem(pem: &str) -> Result<Self, String> {
+        const RSA_ENCRYPTION: &[u8] = &[
+            0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+        ];
+        let spki = SubjectPublicKeyInfoDer::from_pem_slice(pem.as_bytes())
+            .map_err(|e| format!("invalid public key: {e}"))?;
+        let key = der_element(spki.as_ref())
+            .filter(|(tag, _, _)| *tag == 0x30)
+            .and_then(|(_, spki, _)| der_element(spki))
+            .filter(|(tag, algorithm, _)| *tag == 0x30 && algorithm.starts_with(RSA_ENCRYPTION))
+            .and_then(|(_, _, rest)| der_element(rest))
+            .filter(|(tag, bits, _)| *tag == 0x03 && bits.first() == Some(&0))
+            .map(|(_, bits, _)| bits[1..].to_vec())
+            .ok_or_else(|| "not an RSA public key".to_string())?;
+        Ok(JwtKey::Rs256(key))
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            JwtKey::Hs256(_) => "HS256",
+            JwtKey::Rs256(_) => "RS256",
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            JwtKey::Hs256(secret) => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+                hmac::verify(&key, message, signature).is_ok()
+            }
+            JwtKey::Rs256(public_key) => signature::UnparsedPublicKey::new(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                public_key,
+            )
+            .verify(message, signature)
+            .is_ok(),
+        }
+    }
+}
+
+/// Bearer authentication with JSON web tokens signed with HS256 or RS256.
+///
+/// The token must be signed with the algorithm of the key, must not be expired or used
+/// before its `nbf` date, and must carry a `sub` claim naming the principal. Roles are read
+/// from a `roles` array or a space separated `scope` claim.
+#[derive(Clone, Debug)]
+pub struct JwtAuth {
+    realm: String,
+    key: JwtKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    claims: Vec<(String, Value)>,
+    leeway: Duration,
+}
+
+impl JwtAuth {
+    pub fn new(realm: &str, key: JwtKey) -> Self {
+        JwtAuth {
+            realm: realm.to_string(),
+            key,
+            issuer: None,
+            audience: None,
+            claims: Vec::new(),
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Requires `audience` in the `aud` claim, a string or an array of strings.
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Requires the claim `name` to equal `value`.
+    pub fn require_claim(mut self, name: &str, value: Value) -> Self {
+        self.claims.push((name.to_string(), value));
+        self
+    }
+
+    /// Clock skew tolerated on the `exp` and `nbf` claims, a minute by default.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Verifies `token` at `now`, in seconds since the unix epoch, and returns its claims.
+    fn verify(&self, token: &str, now: f64) -> Result<Value, AuthError> {
+        let malformed = |reason: &str| AuthError::Malformed(reason.to_string());
+        let mut parts = token.split('.');
+        let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            (Some(h), Some(p), Some(s), None) => (h, p, s),
+            _ => return Err(malformed("expected three segments")),
+        };
+        let decode = |segment: &str| {
+            URL_SAFE_NO_PAD
+                .decode(segment)
+                .map_err(|_| malformed("invalid base64url"))
+        };
+        let header: Value =
+            serde_json::from_slice(&decode(header)?).map_err(|_| malformed("invalid header"))?;
+        if header["alg"] != self.key.algorithm() {
+            return Err(AuthError::Rejected(format!(
+                "unexpected algorithm {}",
+                header["alg"]
+            )));
+        }
+        let signed = &token[..token.len() - sig.len() - 1];
+        if !self.key.verify(signed.as_bytes(), &decode(sig)?) {
+            return Err(AuthError::Invalid);
+        }
+        let claims: Value =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| malformed("invalid claims"))?;
+        let leeway = self.leeway.as_secs_f64();
+        match claims["exp"].as_f64() {
+            Some(exp) if exp + leeway <= now => return Err(AuthError::Expired),
+            Some(_) => {}
+            None => return Err(AuthError::Rejected("missing exp claim".to_string())),
+        }
+        if claims["nbf"].as_f64().is_some_and(|nbf| nbf - leeway > now) {
+            return Err(AuthError::Rejected("token not valid yet".to_string()));
+        }
+        if let Some(issuer) = self.issuer.as_ref() {
+            if claims["iss"] != issuer.as_str() {
+                return Err(AuthError::Rejected("unexpected issuer".to_string()));
+            }
+        }
+        if let Some(audience) = self.audience.as_ref() {
+            let accepted = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud == audience.as_str()),
+                _ => false,
+            };
+            if !accepted {
+                return Err(AuthError::Rejected("unexpected audience".to_string()));
+            }
+        }
+        if let Some((name, _)) = self.claims.iter().find(|(name, v)| claims[name] != *v) {
+            return Err(AuthError::Rejected(format!("unexpected {name} claim")));
+        }
+        if !claims["sub"].is_string() {
+            return Err(AuthError::Rejected("missing sub claim".to_string()));
+        }
+        Ok(claims)
+    }
+}
+
+impl Authenticator for JwtAuth {
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<HttpPrincipal>, AuthError> {
+        let token = match authorization(req, "Bearer") {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let claims = self.verify(token, now)?;
+        let mut principal = HttpPrincipal::new(claims["sub"].as_str().unwrap_or(""), "bearer");
+        principal.roles = match (&claims["roles"], &claims["scope"]) {
+            (Value::Array(roles), _) => roles
+                .iter()
+                .filter_map(|r| r.as_str().map(String::from))
+                .collect(),
+            (_, Value::String(scope)) => scope.split_whitespace().map(String::from).collect(),
+            _ => Vec::new(),
+        };
+        principal.claims = Some(claims);
+        Ok(Some(principal))
+    }
+
+    fn challenge(&self, error: Option<&AuthError>) -> String {
+        match error {
+            Some(e) => format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                self.realm,
+                e.to_string().replace('"', "'")
+            ),
+            None => format!("Bearer realm=\"{}\"", self.realm),
+        }
+    }
+}
+
+// =========================================================
+// ====================== ApiKeyAuth =======================
+// =========================================================
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiKeyLocation {
+    Header(String),
+    Query(String),
+}
+
+/// Authentication with static API keys, sent in a header or a query parameter.
+#[derive(Clone, Debug)]
+pub struct ApiKeyAuth {
+    location: ApiKeyLocation,
+    keys: Vec<(String, HttpPrincipal)>,
+}
+
+impl ApiKeyAuth {
+    pub fn header(name: &str) -> Self {
+        ApiKeyAuth {
+            location: ApiKeyLocation::Header(name.to_string()),
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn query(name: &str) -> Self {
+        ApiKeyAuth {
+            location: ApiKeyLocation::Query(name.to_string()),
+            keys: Vec::new(),
+        }
+    }
+
+    /// Accepts `key` as the credentials of `principal`.
+    pub fn key(mut self, key: &str, principal: &str, roles: &[&str]) -> Self {
+        let mut principal = HttpPrincipal::new(principal, "api-key");
+        principal.roles = roles.iter().map(|r| r.to_string()).collect();
+        self.keys.push((key.to_string(), principal));
+        self
+    }
+}
+
+impl Authenticator for ApiKeyAuth {
+    fn authenticate(&self, req: &HttpRequest) -> Result<Option<HttpPrincipal>, AuthError> {
+        let presented = match &self.location {
+            ApiKeyLocation::Header(name) => req.metadata.header(name).map(String::from),
+            ApiKeyLocation::Query(name) => req.metadata.query_param(name),
+        };
+        let presented = match presented {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        // Every key is compared so the time taken does not reveal which one matched.
+        self.keys
+            .iter()
+            .fold(None, |found, (key, principal)| {
+                match constant_time_eq(key.as_bytes(), presented.as_bytes()) {
+                    true => Some(principal.clone()),
+                    false => found,
+                }
+            })
+            .map(Some)
+            .ok_or(AuthError::Invalid)
+    }
+
+    fn challenge(&self, _: Option<&AuthError>) -> String {
+        match &self.location {
+            ApiKeyLocation::Header(name) => format!("ApiKey header=\"{name}\""),
+            ApiKeyLocation::Query(name) => format!("ApiKey query=\"{name}\""),
+        }
+    }
+}
+
+// =========================================================
+// ========================= Auth ==========================
+// =========================================================
+type HttpAuthorizer = dyn Fn(&HttpPrincipal) -> bool + Send + Sync;
+
+/// Authentication middleware trying each authenticator in turn.
+///
+/// Requests without credentials, or with refused ones, get `401 Unauthorized` with a
+/// `WWW-Authenticate` challenge per authenticator. Authenticated principals the
+/// authorization check refuses get `403 Forbidden`. Handlers read the principal with
+/// [`HttpAuthContext::principal`].
+#[derive(Clone, Default)]
+pub struct Auth {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    authorize: Option<Arc<HttpAuthorizer>>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth::default()
+    }
+
+    pub fn with<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    /// Only lets through the principals `authorize` returns true for.
+    pub fn authorize<F>(mut self, authorize: F) -> Self
+    where
+        F: Fn(&HttpPrincipal) -> bool + Send + Sync + 'static,
+    {
+        self.authorize = Some(Arc::new(authorize));
+        self
+    }
+
+    pub fn require_role(self, role: &str) -> Self {
+        let role = role.to_string();
+        self.authorize(move |principal| principal.has_role(&role))
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+        next: &HttpRouterFunc,
+    ) -> Result<HttpResponse, HttpError> {
+        next(req, &self.check(req, params)?)
+    }
+
+    /// Authenticates and authorizes `req` without calling a handler, returning `params` with
+    /// the principal added.
+    pub fn check(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> Result<HttpServerContext, HttpError> {
+        let mut refused = None;
+        for (i, authenticator) in self.authenticators.iter().enumerate() {
+            match authenticator.authenticate(req) {
+                Ok(Some(principal)) => {
+                    if self.authorize.as_ref().is_some_and(|a| !a(&principal)) {
+                        debug!(
+                            "Auth: {} is not allowed on {}",
+                            principal.name, req.metadata.uri
+                        );
+                        return Err(HttpError::new(
+                            HttpStatus::Forbidden,
+                            format!("{} is not allowed", principal.name),
+                        ));
+                    }
+                    return Ok(principal.with_context(params));
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("Auth: credentials refused on {} -> {e}", req.metadata.uri);
+                    refused = Some((i, e));
+                    break;
+                }
+            }
+        }
+        let challenges = self
+            .authenticators
+            .iter()
+            .enumerate()
+            .map(|(i, authenticator)| {
+                let error = refused.as_ref().filter(|(j, _)| *j == i).map(|(_, e)| e);
+                authenticator.challenge(error)
+            })
+            .collect::<Vec<String>>();
+        let message = match refused {
+            Some((_, e)) => e.to_string(),
+            None => "authentication required".to_string(),
+        };
+        let error = HttpError::new(HttpStatus::Unauthorized, message);
+        Err(match challenges.is_empty() {
+            true => error,
+            false => error.with_header("WWW-Authenticate", &challenges.join(", ")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{HttpHeaders, HttpMethod},
+        request::HttpRequestMetaData,
+        router::{HttpRouter, HttpRouterBuilder},
+    };
+    use ring::{rand::SystemRandom, signature::RsaKeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut raw = format!("GET {uri} HTTP/1.1\nHost: localhost\nAccept: */*\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\n"));
+        }
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    fn router(auth: Auth) -> HttpRouter {
+        HttpRouterBuilder::new()
+            .add_auth(auth)
+            .add_route(HttpMethod::GET, "/users/:id", |r, c| {
+                let principal = c.principal().unwrap();
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some(format!(
+                        "{} {} {}",
+                        principal.name, principal.scheme, c["id"]
+                    )),
+                ))
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn sign(key: &JwtKey, claims: Value) -> String {
+        let header = json!({ "alg": key.algorithm(), "typ": "JWT" });
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = match key {
+            JwtKey::Hs256(secret) => hmac::sign(
+                &hmac::Key::new(hmac::HMAC_SHA256, secret),
+                signed.as_bytes(),
+            )
+            .as_ref()
+            .to_vec(),
+            JwtKey::Rs256(_) => {
+                let der = PrivatePkcs8KeyDer::from_pem_slice(
+                    include_str!("../res/test_jwt_rs256.key").as_bytes(),
+                )
+                .unwrap();
+                let pair = RsaKeyPair::from_pkcs8(der.secret_pkcs8_der()).unwrap();
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(
+                    &signature::RSA_PKCS1_SHA256,
+                    &SystemRandom::new(),
+                    signed.as_bytes(),
+                    &mut signature,
+                )
+                .unwrap();
+                signature
+            }
+        };
+        format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn now() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+    }
+
+    #[test]
+    fn test_verify_password() {
+        assert_eq!(
+            apr1(b"hunter2", b"sAlT1234"),
+            "$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0"
+        );
+        assert!(verify_password(
+            "$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0",
+            "hunter2"
+        ));
+        assert!(!verify_password(
+            "$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0",
+            "hunter3"
+        ));
+        assert!(verify_password(
+            "{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=",
+            "hunter2"
+        ));
+        assert!(!verify_password("{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=", ""));
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        assert!(verify_password(&bcrypt, "hunter2"));
+        assert!(!verify_password(&bcrypt, "hunter3"));
+        assert!(!verify_password("hunter2", "hunter2"));
+    }
+
+    #[test]
+    fn test_parse_htpasswd() {
+        let auth = BasicAuth::parse_htpasswd(
+            "admin",
+            "# users\n\
+            alice:$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0\n\n\
+            bob:{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=\n",
+        )
+        .unwrap();
+        assert_eq!(auth.users.len(), 2);
+        assert_eq!(
+            BasicAuth::parse_htpasswd("admin", "alice:hunter2")
+                .err()
+                .unwrap(),
+            "line 1: unsupported password hash for `alice`"
+        );
+        assert!(BasicAuth::parse_htpasswd("admin", "alice").is_err());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let basic =
+            BasicAuth::parse_htpasswd("admin", "alice:$apr1$sAlT1234$EUBqB9L/ejufQD8VOcfvE0")
+                .unwrap();
+        let router = router(Auth::new().with(basic));
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+
+        let r = request("/users/7", &[("Authorization", &basic("alice:hunter2"))]);
+        assert_eq!(router.route(&r).unwrap().body.unwrap(), "alice basic 7");
+        let m = router.find_route(&r).unwrap();
+        assert!(m.guards.iter().all(|guard| guard(&r, &m.params).is_ok()));
+        let r = request("/users/7", &[]);
+        let m = router.find_route(&r).unwrap();
+        assert_eq!(
+            (m.guards[0])(&r, &m.params).err().unwrap().status,
+            HttpStatus::Unauthorized
+        );
+
+        for credentials in [
+            basic("alice:wrong"),
+            basic("mallory:hunter2"),
+            basic("alice"),
+        ] {
+            let r = request("/users/7", &[("Authorization", &credentials)]);
+            let e = router.route(&r).err().unwrap();
+            assert_eq!(e.status, HttpStatus::Unauthorized);
+            assert_eq!(
+                e.headers["WWW-Authenticate"],
+                "Basic realm=\"admin\", charset=\"UTF-8\""
+            );
+        }
+        let e = router.route(&request("/users/7", &[])).err().unwrap();
+        assert_eq!(e.status, HttpStatus::Unauthorized);
+    }
+
+    #[test]
+    fn test_jwt_verify_claims() {
+        let jwt = JwtAuth::new("api", JwtKey::hs256(SECRET))
+            .issuer("https://auth.example.com")
+            .audience("api")
+            .require_claim("tenant", json!("acme"));
+        let now = now();
+        let claims = json!({
+            "sub": "alice", "iss": "https://auth.example.com", "aud": ["web", "api"],
+            "tenant": "acme", "exp": now + 300.0, "nbf": now - 10.0,
+        });
+        let token = sign(&jwt.key, claims.clone());
+        assert_eq!(jwt.verify(&token, now).unwrap()["sub"], "alice");
+
+        let with = |changes: Value| {
+            let mut claims = claims.clone();
+            for (k, v) in changes.as_object().unwrap() {
+                claims[k] = v.clone();
+            }
+            jwt.verify(&sign(&jwt.key, claims), now).err().unwrap()
+        };
+        assert_eq!(with(json!({ "exp": now - 61.0 })), AuthError::Expired);
+        // Expired within the leeway is still accepted.
+        let mut late = claims.clone();
+        late["exp"] = json!(now - 30.0);
+        assert!(jwt.verify(&sign(&jwt.key, late), now).is_ok());
+        for changes in [
+            json!({ "nbf": now + 600.0 }),
+            json!({ "iss": "https://evil.example.com" }),
+            json!({ "aud": "web" }),
+            json!({ "tenant": "other" }),
+            json!({ "sub": null }),
+            json!({ "exp": null }),
+        ] {
+            assert!(matches!(with(changes), AuthError::Rejected(_)));
+        }
+
+        let forged = sign(&JwtKey::hs256(b"another secret"), claims.clone());
+        assert_eq!(jwt.verify(&forged, now).err().unwrap(), AuthError::Invalid);
+        let mut tampered = token.split('.').map(String::from).collect::<Vec<_>>();
+        tampered[1] = URL_SAFE_NO_PAD.encode(json!({ "sub": "root" }).to_string());
+        assert_eq!(
+            jwt.verify(&tampered.join("."), now).err().unwrap(),
+            AuthError::Invalid
+        );
+        let none = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        assert!(matches!(
+            jwt.verify(&none, now).err().unwrap(),
+            AuthError::Rejected(_)
+        ));
+        assert!(matches!(
+            jwt.verify("a.b", now).err().unwrap(),
+            AuthError::Malformed(_)
+        ));
+    }
+
+    #[test]
+    fn test_jwt_rs256() {
+        let key = JwtKey::rs256_pem(include_str!("../res/test_jwt_rs256.pub")).unwrap();
+        let jwt = JwtAuth::new("api", key);
+        let token = sign(
+            &jwt.key,
+            json!({ "sub": "svc", "exp": now() + 60.0, "scope": "read write" }),
+        );
+        let principal = jwt
+            .authenticate(&request(
+                "/",
+                &[("Authorization", &format!("Bearer {token}"))],
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(principal.name, "svc");
+        assert_eq!(principal.roles, ["read", "write"]);
+        // An HS256 token signed with the public key must not pass as RS256.
+        let confused = sign(
+            &JwtKey::hs256(include_str!("../res/test_jwt_rs256.pub").as_bytes()),
+            json!({ "sub": "svc", "exp": now() + 60.0 }),
+        );
+        assert!(jwt.verify(&confused, now()).is_err());
+        assert!(
+            JwtKey::rs256_pem("-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_auth_bearer_roles_and_forbidden() {
+        let jwt = JwtAuth::new("api", JwtKey::hs256(SECRET));
+        let router = router(Auth::new().with(jwt.clone()).require_role("admin"));
+        let bearer = |roles: Value| {
+            let token = sign(
+                &jwt.key,
+                json!({ "sub": "alice", "exp": now() + 60.0, "roles": roles }),
+            );
+            request("/users/1", &[("Authorization", &format!("Bearer {token}"))])
+        };
+        assert_eq!(
+            router
+                .route(&bearer(json!(["admin"])))
+                .unwrap()
+                .body
+                .unwrap(),
+            "alice bearer 1"
+        );
+        assert_eq!(
+            router.route(&bearer(json!(["user"]))).err().unwrap().status,
+            HttpStatus::Forbidden
+        );
+        let e = router
+            .route(&request("/users/1", &[("Authorization", "Bearer nope")]))
+            .err()
+            .unwrap();
+        assert_eq!(e.status, HttpStatus::Unauthorized);
+        assert!(e.headers["WWW-Authenticate"].starts_with(
+            "Bearer realm=\"api\", error=\"invalid_token\", error_description=\"malformed"
+        ));
+    }
+
+    #[test]
+    fn test_auth_api_keys_and_context() {
+        let auth = Auth::new()
+            .with(ApiKeyAuth::header("X-API-Key").key("k-123", "ci", &["deploy"]))
+            .with(ApiKeyAuth::query("api_key").key("q-456", "dashboard", &[]));
+        let router = router(auth);
+        let r = request("/users/2", &[("X-Api-Key", "k-123")]);
+        assert_eq!(router.route(&r).unwrap().body.unwrap(), "ci api-key 2");
+        let r = request("/users/2?api_key=q-456", &[]);
+        assert_eq!(
+            router.route(&r).unwrap().body.unwrap(),
+            "dashboard api-key 2"
+        );
+        let e = router
+            .route(&request("/users/2", &[("X-API-Key", "wrong")]))
+            .err()
+            .unwrap();
+        assert_eq!(e.status, HttpStatus::Unauthorized);
+        assert_eq!(
+            e.headers["WWW-Authenticate"],
+            "ApiKey header=\"X-API-Key\", ApiKey query=\"api_key\""
+        );
+
+        let mut principal = HttpPrincipal::new("ci", "api-key");
+        principal.roles = vec!["deploy".to_string()];
+        principal.claims = Some(json!({ "sub": "ci" }));
+        let context = principal.with_context(&HttpServerContext::new());
+        assert_eq!(context.principal().unwrap(), principal);
+        assert!(HttpServerContext::new().principal().is_none());
+    }
+}
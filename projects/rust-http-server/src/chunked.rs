@@ -0,0 +1,117 @@
+use std::io::{self, BufRead, Read};
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`, yielding the payload bytes and
+/// stopping at the terminating zero sized chunk. Trailer fields are read and discarded.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.inner.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chunked body ended unexpectedly",
+            ));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        usize::from_str_radix(size, 16).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid chunk size: {size}"),
+            )
+        })
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size()?;
+            if self.remaining == 0 {
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "chunked body ended unexpectedly",
+            ));
+        }
+        self.remaining -= read;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing CRLF after chunk data",
+            ));
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn decode(raw: &str) -> io::Result<String> {
+        let mut out = String::new();
+        ChunkedReader::new(BufReader::new(raw.as_bytes())).read_to_string(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_chunked_reader_decodes_chunks() {
+        assert_eq!(
+            decode("4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n")
+                .unwrap(),
+            "Wikipedia in \r\n\r\nchunks."
+        );
+    }
+
+    #[test]
+    fn test_chunked_reader_skips_trailers_and_stops() {
+        let raw = "3\r\nfoo\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut reader = ChunkedReader::new(BufReader::new(raw.as_bytes()));
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "foo");
+        let mut rest = String::new();
+        reader.into_inner().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn test_chunked_reader_errors() {
+        assert!(decode("zz\r\nfoo\r\n0\r\n\r\n").is_err());
+        assert!(decode("5\r\nfoo").is_err());
+        assert!(decode("3\r\nfooXX0\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::config::{AccessLogSection, Config};
+
+/// A small HTTP/1.1 server.
+///
+/// Settings are read from the TOML file given with `--config`; the flags below take
+/// precedence over the values of the file.
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the TOML configuration file.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, may be repeated. Replaces `server.bind`.
+    #[arg(short, long)]
+    pub bind: Vec<String>,
+    /// Number of worker threads.
+    #[arg(short, long)]
+    pub workers: Option<usize>,
+    /// Log level (`error`, `warn`, `info`, `debug`, `trace` or `off`).
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// File to write the access log to.
+    #[arg(long)]
+    pub access_log: Option<PathBuf>,
+    /// Maximum accepted request body size in bytes.
+    #[arg(long)]
+    pub max_body_bytes: Option<usize>,
+    /// Validate the configuration and exit.
+    #[arg(long)]
+    pub check: bool,
+}
+
+impl Cli {
+    /// Applies the flags given on the command line on top of `config`.
+    pub fn apply(&self, config: &mut Config) {
+        if !self.bind.is_empty() {
+            config.server.bind = self.bind.clone();
+        }
+        if let Some(workers) = self.workers {
+            config.server.workers = Some(workers);
+        }
+        if let Some(level) = self.log_level.as_ref() {
+            config.logging.level = level.clone();
+        }
+        if let Some(path) = self.access_log.as_ref() {
+            match config.logging.access_log.as_mut() {
+                Some(access_log) => access_log.path = path.clone(),
+                None => config.logging.access_log = Some(AccessLogSection::new(path.clone())),
+            }
+        }
+        if let Some(max) = self.max_body_bytes {
+            config.limits.max_body_bytes = Some(max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_CONFIG;
+
+    #[test]
+    fn test_cli_overrides_config() {
+        let cli = Cli::try_parse_from([
+            "rust-http-server",
+            "--config",
+            "server.toml",
+            "-b",
+            "0.0.0.0:80",
+            "--bind",
+            "[::]:80",
+            "--workers",
+            "16",
+            "--log-level",
+            "debug",
+            "--access-log",
+            "access.log",
+        ])
+        .unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("server.toml")));
+        let mut config = Config::parse(DEFAULT_CONFIG).unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.server.bind, ["0.0.0.0:80", "[::]:80"]);
+        assert_eq!(config.server.workers, Some(16));
+        assert_eq!(config.logging.level, "debug");
+        let access_log = config.logging.access_log.unwrap();
+        assert_eq!(access_log.path, PathBuf::from("access.log"));
+        assert_eq!(access_log.format, "combined");
+    }
+
+    #[test]
+    fn test_cli_without_flags_keeps_config() {
+        let cli = Cli::try_parse_from(["rust-http-server"]).unwrap();
+        let mut config =
+            Config::parse("[server]\nbind = [\"127.0.0.1:1\"]\nworkers = 3\n").unwrap();
+        cli.apply(&mut config);
+        assert_eq!(config.server.bind, ["127.0.0.1:1"]);
+        assert_eq!(config.server.workers, Some(3));
+        assert!(config.logging.access_log.is_none());
+    }
+}
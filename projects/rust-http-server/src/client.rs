@@ -0,0 +1,815 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::{
+    common::{HttpHeaders, HttpMethod, HttpProtocol, HttpStatus},
+    request::{HttpRequest, HttpRequestMetaData},
+    response::{read_response_body, read_response_head, HttpResponse, HttpResponseMetaData},
+};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 4;
+
+#[derive(thiserror::Error, Debug)]
+pub enum HttpClientError {
+    #[error("invalid url {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("request has no Host header")]
+    MissingHost,
+    #[error("cannot connect to {host}: {source}")]
+    Connect { host: String, source: io::Error },
+    #[error("{host} timed out")]
+    Timeout { host: String },
+    #[error("malformed response: {0}")]
+    InvalidResponse(String),
+    #[error("stopped after {0} redirects")]
+    TooManyRedirects(usize),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HttpClientError {
+    /// Whether the error shows a pooled connection was closed by the server while idle. Only
+    /// meaningful for errors raised before any byte of the response was read.
+    fn is_stale_connection(&self) -> bool {
+        matches!(self, HttpClientError::Io(e) if matches!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+        ))
+    }
+
+    fn on_host(self, host: &str) -> Self {
+        match self {
+            HttpClientError::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                HttpClientError::Timeout {
+                    host: host.to_string(),
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+// =========================================================
+// ======================== HttpUrl ========================
+// =========================================================
+
+/// An `http://` url split into the `host:port` to connect to and the request target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpUrl {
+    pub authority: String,
+    pub target: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, HttpClientError> {
+        let invalid = |reason: &str| HttpClientError::InvalidUrl {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some((scheme, _)) => return Err(invalid(&format!("unsupported scheme {scheme}"))),
+            None => return Err(invalid("missing scheme")),
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let target = target.split('#').next().unwrap_or("/").to_string();
+        if authority.is_empty() || authority.contains(['@', ' ']) {
+            return Err(invalid("malformed host"));
+        }
+        let authority = match authority.rsplit_once(':') {
+            Some(("", _)) => return Err(invalid("malformed host")),
+            Some((_, port)) if !authority.ends_with(']') => match port.parse::<u16>() {
+                Ok(_) => authority.to_string(),
+                Err(_) => return Err(invalid("malformed port")),
+            },
+            _ => format!("{authority}:80"),
+        };
+        Ok(HttpUrl { authority, target })
+    }
+
+    /// Resolves the `Location` of a redirect answering a request for `target` on `authority`.
+    fn resolve(location: &str, authority: &str, target: &str) -> Result<HttpUrl, HttpClientError> {
+        if location.contains("://") {
+            return HttpUrl::parse(location);
+        }
+        let target = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let path = target.split('?').next().unwrap_or("/");
+            format!("{}{location}", &path[..=path.rfind('/').unwrap_or(0)])
+        };
+        Ok(HttpUrl {
+            authority: authority.to_string(),
+            target,
+        })
+    }
+}
+
+// =========================================================
+// ================== Request and response =================
+// =========================================================
+
+fn header_value<'a>(headers: &'a HttpHeaders, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn has_token(headers: &HttpHeaders, name: &str, token: &str) -> bool {
+    header_value(headers, name)
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Writes `request` to `writer`, adding the `Content-Length` of its body when missing. The
+/// body is sent as UTF-8, a stream set on the request is not sent.
+pub fn write_request<W: Write>(writer: &mut W, request: &HttpRequest) -> io::Result<()> {
+    let mut metadata = request.metadata.clone();
+    let body = request.body.as_deref().unwrap_or("");
+    if header_value(&metadata.headers, "Content-Length").is_none()
+        && header_value(&metadata.headers, "Transfer-Encoding").is_none()
+        && (request.body.is_some() || matches!(metadata.method, HttpMethod::POST | HttpMethod::PUT))
+    {
+        metadata
+            .headers
+            .insert("Content-Length".to_string(), body.len().to_string());
+    }
+    writer.write_all(format!("{metadata}\r\n").as_bytes())?;
+    writer.write_all(body.as_bytes())?;
+    writer.flush()
+}
+
+/// Reads a response to a request off `reader`, skipping interim `1xx` responses. The body is
+/// read as described in [`read_response_body`] and decoded into the body of the response.
+///
+/// Returns the response along with whether the connection can carry another request.
+pub fn read_response<R: Read>(
+    reader: &mut BufReader<R>,
+) -> Result<(HttpResponse, bool), HttpClientError> {
+    let metadata = loop {
+        let head = read_response_head(reader)?;
+        // `HttpStatus` has no informational statuses, they are told apart by their code.
+        let code = head.split(' ').nth(1).unwrap_or("");
+        if code.len() == 3 && code.starts_with('1') {
+            continue;
+        }
+        break HttpResponseMetaData::parse(&head)
+            .map_err(|e| HttpClientError::InvalidResponse(e.message().to_string()))?;
+    };
+    let headers = &metadata.headers;
+    let keep_alive = match metadata.protocol {
+        HttpProtocol::Http1_1 => !has_token(headers, "Connection", "close"),
+        HttpProtocol::Http1 => has_token(headers, "Connection", "keep-alive"),
+    };
+    let (body, delimited) = read_response_body(reader, &metadata)?;
+    let body = match metadata.status {
+        HttpStatus::NoContent | HttpStatus::NotModified => None,
+        _ => Some(String::from_utf8_lossy(&body).into_owned()),
+    };
+    Ok((
+        HttpResponse::new(metadata.protocol, metadata.status, metadata.headers, body),
+        keep_alive && delimited,
+    ))
+}
+
+// =========================================================
+// ======================= HttpClient ======================
+// =========================================================
+
+type PooledConnection = BufReader<TcpStream>;
+
+/// A blocking HTTP/1.1 client keeping connections alive between requests to the same host
+/// and following redirects.
+///
+/// ```ignore
+/// let client = HttpClient::new().with_read_timeout(Duration::from_secs(2));
+/// let response = client.get("http://127.0.0.1:8080/health")?;
+/// ```
+///
+/// Clones share their idle connections.
+#[derive(Clone)]
+pub struct HttpClient {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    max_redirects: usize,
+    max_idle_per_host: usize,
+    idle: Arc<Mutex<HashMap<String, Vec<PooledConnection>>>>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        HttpClient {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+    /// Sets the socket read and write timeouts, a response taking longer between two reads
+    /// fails with [`HttpClientError::Timeout`].
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+    /// Follows at most `max_redirects` redirects, `0` returns redirect responses as is.
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+    /// Keeps at most `max_idle` connections per host open for later requests, `0` closes
+    /// every connection after its response.
+    pub fn with_max_idle_per_host(mut self, max_idle: usize) -> Self {
+        self.max_idle_per_host = max_idle;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> Result<HttpResponse, HttpClientError> {
+        self.request(HttpMethod::GET, url, None)
+    }
+    pub fn post(&self, url: &str, body: &str) -> Result<HttpResponse, HttpClientError> {
+        self.request(HttpMethod::POST, url, Some(body.to_string()))
+    }
+    pub fn request(
+        &self,
+        method: HttpMethod,
+        url: &str,
+        body: Option<String>,
+    ) -> Result<HttpResponse, HttpClientError> {
+        let url = HttpUrl::parse(url)?;
+        let mut headers = HttpHeaders::new();
+        headers.insert("Host".to_string(), host_header(&url.authority));
+        let request = HttpRequest {
+            metadata: HttpRequestMetaData {
+                protocol: HttpProtocol::Http1_1,
+                uri: url.target,
+                method,
+                headers,
+            },
+            body,
+            stream: None,
+        };
+        self.send_to(&url.authority, request)
+    }
+
+    /// Sends `request` to the host named by its `Host` header.
+    pub fn send(&self, request: HttpRequest) -> Result<HttpResponse, HttpClientError> {
+        let host = request
+            .metadata
+            .header("Host")
+            .ok_or(HttpClientError::MissingHost)?;
+        let authority = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+            _ => format!("{host}:80"),
+        };
+        self.send_to(&authority, request)
+    }
+
+    /// Sends `request` to `authority`, a `host:port`, whatever its `Host` header.
+    pub fn send_to(
+        &self,
+        authority: &str,
+        mut request: HttpRequest,
+    ) -> Result<HttpResponse, HttpClientError> {
+        let mut authority = authority.to_string();
+        let mut redirects = 0;
+        loop {
+            let response = self.exchange(&authority, &request)?;
+            let next = match self.max_redirects {
+                0 => None,
+                _ => redirect(&authority, &request, &response)?,
+            };
+            match next {
+                None => return Ok(response),
+                Some(_) if redirects == self.max_redirects => {
+                    return Err(HttpClientError::TooManyRedirects(redirects))
+                }
+                Some((next_authority, next_request)) => {
+                    debug!(
+                        "HttpClient: following redirect to {next_authority}{}",
+                        next_request.metadata.uri
+                    );
+                    (authority, request) = (next_authority, next_request);
+                    redirects += 1;
+                }
+            }
+        }
+    }
+
+    /// Sends `request` on an idle connection to `authority`, or on a new one when there is
+    /// none or the idle one turns out to be closed. Only idempotent requests are sent again,
+    /// and only when the closed connection did not answer any byte.
+    fn exchange(
+        &self,
+        authority: &str,
+        request: &HttpRequest,
+    ) -> Result<HttpResponse, HttpClientError> {
+        if let Some(mut conn) = self.take_idle(authority) {
+            match send_on(&mut conn, request) {
+                Ok(()) => {
+                    return self
+                        .receive_on(authority, conn, request)
+                        .map_err(|e| e.on_host(authority))
+                }
+                Err(e) if e.is_stale_connection() && request.metadata.method.is_idempotent() => {
+                    debug!("HttpClient: idle connection to {authority} was closed -> {e}")
+                }
+                Err(e) => return Err(e.on_host(authority)),
+            }
+        }
+        let mut conn = BufReader::new(self.connect(authority)?);
+        send_on(&mut conn, request)
+            .and_then(|()| self.receive_on(authority, conn, request))
+            .map_err(|e| e.on_host(authority))
+    }
+
+    fn receive_on(
+        &self,
+        authority: &str,
+        mut conn: PooledConnection,
+        request: &HttpRequest,
+    ) -> Result<HttpResponse, HttpClientError> {
+        let (response, reusable) = read_response(&mut conn)?;
+        let closing = has_token(&request.metadata.headers, "Connection", "close");
+        if reusable && !closing {
+            self.release(authority, conn);
+        }
+        Ok(response)
+    }
+
+    fn connect(&self, authority: &str) -> Result<TcpStream, HttpClientError> {
+        let connect_error = |source| HttpClientError::Connect {
+            host: authority.to_string(),
+            source,
+        };
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cannot resolve {authority}"),
+        );
+        for addr in authority.to_socket_addrs().map_err(connect_error)? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(s) => {
+                    s.set_read_timeout(Some(self.read_timeout))?;
+                    s.set_write_timeout(Some(self.read_timeout))?;
+                    return Ok(s);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(connect_error(last_error))
+    }
+
+    fn take_idle(&self, authority: &str) -> Option<PooledConnection> {
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(authority)
+            .and_then(Vec::pop)
+    }
+
+    fn release(&self, authority: &str, conn: PooledConnection) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let conns = idle.entry(authority.to_string()).or_default();
+        if conns.len() < self.max_idle_per_host {
+            conns.push(conn);
+        }
+    }
+
+    /// Returns the number of idle connections kept open to `authority`.
+    pub fn idle_connections(&self, authority: &str) -> usize {
+        self.idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(authority)
+            .map_or(0, Vec::len)
+    }
+}
+
+/// Writes `request` to `conn` and waits for the first byte of the response, a connection
+/// closed before answering fails with [`io::ErrorKind::UnexpectedEof`].
+fn send_on(conn: &mut PooledConnection, request: &HttpRequest) -> Result<(), HttpClientError> {
+    write_request(conn.get_mut(), request)?;
+    if conn.fill_buf()?.is_empty() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// The `Host` header naming `authority`, without the default port.
+fn host_header(authority: &str) -> String {
+    authority
+        .strip_suffix(":80")
+        .unwrap_or(authority)
+        .to_string()
+}
+
+/// Builds the request following `response` when it is a redirect. `303 See Other`, and
+/// `301`/`302` answering a `POST`, are followed with a `GET` without body; credentials are
+/// dropped when the redirect leaves `authority`.
+fn redirect(
+    authority: &str,
+    request: &HttpRequest,
+    response: &HttpResponse,
+) -> Result<Option<(String, HttpRequest)>, HttpClientError> {
+    let as_get = match response.metadata.status {
+        HttpStatus::SeeOther => true,
+        HttpStatus::MovedPermanently | HttpStatus::Found => {
+            request.metadata.method == HttpMethod::POST
+        }
+        HttpStatus::TemporaryRedirect | HttpStatus::PermanentRedirect => false,
+        _ => return Ok(None),
+    };
+    let Some(location) = header_value(&response.metadata.headers, "Location") else {
+        return Ok(None);
+    };
+    let url = HttpUrl::resolve(location, authority, &request.metadata.uri)?;
+    let mut next = HttpRequest {
+        stream: None,
+        ..request.clone()
+    };
+    let headers = &mut next.metadata.headers;
+    if as_get {
+        next.metadata.method = HttpMethod::GET;
+        next.body = None;
+        headers.retain(|k, _| {
+            !["Content-Length", "Content-Type", "Transfer-Encoding"]
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(k))
+        });
+    }
+    if url.authority != authority {
+        headers.retain(|k, _| {
+            !["Authorization", "Cookie"]
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(k))
+        });
+        headers.retain(|k, _| !k.eq_ignore_ascii_case("Host"));
+        headers.insert("Host".to_string(), host_header(&url.authority));
+    }
+    next.metadata.uri = url.target;
+    Ok(Some((url.authority, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::BufRead,
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use crate::{
+        request::parse_http_request, router::HttpRouterBuilder, server::HttpServer,
+        testing::TestServer,
+    };
+
+    /// Starts a server answering with the next of `responses` in turn, closing connections
+    /// without notice after `per_connection` requests, and returns its address along with the
+    /// number of connections it accepted.
+    fn keep_alive_upstream(
+        responses: &'static [&'static str],
+        per_connection: usize,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        thread::spawn(move || {
+            let mut next = 0;
+            for stream in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.unwrap());
+                for _ in 0..per_connection {
+                    if parse_http_request(&mut reader).is_err() {
+                        break;
+                    }
+                    let response = responses[next % responses.len()];
+                    next += 1;
+                    if reader.get_mut().write_all(response.as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        (addr, connections)
+    }
+
+    #[test]
+    fn test_parse_urls() {
+        let url = HttpUrl::parse("http://example.com/a?b=1#top").unwrap();
+        assert_eq!(url.authority, "example.com:80");
+        assert_eq!(url.target, "/a?b=1");
+        let url = HttpUrl::parse("HTTP://127.0.0.1:8080?x").unwrap();
+        assert_eq!(url.authority, "127.0.0.1:8080");
+        assert_eq!(url.target, "/?x");
+        assert_eq!(
+            HttpUrl::parse("http://[::1]").unwrap().authority,
+            "[::1]:80"
+        );
+        for url in [
+            "https://example.com",
+            "example.com/a",
+            "http://:8080/",
+            "http://a:x/",
+        ] {
+            assert!(HttpUrl::parse(url).is_err(), "{url}");
+        }
+        let resolve = |location| HttpUrl::resolve(location, "a:80", "/x/y?q").unwrap();
+        assert_eq!(resolve("/z").target, "/z");
+        assert_eq!(resolve("z?1").target, "/x/z?1");
+        assert_eq!(resolve("http://b/").authority, "b:80");
+    }
+
+    #[test]
+    fn test_write_request() {
+        let mut request = HttpRequest {
+            metadata: HttpRequestMetaData::parse("POST /a HTTP/1.1\nHost: x\nUser-Agent: t\n")
+                .unwrap(),
+            body: Some("héllo".to_string()),
+            stream: None,
+        };
+        let mut raw = Vec::new();
+        write_request(&mut raw, &request).unwrap();
+        assert_eq!(
+            String::from_utf8(raw).unwrap(),
+            "POST /a HTTP/1.1\r\nContent-Length: 6\r\nHost: x\r\nUser-Agent: t\r\n\r\nhéllo"
+        );
+        request.metadata.method = HttpMethod::GET;
+        request.body = None;
+        let mut raw = Vec::new();
+        write_request(&mut raw, &request).unwrap();
+        assert_eq!(raw, b"GET /a HTTP/1.1\r\nHost: x\r\nUser-Agent: t\r\n\r\n");
+    }
+
+    #[test]
+    fn test_read_response_framing() {
+        let read = |raw: &'static str| {
+            let mut reader = BufReader::new(raw.as_bytes());
+            let (response, reusable) = read_response(&mut reader).unwrap();
+            let mut rest = String::new();
+            reader.read_line(&mut rest).unwrap();
+            (response, reusable, rest)
+        };
+        let (response, reusable, rest) =
+            read("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcNEXT");
+        assert_eq!(response.body.as_deref(), Some("abc"));
+        assert!(reusable);
+        assert_eq!(rest, "NEXT");
+
+        let (response, reusable, rest) = read(
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2\r\nab\r\n1\r\nc\r\n0\r\n\r\nNEXT",
+        );
+        assert_eq!(response.metadata.status, HttpStatus::Ok);
+        assert_eq!(response.body.as_deref(), Some("abc"));
+        assert!(reusable);
+        assert_eq!(rest, "NEXT");
+
+        let (response, reusable, _) = read("HTTP/1.1 200 OK\r\n\r\nuntil the end");
+        assert_eq!(response.body.as_deref(), Some("until the end"));
+        assert!(!reusable);
+
+        let (response, reusable, _) = read("HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        assert_eq!(response.body, None);
+        assert!(!reusable);
+
+        let (_, reusable, _) = read("HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n");
+        assert!(!reusable);
+
+        for raw in [
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc",
+            "HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n",
+            "HTTP/1.1 2000 OK\r\n\r\n",
+            "",
+        ] {
+            assert!(
+                read_response(&mut BufReader::new(raw.as_bytes())).is_err(),
+                "{raw}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_reuses_connections() {
+        let (addr, connections) =
+            keep_alive_upstream(&["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"], 3);
+        let client = HttpClient::new();
+        for _ in 0..3 {
+            let response = client.get(&format!("http://{addr}/")).unwrap();
+            assert_eq!(response.body.as_deref(), Some("ok"));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(client.idle_connections(&addr), 1);
+
+        // The upstream dropped the idle connection, the request is sent again on a new one.
+        let response = client.get(&format!("http://{addr}/")).unwrap();
+        assert_eq!(response.body.as_deref(), Some("ok"));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        let (addr, connections) =
+            keep_alive_upstream(&["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"], 10);
+        let client = HttpClient::new().with_max_idle_per_host(0);
+        for _ in 0..2 {
+            client.get(&format!("http://{addr}/")).unwrap();
+        }
+        assert_eq!(client.idle_connections(&addr), 0);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_client_does_not_replay_posts() {
+        let (addr, connections) =
+            keep_alive_upstream(&["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"], 1);
+        let client = HttpClient::new();
+        client.post(&format!("http://{addr}/"), "a=1").unwrap();
+        assert_eq!(client.idle_connections(&addr), 1);
+
+        // The upstream dropped the idle connection, the request may have been processed.
+        assert!(client.post(&format!("http://{addr}/"), "a=1").is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert_eq!(client.idle_connections(&addr), 0);
+    }
+
+    #[test]
+    fn test_client_follows_redirects() {
+        let (addr, _) = keep_alive_upstream(
+            &[
+                "HTTP/1.1 303 See Other\r\nLocation: /done\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone",
+            ],
+            10,
+        );
+        let response = HttpClient::new()
+            .post(&format!("http://{addr}/form"), "a=1")
+            .unwrap();
+        assert_eq!(response.metadata.status, HttpStatus::Ok);
+        assert_eq!(response.body.as_deref(), Some("done"));
+
+        let (addr, _) = keep_alive_upstream(
+            &["HTTP/1.1 302 Found\r\nLocation: /again\r\nContent-Length: 0\r\n\r\n"],
+            10,
+        );
+        let client = HttpClient::new().with_max_redirects(2);
+        assert!(matches!(
+            client.get(&format!("http://{addr}/")),
+            Err(HttpClientError::TooManyRedirects(2))
+        ));
+        let response = client
+            .with_max_redirects(0)
+            .get(&format!("http://{addr}/"))
+            .unwrap();
+        assert_eq!(response.metadata.status, HttpStatus::Found);
+    }
+
+    #[test]
+    fn test_redirect_requests() {
+        let request = HttpRequest {
+            metadata: HttpRequestMetaData::parse(
+                "POST /a HTTP/1.1\nAuthorization: secret\nContent-Length: 1\nHost: a\n",
+            )
+            .unwrap(),
+            body: Some("x".to_string()),
+            stream: None,
+        };
+        let response = |status, location: &str| {
+            let mut headers = HttpHeaders::new();
+            headers.insert("Location".to_string(), location.to_string());
+            HttpResponse::new(HttpProtocol::Http1_1, status, headers, None)
+        };
+        let (_, next) = redirect(
+            "a:80",
+            &request,
+            &response(HttpStatus::PermanentRedirect, "/b"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(next.metadata.method, HttpMethod::POST);
+        assert_eq!(next.body.as_deref(), Some("x"));
+        assert_eq!(next.metadata.header("Authorization"), Some("secret"));
+
+        let (authority, next) = redirect(
+            "a:80",
+            &request,
+            &response(HttpStatus::Found, "http://b:81/c"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(authority, "b:81");
+        assert_eq!(next.metadata.method, HttpMethod::GET);
+        assert_eq!(next.metadata.uri, "/c");
+        assert_eq!(next.body, None);
+        assert_eq!(next.metadata.header("Host"), Some("b:81"));
+        assert_eq!(next.metadata.header("Authorization"), None);
+        assert_eq!(next.metadata.header("Content-Length"), None);
+
+        assert!(redirect("a:80", &request, &response(HttpStatus::Ok, "/b"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_client_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let silent = thread::spawn(move || listener.accept().map(|(s, _)| s));
+        let result = HttpClient::new()
+            .with_read_timeout(Duration::from_millis(50))
+            .get(&format!("http://{addr}/"));
+        assert!(
+            matches!(result, Err(HttpClientError::Timeout { .. })),
+            "{:?}",
+            result.err()
+        );
+        drop(silent.join());
+
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let result = HttpClient::new().get(&format!("http://{closed}/"));
+        assert!(matches!(result, Err(HttpClientError::Connect { .. })));
+    }
+
+    #[test]
+    fn test_client_talks_to_the_server() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::POST, "/echo", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    r.body.clone(),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = TestServer::start(HttpServer::new(router).with_workers(1));
+        let client = HttpClient::new();
+        for body in ["first", "second"] {
+            let response = client
+                .post(&format!("http://{}/echo", server.addr()), body)
+                .unwrap();
+            assert_eq!(response.body.as_deref(), Some(body));
+            assert_eq!(
+                response
+                    .metadata
+                    .headers
+                    .get("Connection")
+                    .map(String::as_str),
+                Some("close")
+            );
+        }
+        // The server closes every connection after its response, none is kept.
+        assert_eq!(client.idle_connections(&server.addr().to_string()), 0);
+        let raw = format!(
+            "POST /echo HTTP/1.1\nHost: {}\nAccept: */*\n",
+            server.addr()
+        );
+        let mut request = HttpRequest {
+            metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+            body: Some("third".to_string()),
+            stream: None,
+        };
+        let response = client.send(request.clone()).unwrap();
+        assert_eq!(response.body.as_deref(), Some("third"));
+        request.metadata.headers.remove("Host");
+        assert!(matches!(
+            client.send(request),
+            Err(HttpClientError::MissingHost)
+        ));
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     collections::{BTreeMap, HashMap},
     fmt::Display,
     str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 // =========================================================
@@ -11,6 +12,8 @@ use std::{
 pub struct HttpError {
     pub status: HttpStatus,
     message: String,
+    /// Headers sent along with the error response, such as `WWW-Authenticate`.
+    pub headers: HttpHeaders,
 }
 
 impl HttpError {
@@ -18,8 +21,18 @@ impl HttpError {
         Self {
             status: status,
             message: message.into(),
+            headers: HttpHeaders::new(),
         }
     }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
 }
 
 impl Clone for HttpError {
@@ -27,6 +40,7 @@ impl Clone for HttpError {
         Self {
             status: self.status,
             message: self.message.clone(),
+            headers: self.headers.clone(),
         }
     }
 }
@@ -77,15 +91,32 @@ impl FromStr for HttpProtocol {
 // =========================================================
 // ================= HttpMethod Section ====================
 // =========================================================
-#[derive(Eq, PartialOrd, PartialEq, Copy, Clone, Debug, Hash)]
+#[derive(Eq, Ord, PartialOrd, PartialEq, Copy, Clone, Debug, Hash)]
 pub enum HttpMethod {
     DELETE,
     GET,
+    OPTIONS,
     PATCH,
     POST,
     PUT,
 }
 
+impl HttpMethod {
+    pub const ALL: [HttpMethod; 6] = [
+        HttpMethod::DELETE,
+        HttpMethod::GET,
+        HttpMethod::OPTIONS,
+        HttpMethod::PATCH,
+        HttpMethod::POST,
+        HttpMethod::PUT,
+    ];
+
+    /// Whether sending the request twice has the same effect as sending it once.
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, HttpMethod::PATCH | HttpMethod::POST)
+    }
+}
+
 impl FromStr for HttpMethod {
     type Err = HttpError;
 
@@ -96,6 +127,7 @@ impl FromStr for HttpMethod {
             "PUT" => Ok(HttpMethod::PUT),
             "DELETE" => Ok(HttpMethod::DELETE),
             "PATCH" => Ok(HttpMethod::PATCH),
+            "OPTIONS" => Ok(HttpMethod::OPTIONS),
             _ => Err(HttpError::new(
                 HttpStatus::BadRequest,
                 format!("cannot parse {} as request method", s),
@@ -112,6 +144,7 @@ impl Display for HttpMethod {
             HttpMethod::PUT => "PUT",
             HttpMethod::GET => "GET",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::OPTIONS => "OPTIONS",
         };
         write!(f, "{}", str)
     }
@@ -121,7 +154,7 @@ impl Display for HttpMethod {
 // ================= HttpStatus Section ====================
 // =========================================================
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum HttpStatusType {
     Success,
     Redirect,
@@ -130,6 +163,22 @@ pub enum HttpStatusType {
     Unknown,
 }
 
+impl Display for HttpStatusType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                HttpStatusType::Success => "2xx",
+                HttpStatusType::Redirect => "3xx",
+                HttpStatusType::ClientError => "4xx",
+                HttpStatusType::ServerError => "5xx",
+                HttpStatusType::Unknown => "unknown",
+            }
+        )
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum HttpStatus {
     // 2xx Range
@@ -262,7 +311,7 @@ impl HttpStatus {
         }
     }
 
-    fn status_type(&self) -> HttpStatusType {
+    pub fn status_type(&self) -> HttpStatusType {
         match *self as u16 {
             200..300 => HttpStatusType::Success,
             300..400 => HttpStatusType::Redirect,
@@ -271,7 +320,8 @@ impl HttpStatus {
             _ => HttpStatusType::Unknown,
         }
     }
-    fn message(&self) -> &'static str {
+    /// Returns the reason phrase of the status, `Not Found` for `404`.
+    pub fn message(&self) -> &'static str {
         match self {
             HttpStatus::Ok => "OK",
             HttpStatus::Created => "Created",
@@ -336,12 +386,59 @@ pub type HttpHeaders = BTreeMap<String, String>;
 pub type HttpBody = Option<String>;
 pub type HttpServerContext = HashMap<String, String>;
 
+// =========================================================
+// ======================= HTTP dates ======================
+// =========================================================
+/// Seconds since the unix epoch, `0` for earlier times.
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = unix_seconds(time);
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let rem = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since the unix epoch into a (year, month, day) triple.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
 // ########################################################################
 // ############################### Tests ##################################
 // ########################################################################
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
 
     // =========================================================
     // ================= HttpProtocol Tests ====================
@@ -422,4 +519,26 @@ mod tests {
             )
         }
     }
+
+    #[test]
+    fn test_http_status_type() {
+        assert_eq!(HttpStatus::Ok.status_type(), HttpStatusType::Success);
+        assert_eq!(HttpStatus::Found.status_type(), HttpStatusType::Redirect);
+        assert_eq!(
+            HttpStatus::NotFound.status_type(),
+            HttpStatusType::ClientError
+        );
+        assert_eq!(
+            HttpStatus::BadGateway.status_type(),
+            HttpStatusType::ServerError
+        );
+        assert_eq!(format!("{}", HttpStatusType::ClientError), "4xx");
+    }
+
+    #[test]
+    fn test_http_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
 }
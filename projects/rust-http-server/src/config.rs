@@ -0,0 +1,1318 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    net::{TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::LevelFilter;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    access_log::{AccessLog, AccessLogFormat, RotatingFileWriter},
+    common::{HttpMethod, HttpStatus},
+    connection::TrustedProxies,
+    cors::Cors,
+    error_page::ErrorPages,
+    openapi::OpenApiInfo,
+    proxy::ProxyHandler,
+    rate_limit::{Quota, RateLimit, RateLimitKey},
+    request_id::RequestIdFormat,
+    router::{HttpRouter, HttpRouterBuilder, HttpRouterError},
+    server::{HttpListener, HttpServer, HttpServerSettings},
+    static_files::StaticFiles,
+    tls,
+    upstream::{HealthCheck, LoadBalanceStrategy, UpstreamPool},
+    vhost::{HostPattern, VirtualHosts},
+};
+
+/// Configuration used when no config file is given, matching the historical behaviour of
+/// serving the working directory on `127.0.0.1:18000`.
+pub const DEFAULT_CONFIG: &str = r#"
+[server]
+bind = ["127.0.0.1:18000"]
+
+[[static]]
+prefix = "/"
+root = "."
+"#;
+
+// =========================================================
+// ==================== ConfigError ========================
+// =========================================================
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("cannot read config file {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid config file: {0}")]
+    Parse(String),
+    #[error("invalid config value at `{key}`: {message}")]
+    Invalid { key: String, message: String },
+    #[error(transparent)]
+    Routes(#[from] HttpRouterError),
+}
+
+impl ConfigError {
+    fn invalid<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        ConfigError::Invalid {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// =========================================================
+// ====================== Sections =========================
+// =========================================================
+fn default_bind() -> Vec<String> {
+    vec!["127.0.0.1:18000".to_string()]
+}
+
+fn default_request_id() -> String {
+    "uuid".to_string()
+}
+
+fn default_timeout_ms() -> Option<u64> {
+    Some(30_000)
+}
+
+fn default_max_body_bytes() -> Option<usize> {
+    Some(10 * 1024 * 1024)
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_access_log_format() -> String {
+    "combined".to_string()
+}
+
+fn default_access_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_access_log_max_files() -> usize {
+    5
+}
+
+fn default_metrics_route() -> String {
+    "/metrics".to_string()
+}
+
+fn default_openapi_route() -> String {
+    "/openapi.json".to_string()
+}
+
+fn default_openapi_title() -> String {
+    env!("CARGO_PKG_NAME").to_string()
+}
+
+fn default_openapi_version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+fn default_rate_limit_key() -> String {
+    "peer".to_string()
+}
+
+fn default_rate_limit_period() -> u64 {
+    60
+}
+
+fn default_reload_route() -> String {
+    "/-/reload".to_string()
+}
+
+fn default_strategy() -> String {
+    "round_robin".to_string()
+}
+
+fn default_max_failures() -> usize {
+    3
+}
+
+fn default_ejection_ms() -> u64 {
+    30_000
+}
+
+fn default_health_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_health_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_unhealthy_threshold() -> usize {
+    2
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSection {
+    #[serde(default = "default_bind")]
+    pub bind: Vec<String>,
+    pub workers: Option<usize>,
+    /// Answer requests for hosts without a `[[vhost]]` with `421` instead of the top level
+    /// mounts.
+    #[serde(default)]
+    pub strict_hosts: bool,
+    /// Expect a PROXY protocol v1 or v2 header on every connection.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Addresses or CIDR blocks whose `Forwarded` and `X-Forwarded-For` headers are trusted.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Format of the ids given to requests without an `X-Request-ID`, `uuid` or `ulid`.
+    #[serde(default = "default_request_id")]
+    pub request_id: String,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            bind: default_bind(),
+            workers: None,
+            strict_hosts: false,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            request_id: default_request_id(),
+        }
+    }
+}
+
+impl ServerSection {
+    pub fn build_request_ids(&self) -> Result<RequestIdFormat, ConfigError> {
+        RequestIdFormat::from_str(&self.request_id)
+            .map_err(|_| ConfigError::invalid("server.request_id", "expected `uuid` or `ulid`"))
+    }
+
+    pub fn build_trusted_proxies(&self) -> Result<TrustedProxies, ConfigError> {
+        self.trusted_proxies.iter().enumerate().try_fold(
+            TrustedProxies::new(),
+            |proxies, (i, network)| {
+                proxies
+                    .trust(network)
+                    .map_err(|e| ConfigError::invalid(format!("server.trusted_proxies[{i}]"), e))
+            },
+        )
+    }
+}
+
+/// Socket timeouts in milliseconds, `0` disables a timeout.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsSection {
+    #[serde(default = "default_timeout_ms")]
+    pub read_ms: Option<u64>,
+    #[serde(default = "default_timeout_ms")]
+    pub write_ms: Option<u64>,
+}
+
+impl Default for TimeoutsSection {
+    fn default() -> Self {
+        TimeoutsSection {
+            read_ms: default_timeout_ms(),
+            write_ms: default_timeout_ms(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsSection {
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: Option<usize>,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        LimitsSection {
+            max_body_bytes: default_max_body_bytes(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub bind: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogSection {
+    pub path: PathBuf,
+    #[serde(default = "default_access_log_format")]
+    pub format: String,
+    #[serde(default = "default_access_log_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_access_log_max_files")]
+    pub max_files: usize,
+}
+
+impl AccessLogSection {
+    pub fn new(path: PathBuf) -> Self {
+        AccessLogSection {
+            path,
+            format: default_access_log_format(),
+            max_bytes: default_access_log_max_bytes(),
+            max_files: default_access_log_max_files(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingSection {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    pub access_log: Option<AccessLogSection>,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            level: default_log_level(),
+            access_log: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsSection {
+    #[serde(default = "default_metrics_route")]
+    pub route: String,
+}
+
+/// Serves the OpenAPI document of the default router.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OpenApiSection {
+    #[serde(default = "default_openapi_route")]
+    pub route: String,
+    #[serde(default = "default_openapi_title")]
+    pub title: String,
+    #[serde(default = "default_openapi_version")]
+    pub version: String,
+    pub description: Option<String>,
+}
+
+/// Cross-origin resource sharing policy of the default router. `"*"` in `allow_origins` or
+/// `allow_headers` allows any origin or request header, credentials cannot be allowed to any
+/// origin. `allow_origin_patterns` are matched against the whole origin.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CorsSection {
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    #[serde(default)]
+    pub allow_origin_patterns: Vec<String>,
+    pub allow_methods: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+impl CorsSection {
+    pub fn build(&self) -> Result<Cors, ConfigError> {
+        if self.allow_credentials && self.allow_origins.iter().any(|o| o == "*") {
+            return Err(ConfigError::invalid(
+                "cors.allow_credentials",
+                "credentials cannot be allowed when `allow_origins` has `*`",
+            ));
+        }
+        let mut cors = Cors::new().allow_credentials(self.allow_credentials);
+        for origin in self.allow_origins.iter() {
+            cors = match origin.as_str() {
+                "*" => cors.allow_any_origin(),
+                origin => cors.allow_origin(origin),
+            };
+        }
+        for (i, pattern) in self.allow_origin_patterns.iter().enumerate() {
+            let re = Regex::new(&format!("^(?:{pattern})$")).map_err(|e| {
+                ConfigError::invalid(format!("cors.allow_origin_patterns[{i}]"), e.to_string())
+            })?;
+            cors = cors.allow_origin_pattern(re);
+        }
+        if let Some(methods) = self.allow_methods.as_ref() {
+            let methods = methods
+                .iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    HttpMethod::from_str(&m.to_ascii_uppercase()).map_err(|_| {
+                        ConfigError::invalid(
+                            format!("cors.allow_methods[{i}]"),
+                            format!("unknown method `{m}`"),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<HttpMethod>, ConfigError>>()?;
+            cors = cors.allow_methods(&methods);
+        }
+        let headers = self.allow_headers.iter().map(String::as_str);
+        cors = match self.allow_headers.iter().any(|h| h == "*") {
+            true => cors.allow_any_header(),
+            false => cors.allow_headers(&headers.collect::<Vec<&str>>()),
+        };
+        let expose = self.expose_headers.iter().map(String::as_str);
+        cors = cors.expose_headers(&expose.collect::<Vec<&str>>());
+        if let Some(max_age) = self.max_age_secs {
+            cors = cors.max_age(Duration::from_secs(max_age));
+        }
+        Ok(cors)
+    }
+}
+
+/// Rendering of error responses. `pages` maps status codes to the files served for them,
+/// where `{status}`, `{title}`, `{detail}` and `{request_id}` are replaced.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorsSection {
+    #[serde(default)]
+    pub expose_messages: bool,
+    #[serde(default)]
+    pub pages: BTreeMap<String, PathBuf>,
+}
+
+impl ErrorsSection {
+    pub fn build(&self) -> Result<ErrorPages, ConfigError> {
+        let mut pages = ErrorPages::new().expose_messages(self.expose_messages);
+        for (code, path) in self.pages.iter() {
+            let key = format!("errors.pages.{code}");
+            let status = match code.parse::<u16>() {
+                Ok(c) if (400..600).contains(&c) => HttpStatus::from_code(c),
+                _ => return Err(ConfigError::invalid(key, "expected an error status code")),
+            };
+            pages = pages
+                .page_file(status, path)
+                .map_err(|e| ConfigError::invalid(key, format!("{}: {e}", path.display())))?;
+        }
+        Ok(pages)
+    }
+}
+
+/// A quota of its own for the routes matching `path`, for every method when `method` is
+/// not set.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRoute {
+    pub method: Option<String>,
+    pub path: String,
+    pub requests: u32,
+    #[serde(default = "default_rate_limit_period")]
+    pub period_secs: u64,
+}
+
+/// Rate limiting of the default router. `key` is one of `peer`, `principal` or
+/// `header:<name>`; without `requests` only the listed routes are limited.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSection {
+    #[serde(default = "default_rate_limit_key")]
+    pub key: String,
+    pub requests: Option<u32>,
+    #[serde(default = "default_rate_limit_period")]
+    pub period_secs: u64,
+    #[serde(default)]
+    pub routes: Vec<RateLimitRoute>,
+}
+
+impl RateLimitSection {
+    fn quota(key: &str, requests: u32, period_secs: u64) -> Result<Quota, ConfigError> {
+        if requests == 0 || period_secs == 0 {
+            return Err(ConfigError::invalid(
+                key,
+                "requests and period must be positive",
+            ));
+        }
+        Ok(Quota::new(requests, Duration::from_secs(period_secs)))
+    }
+
+    pub fn build(&self) -> Result<RateLimit, ConfigError> {
+        let key = match self.key.split_once(':') {
+            None if self.key == "peer" => RateLimitKey::Peer,
+            None if self.key == "principal" => RateLimitKey::Principal,
+            Some(("header", name)) if !name.trim().is_empty() => {
+                RateLimitKey::Header(name.trim().to_string())
+            }
+            _ => {
+                return Err(ConfigError::invalid(
+                    "rate_limit.key",
+                    "expected one of `peer`, `principal` or `header:<name>`",
+                ))
+            }
+        };
+        let mut limit = RateLimit::new(key);
+        if let Some(requests) = self.requests {
+            limit = limit.quota(Self::quota(
+                "rate_limit.requests",
+                requests,
+                self.period_secs,
+            )?);
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            let method = match route.method.as_ref() {
+                Some(m) => Some(HttpMethod::from_str(&m.to_ascii_uppercase()).map_err(|_| {
+                    ConfigError::invalid(
+                        format!("rate_limit.routes[{i}].method"),
+                        format!("unknown method `{m}`"),
+                    )
+                })?),
+                None => None,
+            };
+            if !route.path.starts_with('/') {
+                return Err(ConfigError::invalid(
+                    format!("rate_limit.routes[{i}].path"),
+                    "path must start with `/`",
+                ));
+            }
+            let quota = Self::quota(
+                &format!("rate_limit.routes[{i}].requests"),
+                route.requests,
+                route.period_secs,
+            )?;
+            limit = limit
+                .route(method, &route.path, quota)
+                .map_err(|e| ConfigError::invalid(format!("rate_limit.routes[{i}].path"), e))?;
+        }
+        Ok(limit)
+    }
+}
+
+/// Administration endpoints, only answered to requests from loopback addresses presenting
+/// `token` as a bearer token.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminSection {
+    #[serde(default = "default_reload_route")]
+    pub reload_route: String,
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StaticMount {
+    pub prefix: String,
+    pub root: PathBuf,
+}
+
+/// Forwards a path prefix either to a single `upstream` address or to a named `pool`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyMount {
+    pub prefix: String,
+    pub upstream: Option<String>,
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    pub connect_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckSection {
+    pub path: String,
+    #[serde(default = "default_health_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamSection {
+    pub servers: Vec<String>,
+    #[serde(default = "default_strategy")]
+    pub strategy: String,
+    pub hash_header: Option<String>,
+    #[serde(default = "default_max_failures")]
+    pub max_failures: usize,
+    #[serde(default = "default_ejection_ms")]
+    pub ejection_ms: u64,
+    pub health_check: Option<HealthCheckSection>,
+}
+
+/// Mounts served only to requests whose `Host` matches one of `hosts`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostSection {
+    pub hosts: Vec<String>,
+    #[serde(default, rename = "static")]
+    pub static_mounts: Vec<StaticMount>,
+    #[serde(default, rename = "proxy")]
+    pub proxy_mounts: Vec<ProxyMount>,
+}
+
+// =========================================================
+// ======================= Config ==========================
+// =========================================================
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerSection,
+    #[serde(default)]
+    pub timeouts: TimeoutsSection,
+    #[serde(default)]
+    pub limits: LimitsSection,
+    pub tls: Option<TlsSection>,
+    #[serde(default)]
+    pub logging: LoggingSection,
+    pub metrics: Option<MetricsSection>,
+    pub admin: Option<AdminSection>,
+    pub openapi: Option<OpenApiSection>,
+    pub cors: Option<CorsSection>,
+    pub rate_limit: Option<RateLimitSection>,
+    #[serde(default)]
+    pub errors: ErrorsSection,
+    #[serde(default, rename = "static")]
+    pub static_mounts: Vec<StaticMount>,
+    #[serde(default, rename = "proxy")]
+    pub proxy_mounts: Vec<ProxyMount>,
+    #[serde(default, rename = "vhost")]
+    pub virtual_hosts: Vec<VirtualHostSection>,
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamSection>,
+}
+
+fn millis(ms: Option<u64>) -> Option<Duration> {
+    ms.filter(|ms| *ms > 0).map(Duration::from_millis)
+}
+
+fn validate_prefix(key: &str, prefix: &str) -> Result<(), ConfigError> {
+    if !prefix.starts_with('/') {
+        return Err(ConfigError::invalid(key, "path prefix must start with `/`"));
+    }
+    if prefix.contains(['*', ':', '?']) {
+        return Err(ConfigError::invalid(
+            key,
+            "path prefix cannot contain route parameters",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_address(key: &str, address: &str) -> Result<(), ConfigError> {
+    match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ConfigError::invalid(
+            key,
+            format!("`{address}` did not resolve"),
+        )),
+        Err(e) => Err(ConfigError::invalid(
+            key,
+            format!("`{address}` is not a valid host:port address: {e}"),
+        )),
+    }
+}
+
+impl Config {
+    pub fn parse(raw: &str) -> Result<Config, ConfigError> {
+        toml::from_str(raw).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let raw = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Config::parse(&raw)
+    }
+
+    /// Checks the values serde cannot, reporting the first offending key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let tls_bind = self.tls.as_ref().map_or(0, |t| t.bind.len());
+        if self.server.bind.is_empty() && tls_bind == 0 {
+            return Err(ConfigError::invalid(
+                "server.bind",
+                "at least one bind address is required",
+            ));
+        }
+        for (i, address) in self.server.bind.iter().enumerate() {
+            validate_address(&format!("server.bind[{i}]"), address)?;
+        }
+        if self.server.workers == Some(0) {
+            return Err(ConfigError::invalid("server.workers", "must be at least 1"));
+        }
+        self.server.build_trusted_proxies()?;
+        if let Some(tls) = self.tls.as_ref() {
+            for (i, address) in tls.bind.iter().enumerate() {
+                validate_address(&format!("tls.bind[{i}]"), address)?;
+            }
+            if !tls.cert.is_file() {
+                return Err(ConfigError::invalid("tls.cert", "file does not exist"));
+            }
+            if !tls.key.is_file() {
+                return Err(ConfigError::invalid("tls.key", "file does not exist"));
+            }
+            tls::load_server_config(&tls.cert, &tls.key)
+                .map_err(|e| ConfigError::invalid("tls", e.to_string()))?;
+        }
+        self.server.build_request_ids()?;
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            return Err(ConfigError::invalid(
+                "logging.level",
+                format!("unknown log level `{}`", self.logging.level),
+            ));
+        }
+        if let Some(access_log) = self.logging.access_log.as_ref() {
+            if AccessLogFormat::from_str(&access_log.format).is_err() {
+                return Err(ConfigError::invalid(
+                    "logging.access_log.format",
+                    "expected one of `common`, `combined` or `json`",
+                ));
+            }
+        }
+        if let Some(metrics) = self.metrics.as_ref() {
+            validate_prefix("metrics.route", &metrics.route)?;
+        }
+        if let Some(admin) = self.admin.as_ref() {
+            validate_prefix("admin.reload_route", &admin.reload_route)?;
+            if admin.token.trim().is_empty() {
+                return Err(ConfigError::invalid("admin.token", "token cannot be empty"));
+            }
+        }
+        if let Some(openapi) = self.openapi.as_ref() {
+            validate_prefix("openapi.route", &openapi.route)?;
+        }
+        if let Some(cors) = self.cors.as_ref() {
+            cors.build()?;
+        }
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            rate_limit.build()?;
+        }
+        self.errors.build()?;
+
+        self.validate_mounts("", &self.static_mounts, &self.proxy_mounts)?;
+        let mut patterns = HashSet::new();
+        for (i, vhost) in self.virtual_hosts.iter().enumerate() {
+            if vhost.hosts.is_empty() {
+                return Err(ConfigError::invalid(
+                    format!("vhost[{i}].hosts"),
+                    "at least one host name is required",
+                ));
+            }
+            for (j, host) in vhost.hosts.iter().enumerate() {
+                let key = format!("vhost[{i}].hosts[{j}]");
+                let pattern = HostPattern::from_str(host)
+                    .map_err(|e| ConfigError::invalid(key.as_str(), e))?;
+                if !patterns.insert(pattern.to_string()) {
+                    return Err(ConfigError::invalid(
+                        key,
+                        format!("host `{pattern}` belongs to several virtual hosts"),
+                    ));
+                }
+            }
+            self.validate_mounts(
+                &format!("vhost[{i}]."),
+                &vhost.static_mounts,
+                &vhost.proxy_mounts,
+            )?;
+        }
+        for (name, pool) in self.upstreams.iter() {
+            if pool.servers.is_empty() {
+                return Err(ConfigError::invalid(
+                    format!("upstreams.{name}.servers"),
+                    "at least one server is required",
+                ));
+            }
+            for (i, server) in pool.servers.iter().enumerate() {
+                validate_address(&format!("upstreams.{name}.servers[{i}]"), server)?;
+            }
+            if !["round_robin", "least_connections", "consistent_hash"]
+                .contains(&pool.strategy.as_str())
+            {
+                return Err(ConfigError::invalid(
+                    format!("upstreams.{name}.strategy"),
+                    "expected one of `round_robin`, `least_connections` or `consistent_hash`",
+                ));
+            }
+            if let Some(check) = pool.health_check.as_ref() {
+                validate_prefix(&format!("upstreams.{name}.health_check.path"), &check.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_mounts(
+        &self,
+        key: &str,
+        static_mounts: &[StaticMount],
+        proxy_mounts: &[ProxyMount],
+    ) -> Result<(), ConfigError> {
+        let mut prefixes = HashSet::new();
+        for (i, mount) in static_mounts.iter().enumerate() {
+            validate_prefix(&format!("{key}static[{i}].prefix"), &mount.prefix)?;
+            if !prefixes.insert(mount.prefix.trim_end_matches('/').to_string()) {
+                return Err(ConfigError::invalid(
+                    format!("{key}static[{i}].prefix"),
+                    format!("prefix `{}` is mounted twice", mount.prefix),
+                ));
+            }
+            if !StaticFiles::new(&mount.prefix, &mount.root).root_exists() {
+                return Err(ConfigError::invalid(
+                    format!("{key}static[{i}].root"),
+                    format!("`{}` is not a directory", mount.root.display()),
+                ));
+            }
+        }
+        for (i, mount) in proxy_mounts.iter().enumerate() {
+            validate_prefix(&format!("{key}proxy[{i}].prefix"), &mount.prefix)?;
+            if !prefixes.insert(mount.prefix.trim_end_matches('/').to_string()) {
+                return Err(ConfigError::invalid(
+                    format!("{key}proxy[{i}].prefix"),
+                    format!("prefix `{}` is mounted twice", mount.prefix),
+                ));
+            }
+            match (mount.upstream.as_ref(), mount.pool.as_ref()) {
+                (Some(upstream), None) => {
+                    validate_address(&format!("{key}proxy[{i}].upstream"), upstream)?
+                }
+                (None, Some(pool)) => {
+                    if !self.upstreams.contains_key(pool) {
+                        return Err(ConfigError::invalid(
+                            format!("{key}proxy[{i}].pool"),
+                            format!("no upstream pool named `{pool}`"),
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(ConfigError::invalid(
+                        format!("{key}proxy[{i}]"),
+                        "exactly one of `upstream` or `pool` must be set",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.logging.level).unwrap_or(LevelFilter::Info)
+    }
+
+    fn upstream_pool(
+        &self,
+        name: &str,
+        section: &UpstreamSection,
+        mount: &ProxyMount,
+    ) -> Arc<UpstreamPool> {
+        let strategy = match section.strategy.as_str() {
+            "least_connections" => LoadBalanceStrategy::LeastConnections,
+            "consistent_hash" => LoadBalanceStrategy::ConsistentHash {
+                header: section.hash_header.clone(),
+            },
+            _ => LoadBalanceStrategy::RoundRobin,
+        };
+        let servers = section
+            .servers
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        let pool = Arc::new(
+            UpstreamPool::new(name, &servers, strategy)
+                .passive_ejection(
+                    section.max_failures,
+                    Duration::from_millis(section.ejection_ms),
+                )
+                .proxy(self.proxy_handler(mount, name)),
+        );
+        if let Some(check) = section.health_check.as_ref() {
+            let mut health_check = HealthCheck::new(&check.path);
+            health_check.interval = Duration::from_millis(check.interval_ms);
+            health_check.timeout = Duration::from_millis(check.timeout_ms);
+            health_check.unhealthy_threshold = check.unhealthy_threshold;
+            pool.start_health_checks(health_check);
+        }
+        pool
+    }
+
+    fn proxy_handler(&self, mount: &ProxyMount, upstream: &str) -> ProxyHandler {
+        let mut proxy = ProxyHandler::new(upstream);
+        if mount.strip_prefix {
+            proxy = proxy.strip_prefix(&mount.prefix);
+        }
+        if let Some(timeout) = millis(mount.connect_timeout_ms) {
+            proxy = proxy.connect_timeout(timeout);
+        }
+        if let Some(timeout) = millis(mount.read_timeout_ms) {
+            proxy = proxy.read_timeout(timeout);
+        }
+        proxy
+    }
+
+    fn add_mounts(
+        &self,
+        builder: &mut HttpRouterBuilder,
+        static_mounts: &[StaticMount],
+        proxy_mounts: &[ProxyMount],
+    ) {
+        for mount in static_mounts.iter() {
+            let files = StaticFiles::new(&mount.prefix, &mount.root);
+            builder.add_prefix_route(&mount.prefix, move |r, c| files.handle(r, c));
+        }
+        for mount in proxy_mounts.iter() {
+            match (mount.upstream.as_ref(), mount.pool.as_ref()) {
+                (Some(upstream), _) => {
+                    let proxy = self.proxy_handler(mount, upstream);
+                    builder.add_prefix_route(&mount.prefix, move |r, c| proxy.handle(r, c));
+                }
+                (None, Some(name)) => {
+                    let pool = self.upstream_pool(name, &self.upstreams[name], mount);
+                    builder.add_prefix_route(&mount.prefix, move |r, c| pool.handle(r, c));
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    /// Builds the default router from the top level static and proxy mounts, on top of the
+    /// application routes registered by `routes`.
+    pub fn build_router<F>(&self, routes: F) -> Result<HttpRouter, ConfigError>
+    where
+        F: FnOnce(&mut HttpRouterBuilder),
+    {
+        let mut builder = HttpRouterBuilder::new();
+        if let Some(cors) = self.cors.as_ref() {
+            builder.add_cors(cors.build()?);
+        }
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            let rate_limit = rate_limit.build()?;
+            builder.add_middleware(move |r, c, next| rate_limit.handle(r, c, next));
+        }
+        routes(&mut builder);
+        self.add_mounts(&mut builder, &self.static_mounts, &self.proxy_mounts);
+        if let Some(openapi) = self.openapi.as_ref() {
+            let mut info = OpenApiInfo::new(&openapi.title, &openapi.version);
+            info.description = openapi.description.clone();
+            builder.serve_openapi(&openapi.route, info);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Builds the routers of the `[[vhost]]` sections.
+    pub fn build_virtual_hosts(&self) -> Result<VirtualHosts, ConfigError> {
+        let mut virtual_hosts = VirtualHosts::new();
+        virtual_hosts.strict(self.server.strict_hosts);
+        for (i, vhost) in self.virtual_hosts.iter().enumerate() {
+            let mut builder = HttpRouterBuilder::new();
+            self.add_mounts(&mut builder, &vhost.static_mounts, &vhost.proxy_mounts);
+            let router = builder
+                .build()
+                .map_err(|e| ConfigError::invalid(format!("vhost[{i}]"), e.to_string()))?;
+            for pattern in vhost.hosts.iter().filter_map(|h| h.parse().ok()) {
+                virtual_hosts.add(pattern, router.clone());
+            }
+        }
+        Ok(virtual_hosts)
+    }
+
+    /// Builds the settings that can be swapped on a running server.
+    pub fn build_settings(&self, router: HttpRouter) -> Result<HttpServerSettings, ConfigError> {
+        let access_log = match self.logging.access_log.as_ref() {
+            Some(access_log) => {
+                let writer = RotatingFileWriter::new(
+                    &access_log.path,
+                    access_log.max_bytes,
+                    access_log.max_files,
+                )
+                .map_err(|e| ConfigError::invalid("logging.access_log.path", e.to_string()))?;
+                let format = AccessLogFormat::from_str(&access_log.format)
+                    .map_err(|e| ConfigError::invalid("logging.access_log.format", e))?;
+                Some(Arc::new(AccessLog::new(format, writer)))
+            }
+            None => None,
+        };
+        Ok(HttpServerSettings {
+            router,
+            read_timeout: millis(self.timeouts.read_ms),
+            write_timeout: millis(self.timeouts.write_ms),
+            max_body_size: self.limits.max_body_bytes,
+            access_log,
+            virtual_hosts: self.build_virtual_hosts()?,
+            trusted_proxies: self.server.build_trusted_proxies()?,
+            error_pages: self.errors.build()?,
+            request_ids: self.server.build_request_ids()?,
+        })
+    }
+
+    /// Returns the sections that differ from `running` but only take effect after a restart.
+    pub fn restart_required(&self, running: &Config) -> Vec<&'static str> {
+        let mut sections = Vec::new();
+        if self.server.bind != running.server.bind {
+            sections.push("server.bind");
+        }
+        if self.server.workers != running.server.workers {
+            sections.push("server.workers");
+        }
+        if self.server.proxy_protocol != running.server.proxy_protocol {
+            sections.push("server.proxy_protocol");
+        }
+        if self.tls != running.tls {
+            sections.push("tls");
+        }
+        if self.metrics != running.metrics {
+            sections.push("metrics");
+        }
+        if self.admin != running.admin {
+            sections.push("admin");
+        }
+        if self.logging.level != running.logging.level {
+            sections.push("logging.level");
+        }
+        sections
+    }
+
+    /// Builds the server around `router` and binds its listeners.
+    pub fn build_server(
+        &self,
+        router: HttpRouter,
+    ) -> Result<(HttpServer, Vec<HttpListener>), ConfigError> {
+        let mut server = HttpServer::from_settings(self.build_settings(router)?);
+        if let Some(workers) = self.server.workers {
+            server = server.with_workers(workers);
+        }
+        if let Some(metrics) = self.metrics.as_ref() {
+            server = server.with_metrics(&metrics.route);
+        }
+        if let Some(admin) = self.admin.as_ref() {
+            server = server.with_admin_reload(&admin.reload_route, &admin.token);
+        }
+
+        let bind = |key: String, address: &str| {
+            TcpListener::bind(address)
+                .map_err(|e| ConfigError::invalid(key, format!("cannot bind `{address}`: {e}")))
+        };
+        let mut listeners = Vec::new();
+        for (i, address) in self.server.bind.iter().enumerate() {
+            let listener = HttpListener::plain(bind(format!("server.bind[{i}]"), address)?);
+            listeners.push(listener.with_proxy_protocol(self.server.proxy_protocol));
+        }
+        if let Some(tls) = self.tls.as_ref() {
+            let config = tls::load_server_config(&tls.cert, &tls.key)
+                .map_err(|e| ConfigError::invalid("tls", e.to_string()))?;
+            for (i, address) in tls.bind.iter().enumerate() {
+                let listener = HttpListener::tls(
+                    bind(format!("tls.bind[{i}]"), address)?,
+                    Arc::clone(&config),
+                );
+                listeners.push(listener.with_proxy_protocol(self.server.proxy_protocol));
+            }
+        }
+        Ok((server, listeners))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::self_signed_cert;
+
+    fn invalid_key(raw: &str) -> String {
+        match Config::parse(raw).unwrap().validate() {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("expected a validation error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::parse(DEFAULT_CONFIG).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.server.bind, ["127.0.0.1:18000"]);
+        assert_eq!(config.static_mounts.len(), 1);
+        assert_eq!(config.timeouts.read_ms, Some(30_000));
+        assert_eq!(config.limits.max_body_bytes, Some(10 * 1024 * 1024));
+        assert_eq!(config.log_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_example_config_parses() {
+        let config = Config::parse(include_str!("../server.example.toml")).unwrap();
+        assert_eq!(config.proxy_mounts.len(), 2);
+        assert_eq!(config.upstreams["backends"].servers.len(), 2);
+        assert!(config.tls.is_some());
+        assert_eq!(config.virtual_hosts[0].hosts.len(), 2);
+        assert_eq!(config.admin.unwrap().reload_route, "/-/reload");
+        assert_eq!(config.openapi.unwrap().route, "/openapi.json");
+        assert!(config.cors.unwrap().build().is_ok());
+        assert_eq!(config.rate_limit.as_ref().unwrap().routes.len(), 1);
+        assert!(config.rate_limit.unwrap().build().is_ok());
+    }
+
+    #[test]
+    fn test_cors_origin_patterns_are_anchored() {
+        let config =
+            Config::parse("[cors]\nallow_origin_patterns = ['https://[a-z]+\\.example\\.com']\n")
+                .unwrap();
+        let cors = config.cors.unwrap().build().unwrap();
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(!cors.allows_origin("https://app.example.com.evil.test"));
+        assert!(!cors.allows_origin("https://evil.test/https://app.example.com"));
+    }
+
+    #[test]
+    fn test_parse_errors_mention_the_key() {
+        let err = Config::parse("[server]\nworkers = \"many\"\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("workers"));
+        let err = Config::parse("[server]\nport = 80\n").err().unwrap();
+        assert!(err.to_string().contains("port"));
+        let err = Config::parse("[[static]]\nprefix = \"/\"\n").err().unwrap();
+        assert!(err.to_string().contains("root"));
+    }
+
+    #[test]
+    fn test_validation_errors_point_at_key() {
+        assert_eq!(invalid_key("[server]\nbind = []\n"), "server.bind");
+        assert_eq!(
+            invalid_key("[server]\nbind = [\"nope\"]\n"),
+            "server.bind[0]"
+        );
+        assert_eq!(invalid_key("[server]\nworkers = 0\n"), "server.workers");
+        assert_eq!(
+            invalid_key("[server]\nrequest_id = \"serial\"\n"),
+            "server.request_id"
+        );
+        assert_eq!(
+            invalid_key("[logging]\nlevel = \"loud\"\n"),
+            "logging.level"
+        );
+        assert_eq!(
+            invalid_key("[logging.access_log]\npath = \"a.log\"\nformat = \"xml\"\n"),
+            "logging.access_log.format"
+        );
+        assert_eq!(invalid_key("[admin]\ntoken = \" \"\n"), "admin.token");
+        assert_eq!(
+            invalid_key("[openapi]\nroute = \"openapi.json\"\n"),
+            "openapi.route"
+        );
+        assert_eq!(
+            invalid_key("[cors]\nallow_origin_patterns = [\"^https://(\"]\n"),
+            "cors.allow_origin_patterns[0]"
+        );
+        assert_eq!(
+            invalid_key("[cors]\nallow_origins = [\"*\"]\nallow_credentials = true\n"),
+            "cors.allow_credentials"
+        );
+        assert_eq!(
+            invalid_key("[cors]\nallow_methods = [\"GET\", \"FETCH\"]\n"),
+            "cors.allow_methods[1]"
+        );
+        assert_eq!(
+            invalid_key("[server]\ntrusted_proxies = [\"10.0.0.0/8\", \"10.0.0.0/40\"]\n"),
+            "server.trusted_proxies[1]"
+        );
+        assert_eq!(
+            invalid_key("[errors.pages]\n200 = \"Cargo.toml\"\n"),
+            "errors.pages.200"
+        );
+        assert_eq!(
+            invalid_key("[errors.pages]\n404 = \"missing/404.html\"\n"),
+            "errors.pages.404"
+        );
+        assert_eq!(
+            invalid_key("[rate_limit]\nkey = \"cookie:id\"\n"),
+            "rate_limit.key"
+        );
+        assert_eq!(
+            invalid_key("[rate_limit]\nrequests = 0\n"),
+            "rate_limit.requests"
+        );
+        assert_eq!(
+            invalid_key("[[rate_limit.routes]]\nmethod = \"FETCH\"\npath = \"/\"\nrequests = 1\n"),
+            "rate_limit.routes[0].method"
+        );
+        assert_eq!(
+            invalid_key(
+                "[[rate_limit.routes]]\npath = \"/a/:id\"\nrequests = 1\n\
+                [[rate_limit.routes]]\npath = \"/a/:name\"\nrequests = 2\n"
+            ),
+            "rate_limit.routes[1].path"
+        );
+        assert_eq!(
+            invalid_key("[[static]]\nprefix = \"assets\"\nroot = \".\"\n"),
+            "static[0].prefix"
+        );
+        assert_eq!(
+            invalid_key("[[static]]\nprefix = \"/\"\nroot = \"/nonexistent\"\n"),
+            "static[0].root"
+        );
+        assert_eq!(
+            invalid_key(
+                "[[static]]\nprefix = \"/a\"\nroot = \".\"\n\
+                [[proxy]]\nprefix = \"/a/\"\nupstream = \"127.0.0.1:9000\"\n"
+            ),
+            "proxy[0].prefix"
+        );
+        assert_eq!(invalid_key("[[proxy]]\nprefix = \"/a\"\n"), "proxy[0]");
+        assert_eq!(
+            invalid_key("[[proxy]]\nprefix = \"/a\"\npool = \"missing\"\n"),
+            "proxy[0].pool"
+        );
+        assert_eq!(
+            invalid_key("[upstreams.api]\nservers = []\n"),
+            "upstreams.api.servers"
+        );
+        assert_eq!(
+            invalid_key("[upstreams.api]\nservers = [\"127.0.0.1:1\"]\nstrategy = \"random\"\n"),
+            "upstreams.api.strategy"
+        );
+        assert_eq!(
+            invalid_key("[tls]\nbind = []\ncert = \"/nonexistent.crt\"\nkey = \"k\"\n"),
+            "tls.cert"
+        );
+    }
+
+    #[test]
+    fn test_tls_config_is_validated() {
+        let (cert, key) = self_signed_cert("config");
+        let raw = format!(
+            "[tls]\nbind = [\"127.0.0.1:0\"]\ncert = {:?}\nkey = {:?}\n",
+            cert, key
+        );
+        assert!(Config::parse(&raw).unwrap().validate().is_ok());
+        let raw = format!(
+            "[tls]\nbind = [\"127.0.0.1:0\"]\ncert = {:?}\nkey = {:?}\n",
+            key, key
+        );
+        assert_eq!(invalid_key(&raw), "tls");
+    }
+
+    #[test]
+    fn test_build_server_binds_listeners() {
+        let (cert, key) = self_signed_cert("listeners");
+        let raw = format!(
+            "[server]\nbind = [\"127.0.0.1:0\", \"127.0.0.1:0\"]\nworkers = 2\n\
+            [tls]\nbind = [\"127.0.0.1:0\"]\ncert = {:?}\nkey = {:?}\n",
+            cert, key
+        );
+        let config = Config::parse(&raw).unwrap();
+        config.validate().unwrap();
+        let (_, listeners) = config
+            .build_server(config.build_router(|_| {}).unwrap())
+            .unwrap();
+        assert_eq!(listeners.len(), 3);
+    }
+
+    #[test]
+    fn test_build_router_mounts() {
+        use crate::{
+            common::HttpMethod,
+            request::{HttpRequest, HttpRequestMetaData},
+        };
+        let config = Config::parse(
+            "[[static]]\nprefix = \"/assets\"\nroot = \".\"\n\
+            [[proxy]]\nprefix = \"/api\"\npool = \"api\"\n\
+            [upstreams.api]\nservers = [\"127.0.0.1:1\"]\n",
+        )
+        .unwrap();
+        let router = config
+            .build_router(|b| {
+                b.add_route(HttpMethod::GET, "/app", |_, _| {
+                    Err(crate::common::HttpError::new(
+                        crate::common::HttpStatus::ImATeapot,
+                        "",
+                    ))
+                });
+            })
+            .unwrap();
+        for (uri, pattern) in [
+            ("/assets/x.css", "/assets/*"),
+            ("/api/users", "/api/*"),
+            ("/api", "/api"),
+            ("/app", "/app"),
+        ] {
+            let r = HttpRequest {
+                metadata: HttpRequestMetaData::parse(&format!("GET {uri} HTTP/1.1")).unwrap(),
+                body: None,
+                stream: None,
+            };
+            assert_eq!(router.find_route(&r).unwrap().pattern, pattern);
+        }
+    }
+
+    #[test]
+    fn test_build_settings() {
+        let config = Config::parse(
+            "[timeouts]\nread_ms = 0\nwrite_ms = 1500\n[limits]\nmax_body_bytes = 64\n",
+        )
+        .unwrap();
+        let settings = config
+            .build_settings(config.build_router(|_| {}).unwrap())
+            .unwrap();
+        assert_eq!(settings.read_timeout, None);
+        assert_eq!(settings.write_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(settings.max_body_size, Some(64));
+        assert!(settings.access_log.is_none());
+        assert_eq!(settings.request_ids, RequestIdFormat::Uuid);
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let running = Config::parse(DEFAULT_CONFIG).unwrap();
+        let mut reloaded = running.clone();
+        reloaded.limits.max_body_bytes = Some(1);
+        reloaded.static_mounts.clear();
+        assert!(reloaded.restart_required(&running).is_empty());
+        reloaded.server.workers = Some(2);
+        reloaded.logging.level = "debug".to_string();
+        assert_eq!(
+            reloaded.restart_required(&running),
+            ["server.workers", "logging.level"]
+        );
+    }
+
+    #[test]
+    fn test_build_virtual_hosts() {
+        use crate::{
+            common::HttpMethod,
+            request::{HttpRequest, HttpRequestMetaData},
+        };
+        let config = Config::parse(
+            "[server]\nstrict_hosts = true\n\
+            [[vhost]]\nhosts = [\"example.com\", \"*.example.com\"]\n\
+            [[vhost.static]]\nprefix = \"/assets\"\nroot = \".\"\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let default = config
+            .build_router(|b| {
+                b.add_route(HttpMethod::GET, "/app", |_, _| {
+                    Err(crate::common::HttpError::new(
+                        crate::common::HttpStatus::ImATeapot,
+                        "",
+                    ))
+                });
+            })
+            .unwrap();
+        let virtual_hosts = config.build_virtual_hosts().unwrap();
+        let r = HttpRequest {
+            metadata: HttpRequestMetaData::parse("GET /assets/a.css HTTP/1.1").unwrap(),
+            body: None,
+            stream: None,
+        };
+        for host in ["example.com", "www.example.com"] {
+            let router = virtual_hosts.select(Some(host), &default).unwrap();
+            assert_eq!(router.find_route(&r).unwrap().pattern, "/assets/*");
+        }
+        assert!(virtual_hosts.select(Some("other.com"), &default).is_none());
+    }
+}
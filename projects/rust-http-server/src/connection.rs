@@ -0,0 +1,495 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    str::FromStr,
+};
+
+use rustls::{ProtocolVersion, ServerConnection, StreamOwned};
+
+use crate::{
+    common::{HttpHeaders, HttpServerContext},
+    request::HttpRequestMetaData,
+};
+
+/// Context keys holding the connection a request came in on. Path parameter names cannot
+/// contain a dot, so they never collide with these.
+pub const PEER_ADDR_KEY: &str = "conn.peer_addr";
+pub const LOCAL_ADDR_KEY: &str = "conn.local_addr";
+pub const CLIENT_IP_KEY: &str = "conn.client_ip";
+pub const TLS_VERSION_KEY: &str = "conn.tls_version";
+pub const SERVER_NAME_KEY: &str = "conn.server_name";
+pub const REQUESTS_KEY: &str = "conn.requests";
+
+/// Signature opening a binary PROXY protocol v2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest PROXY protocol v1 line, CRLF included.
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+/// A client connection accepted by the server, either plain TCP or TLS over TCP.
+pub enum HttpStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl HttpStream {
+    /// Returns the underlying TCP socket.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            HttpStream::Plain(s) => s,
+            HttpStream::Tls(s) => &s.sock,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, HttpStream::Tls(_))
+    }
+
+    /// Returns the negotiated TLS version and the server name sent by the client, once the
+    /// handshake is done.
+    pub fn tls_info(&self) -> Option<(String, Option<String>)> {
+        let HttpStream::Tls(s) = self else {
+            return None;
+        };
+        let version = match s.conn.protocol_version()? {
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            other => format!("{other:?}"),
+        };
+        Some((version, s.conn.server_name().map(String::from)))
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(s) => s.read(buf),
+            HttpStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for HttpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(s) => s.write(buf),
+            HttpStream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HttpStream::Plain(s) => s.flush(),
+            HttpStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+// =========================================================
+// =================== HttpConnectionInfo ==================
+// =========================================================
+/// The connection a request was received on, as seen by the handlers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpConnectionInfo {
+    /// The address the connection comes from, or the source announced in its PROXY protocol
+    /// header.
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    /// The address of the client, recovered from the forwarding headers when the peer is a
+    /// trusted proxy.
+    pub client_ip: Option<IpAddr>,
+    /// The negotiated TLS version, for TLS connections.
+    pub tls_version: Option<String>,
+    /// The server name the client asked for during the TLS handshake.
+    pub server_name: Option<String>,
+    /// Number of requests received on the connection, this one included.
+    pub requests: usize,
+}
+
+impl HttpConnectionInfo {
+    pub fn is_tls(&self) -> bool {
+        self.tls_version.is_some()
+    }
+
+    /// Returns a copy of `params` carrying the connection info, as passed to the handlers.
+    pub fn with_context(&self, params: &HttpServerContext) -> HttpServerContext {
+        let mut params = params.clone();
+        let mut set = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                params.insert(key.to_string(), value);
+            }
+        };
+        set(PEER_ADDR_KEY, self.peer_addr.map(|a| a.to_string()));
+        set(LOCAL_ADDR_KEY, self.local_addr.map(|a| a.to_string()));
+        set(CLIENT_IP_KEY, self.client_ip.map(|a| a.to_string()));
+        set(TLS_VERSION_KEY, self.tls_version.clone());
+        set(SERVER_NAME_KEY, self.server_name.clone());
+        set(REQUESTS_KEY, Some(self.requests.to_string()));
+        params
+    }
+}
+
+/// Access to the connection of a request from the handlers and middlewares.
+pub trait HttpConnectionContext {
+    fn connection(&self) -> Option<HttpConnectionInfo>;
+}
+
+impl HttpConnectionContext for HttpServerContext {
+    fn connection(&self) -> Option<HttpConnectionInfo> {
+        let addr = |key: &str| self.get(key).and_then(|v| v.parse().ok());
+        Some(HttpConnectionInfo {
+            peer_addr: addr(PEER_ADDR_KEY),
+            local_addr: addr(LOCAL_ADDR_KEY),
+            client_ip: self.get(CLIENT_IP_KEY).and_then(|v| v.parse().ok()),
+            tls_version: self.get(TLS_VERSION_KEY).cloned(),
+            server_name: self.get(SERVER_NAME_KEY).cloned(),
+            requests: self.get(REQUESTS_KEY)?.parse().ok()?,
+        })
+    }
+}
+
+// =========================================================
+// ==================== PROXY protocol =====================
+// =========================================================
+/// The addresses announced by a PROXY protocol header, absent for health checks sent by the
+/// proxy itself and for unknown protocols.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+fn invalid_proxy_header(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {message}"),
+    )
+}
+
+/// Reads the PROXY protocol v1 or v2 header opening a connection, without reading past it.
+pub fn read_proxy_header<R: Read>(reader: &mut R) -> io::Result<ProxyHeader> {
+    let mut start = [0u8; 5];
+    reader.read_exact(&mut start)?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= PROXY_V1_MAX_LENGTH {
+                return Err(invalid_proxy_header("v1 line too long"));
+            }
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid_proxy_header("not ascii"))?;
+        return parse_proxy_v1(line.trim_end());
+    }
+    if start != PROXY_V2_SIGNATURE[..5] {
+        return Err(invalid_proxy_header("missing signature"));
+    }
+    let mut header = [0u8; 11];
+    reader.read_exact(&mut header)?;
+    if header[..7] != PROXY_V2_SIGNATURE[5..] || header[7] >> 4 != 2 {
+        return Err(invalid_proxy_header("bad v2 signature or version"));
+    }
+    let mut payload = vec![0u8; u16::from_be_bytes([header[9], header[10]]) as usize];
+    reader.read_exact(&mut payload)?;
+    parse_proxy_v2(header[7] & 0x0f, header[8], &payload)
+}
+
+fn parse_proxy_v1(line: &str) -> io::Result<ProxyHeader> {
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader {
+            source: None,
+            destination: None,
+        }),
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip = IpAddr::from_str(ip).map_err(|_| invalid_proxy_header("bad address"))?;
+                let port = u16::from_str(port).map_err(|_| invalid_proxy_header("bad port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(ProxyHeader {
+                source: Some(addr(src, src_port)?),
+                destination: Some(addr(dst, dst_port)?),
+            })
+        }
+        _ => Err(invalid_proxy_header("malformed v1 line")),
+    }
+}
+
+fn parse_proxy_v2(command: u8, family: u8, payload: &[u8]) -> io::Result<ProxyHeader> {
+    let unknown = ProxyHeader {
+        source: None,
+        destination: None,
+    };
+    match command {
+        0 => return Ok(unknown),
+        1 => {}
+        _ => return Err(invalid_proxy_header("unknown v2 command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = |at: usize| {
+                let octets: [u8; 4] = payload[at..at + 4].try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(octets))
+            };
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(8))),
+                destination: Some(SocketAddr::new(ip(4), port(10))),
+            })
+        }
+        2 if payload.len() >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = payload[at..at + 16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(32))),
+                destination: Some(SocketAddr::new(ip(16), port(34))),
+            })
+        }
+        1 | 2 => Err(invalid_proxy_header("truncated v2 addresses")),
+        _ => Ok(unknown),
+    }
+}
+
+// =========================================================
+// ==================== TrustedProxies =====================
+// =========================================================
+/// The proxies whose `Forwarded` and `X-Forwarded-For` headers are believed, as addresses or
+/// CIDR blocks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Self {
+        TrustedProxies::default()
+    }
+
+    /// Trusts `network`, an address such as `10.0.0.1` or a block such as `10.0.0.0/8`.
+    pub fn trust(mut self, network: &str) -> Result<Self, String> {
+        let (ip, prefix) = match network.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (network, None),
+        };
+        let ip = IpAddr::from_str(ip.trim()).map_err(|_| format!("`{ip}` is not an address"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("`{p}` is not a prefix length"))?,
+            None => max,
+        };
+        self.networks.push((ip, prefix));
+        Ok(self)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(n), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(*n) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(n), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(*n) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            })
+    }
+
+    /// The hops listed by the forwarding headers, closest last. `Forwarded` is preferred
+    /// over `X-Forwarded-For`; unparsable hops such as `unknown` are kept as `None`.
+    fn hops(headers: &HttpHeaders) -> Vec<Option<IpAddr>> {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        if let Some(forwarded) = header("Forwarded") {
+            return forwarded
+                .split(',')
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.trim().split_once('='))
+                        .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                        .and_then(|(_, v)| parse_node(v.trim_matches('"')))
+                })
+                .collect();
+        }
+        match header("X-Forwarded-For") {
+            Some(xff) => xff.split(',').map(|hop| parse_node(hop.trim())).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the client address of a request received from `peer`: the first address of
+    /// the forwarding headers not belonging to a trusted proxy, walking from the closest hop.
+    pub fn client_ip(&self, peer: IpAddr, metadata: &HttpRequestMetaData) -> IpAddr {
+        let mut client = peer;
+        if !self.contains(peer) {
+            return client;
+        }
+        for hop in Self::hops(&metadata.headers).into_iter().rev() {
+            match hop {
+                Some(ip) => client = ip,
+                None => break,
+            }
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Parses a forwarded node: an address, with an optional port, IPv6 ones in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip());
+    }
+    let bracketed = node.strip_prefix('[')?.split(']').next()?;
+    IpAddr::from_str(bracketed).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(headers: &[(&str, &str)]) -> HttpRequestMetaData {
+        let mut raw = "GET / HTTP/1.1\nHost: localhost\nAccept: */*\n".to_string();
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\n"));
+        }
+        HttpRequestMetaData::parse(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_read_proxy_header_v1() {
+        let mut raw: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        let header = read_proxy_header(&mut raw).unwrap();
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("198.51.100.1:443".parse().unwrap())
+        );
+        assert_eq!(raw, b"GET /");
+
+        let mut raw: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n";
+        let header = read_proxy_header(&mut raw).unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:1".parse().unwrap()));
+        let mut raw: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut raw).unwrap().source, None);
+
+        for raw in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            &[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(40),
+        ] {
+            assert!(read_proxy_header(&mut &raw[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_proxy_header_v2() {
+        let mut raw = PROXY_V2_SIGNATURE.to_vec();
+        raw.extend([
+            0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb,
+        ]);
+        raw.extend(b"GET /");
+        let mut reader = &raw[..];
+        let header = read_proxy_header(&mut reader).unwrap();
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("198.51.100.1:443".parse().unwrap())
+        );
+        assert_eq!(reader, b"GET /");
+
+        let mut local = PROXY_V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_proxy_header(&mut &local[..]).unwrap().source, None);
+
+        let mut truncated = PROXY_V2_SIGNATURE.to_vec();
+        truncated.extend([0x21, 0x11, 0, 4, 1, 2, 3, 4]);
+        assert!(read_proxy_header(&mut &truncated[..]).is_err());
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted = TrustedProxies::new()
+            .trust("10.0.0.0/8")
+            .unwrap()
+            .trust("::1")
+            .unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("::1".parse().unwrap()));
+        assert!(!trusted.contains("11.0.0.1".parse().unwrap()));
+        assert!(TrustedProxies::new().trust("10.0.0.0/33").is_err());
+        assert!(TrustedProxies::new().trust("localhost").is_err());
+        assert!(TrustedProxies::new()
+            .trust("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+
+        let ip = |peer: &str, headers: &[(&str, &str)]| {
+            trusted
+                .client_ip(peer.parse().unwrap(), &metadata(headers))
+                .to_string()
+        };
+        let xff = [("X-Forwarded-For", "203.0.113.9, 198.51.100.7, 10.0.0.2")];
+        assert_eq!(ip("10.0.0.1", &xff), "198.51.100.7");
+        assert_eq!(ip("192.0.2.1", &xff), "192.0.2.1");
+        assert_eq!(ip("10.0.0.1", &[]), "10.0.0.1");
+        assert_eq!(
+            ip("10.0.0.1", &[("X-Forwarded-For", "unknown, 10.0.0.3")]),
+            "10.0.0.3"
+        );
+        let forwarded = [
+            (
+                "Forwarded",
+                "for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\"",
+            ),
+            ("X-Forwarded-For", "203.0.113.9"),
+        ];
+        assert_eq!(ip("::1", &forwarded), "2001:db8:cafe::17");
+    }
+
+    #[test]
+    fn test_connection_context() {
+        assert_eq!(HttpServerContext::new().connection(), None);
+        let info = HttpConnectionInfo {
+            peer_addr: Some("10.0.0.1:5000".parse().unwrap()),
+            local_addr: Some("127.0.0.1:443".parse().unwrap()),
+            client_ip: Some("203.0.113.9".parse().unwrap()),
+            tls_version: Some("TLSv1.3".to_string()),
+            server_name: None,
+            requests: 1,
+        };
+        let params = info.with_context(&HttpServerContext::new());
+        assert_eq!(params.get(REQUESTS_KEY).map(String::as_str), Some("1"));
+        assert_eq!(params.connection(), Some(info));
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
+
+use crate::common::{http_date, HttpHeaders};
+
+/// Parses a `Cookie` request header into its `name=value` pairs. The first occurrence of a
+/// name wins and double quotes around values are removed.
+pub fn parse_cookie_header(header: &str) -> BTreeMap<String, String> {
+    let mut cookies = BTreeMap::new();
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        cookies
+            .entry(name.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+    cookies
+}
+
+/// Appends a `Set-Cookie` header to `headers`. Several cookies are kept on separate lines of
+/// the same entry, which responses write as separate headers.
+pub fn add_cookie(headers: &mut HttpHeaders, cookie: &SetCookie) {
+    let name = headers
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case("Set-Cookie"))
+        .cloned()
+        .unwrap_or_else(|| "Set-Cookie".to_string());
+    headers
+        .entry(name)
+        .and_modify(|v| *v = format!("{v}\n{cookie}"))
+        .or_insert_with(|| cookie.to_string());
+}
+
+// =========================================================
+// ====================== SetCookie ========================
+// =========================================================
+/// Whether `name` is a token, as cookie names must be.
+pub(crate) fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Whether `b` can appear in a cookie value: printable ASCII without spaces, double quotes,
+/// commas, semicolons and backslashes.
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        write!(f, "{value}")
+    }
+}
+
+/// A cookie sent with a `Set-Cookie` response header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// A cookie `name` holding `value`, optionally double quoted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when `name` is not a token or `value` holds characters a cookie value
+    /// cannot, such as spaces, commas, semicolons or backslashes, as defined by RFC 6265.
+    pub fn new(name: &str, value: &str) -> Result<Self, String> {
+        if !is_token(name) {
+            return Err(format!("invalid cookie name {name:?}"));
+        }
+        let unquoted = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        if !unquoted.bytes().all(is_cookie_octet) {
+            return Err(format!("invalid value for cookie {name}: {value:?}"));
+        }
+        Ok(SetCookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    /// A cookie telling the client to drop the cookie `name` set on `path`.
+    pub fn removal(name: &str, path: &str) -> Result<Self, String> {
+        Ok(SetCookie::new(name, "")?
+            .path(path)?
+            .max_age(Duration::ZERO))
+    }
+
+    /// Sets the `Path` attribute, which cannot hold control characters or semicolons.
+    pub fn path(mut self, path: &str) -> Result<Self, String> {
+        if !path.bytes().all(|b| (0x20..0x7f).contains(&b) && b != b';') {
+            return Err(format!("invalid path for cookie {}: {path:?}", self.name));
+        }
+        self.path = Some(path.to_string());
+        Ok(self)
+    }
+
+    /// Sets the `Domain` attribute, a host name whose leading dot is optional.
+    pub fn domain(mut self, domain: &str) -> Result<Self, String> {
+        let host = domain.strip_prefix('.').unwrap_or(domain);
+        let valid = host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+        if !valid {
+            return Err(format!(
+                "invalid domain for cookie {}: {domain:?}",
+                self.name
+            ));
+        }
+        self.domain = Some(domain.to_string());
+        Ok(self)
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Sets the `SameSite` attribute. Browsers reject `SameSite=None` on insecure cookies,
+    /// so it turns `Secure` on.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        if same_site == SameSite::None {
+            self.secure = true;
+        }
+        self
+    }
+}
+
+impl Display for SetCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = self.path.as_ref() {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = self.domain.as_ref() {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_parse_cookie_header() {
+        let cookies = parse_cookie_header("a=1; b=\"two words\";c=; a=ignored; junk; =x");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["a"], "1");
+        assert_eq!(cookies["b"], "two words");
+        assert_eq!(cookies["c"], "");
+        assert!(parse_cookie_header("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_cookie_header_values() {
+        let cookies = parse_cookie_header("  token = a=b==  ;half=\"open; empty=\"\"; sp=x y");
+        assert_eq!(cookies["token"], "a=b==");
+        assert_eq!(cookies["half"], "\"open");
+        assert_eq!(cookies["empty"], "");
+        assert_eq!(cookies["sp"], "x y");
+        assert_eq!(cookies.len(), 4);
+    }
+
+    #[test]
+    fn test_set_cookie_display() {
+        assert_eq!(SetCookie::new("id", "42").unwrap().to_string(), "id=42");
+        let cookie = SetCookie::new("id", "42")
+            .unwrap()
+            .path("/")
+            .unwrap()
+            .domain("example.com")
+            .unwrap()
+            .max_age(Duration::from_secs(3600))
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            cookie.to_string(),
+            "id=42; Path=/; Domain=example.com; Max-Age=3600; \
+            Expires=Sun, 06 Nov 1994 08:49:37 GMT; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(
+            SetCookie::new("a", "b")
+                .unwrap()
+                .same_site(SameSite::None)
+                .to_string(),
+            "a=b; Secure; SameSite=None"
+        );
+        assert_eq!(
+            SetCookie::removal("id", "/").unwrap().to_string(),
+            "id=; Path=/; Max-Age=0"
+        );
+    }
+
+    #[test]
+    fn test_set_cookie_attributes() {
+        assert_eq!(
+            SetCookie::new("id", "42")
+                .unwrap()
+                .secure(true)
+                .same_site(SameSite::Strict)
+                .to_string(),
+            "id=42; Secure; SameSite=Strict"
+        );
+        // `SameSite=None` turns `Secure` on, even when it was turned off before.
+        let cookie = SetCookie::new("id", "42")
+            .unwrap()
+            .secure(false)
+            .same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "id=42; Secure; SameSite=None");
+        assert_eq!(
+            SetCookie::new("id", "42")
+                .unwrap()
+                .http_only(true)
+                .http_only(false)
+                .max_age(Duration::from_millis(1500))
+                .to_string(),
+            "id=42; Max-Age=1"
+        );
+        let parsed = parse_cookie_header(
+            &SetCookie::new("id", "42")
+                .unwrap()
+                .path("/")
+                .unwrap()
+                .to_string(),
+        );
+        assert_eq!(parsed["id"], "42");
+    }
+
+    #[test]
+    fn test_add_cookie() {
+        let mut headers = HttpHeaders::new();
+        add_cookie(&mut headers, &SetCookie::new("a", "1").unwrap());
+        add_cookie(&mut headers, &SetCookie::new("b", "2").unwrap());
+        assert_eq!(headers["Set-Cookie"], "a=1\nb=2");
+
+        let mut headers = HttpHeaders::new();
+        headers.insert("set-cookie".to_string(), "a=1".to_string());
+        add_cookie(&mut headers, &SetCookie::new("b", "2").unwrap());
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["set-cookie"], "a=1\nb=2");
+    }
+
+    #[test]
+    fn test_set_cookie_validation() {
+        assert_eq!(
+            SetCookie::new("id", "\"quoted\"").unwrap().to_string(),
+            "id=\"quoted\""
+        );
+        assert!(SetCookie::new("__Host-id", "a.b_c-d=").is_ok());
+        for (name, value) in [
+            ("", "1"),
+            ("a b", "1"),
+            ("a=b", "1"),
+            ("a;", "1"),
+            ("id", "two words"),
+            ("id", "a;b"),
+            ("id", "a,b"),
+            ("id", "a\\b"),
+            ("id", "\"open"),
+            ("id", "line\r\nSet-Cookie: x=y"),
+            ("id", "é"),
+        ] {
+            assert!(SetCookie::new(name, value).is_err(), "{name}={value}");
+        }
+        let cookie = || SetCookie::new("id", "1").unwrap();
+        assert!(cookie().path("/a b").is_ok());
+        assert!(cookie().path("/a;Secure").is_err());
+        assert!(cookie().path("/\n").is_err());
+        assert!(cookie().domain(".example.com").is_ok());
+        for domain in ["", ".", "a..b", "a.com;x", "a b.com"] {
+            assert!(cookie().domain(domain).is_err(), "{domain}");
+        }
+        assert!(SetCookie::removal("id", "/;").is_err());
+    }
+}
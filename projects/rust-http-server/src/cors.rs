@@ -0,0 +1,498 @@
+use std::time::Duration;
+
+use log::debug;
+use regex::Regex;
+
+use crate::{
+    common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext, HttpStatus},
+    request::HttpRequest,
+    response::HttpResponse,
+    router::HttpRouterFunc,
+};
+
+/// Appends `value` to the `Vary` header of `headers`, keeping the values already listed.
+pub fn append_vary(headers: &mut HttpHeaders, value: &str) {
+    let key = headers
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case("Vary"))
+        .cloned()
+        .unwrap_or_else(|| "Vary".to_string());
+    let vary = match headers.remove(&key) {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case(value)) =>
+        {
+            vary
+        }
+        Some(vary) => format!("{vary}, {value}"),
+        None => value.to_string(),
+    };
+    headers.insert(key, vary);
+}
+
+#[derive(Clone, Debug)]
+enum CorsOrigin {
+    Exact(String),
+    Pattern(Regex),
+}
+
+/// Cross-origin resource sharing policy of a router.
+///
+/// Answers the preflight `OPTIONS` requests of allowed origins itself and adds the
+/// `Access-Control-*` headers to the responses of the other requests. Requests without an
+/// `Origin` header, or from origins that are not allowed, are passed through untouched.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    any_origin: bool,
+    origins: Vec<CorsOrigin>,
+    methods: Vec<HttpMethod>,
+    any_header: bool,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            any_origin: false,
+            origins: Vec::new(),
+            methods: HttpMethod::ALL.to_vec(),
+            any_header: false,
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    /// A policy allowing no origin, every method and no request headers.
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    /// Allows every origin, answered with `*`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if credentials are allowed, as any site could then make credentialed requests.
+    pub fn allow_any_origin(mut self) -> Self {
+        assert!(
+            !self.credentials,
+            "credentials cannot be allowed to any origin"
+        );
+        self.any_origin = true;
+        self
+    }
+
+    /// Allows `origin`, such as `https://app.example.com`, compared case-insensitively.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let origin = origin.trim_end_matches('/').to_string();
+        self.origins.push(CorsOrigin::Exact(origin));
+        self
+    }
+
+    /// Allows the origins matching `pattern`, which should be anchored: an unanchored pattern
+    /// also matches origins that merely contain an allowed one.
+    pub fn allow_origin_pattern(mut self, pattern: Regex) -> Self {
+        self.origins.push(CorsOrigin::Pattern(pattern));
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[HttpMethod]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    pub fn allow_any_header(mut self) -> Self {
+        self.any_header = true;
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Response headers, besides the CORS-safelisted ones, scripts are allowed to read.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Allows cookies and credentials.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any origin is allowed, see [`Cors::allow_any_origin`].
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        assert!(
+            !(credentials && self.any_origin),
+            "credentials cannot be allowed to any origin"
+        );
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub(crate) fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.origins.iter().any(|allowed| match allowed {
+                CorsOrigin::Exact(allowed) => allowed.eq_ignore_ascii_case(origin),
+                CorsOrigin::Pattern(re) => re.is_match(origin),
+            })
+    }
+
+    /// Whether the allowed origin header depends on the request `Origin`, in which case the
+    /// responses must carry `Vary: Origin` for caches.
+    fn varies_by_origin(&self) -> bool {
+        !self.any_origin
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        self.any_header
+            || requested
+                .split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)))
+    }
+
+    fn add_origin_headers(&self, headers: &mut HttpHeaders, origin: &str) {
+        let allowed = match self.varies_by_origin() {
+            true => origin,
+            false => "*",
+        };
+        headers.insert(
+            "Access-Control-Allow-Origin".to_string(),
+            allowed.to_string(),
+        );
+        if self.credentials {
+            headers.insert(
+                "Access-Control-Allow-Credentials".to_string(),
+                "true".to_string(),
+            );
+        }
+    }
+
+    fn preflight(&self, req: &HttpRequest, origin: &str, method: &str) -> HttpResponse {
+        let requested_headers = req
+            .metadata
+            .header("Access-Control-Request-Headers")
+            .unwrap_or("");
+        let mut headers = HttpHeaders::new();
+        append_vary(&mut headers, "Origin");
+        append_vary(&mut headers, "Access-Control-Request-Method");
+        append_vary(&mut headers, "Access-Control-Request-Headers");
+        let method_allowed = method
+            .parse::<HttpMethod>()
+            .is_ok_and(|m| self.methods.contains(&m));
+        if !self.allows_origin(origin) || !method_allowed || !self.allows_headers(requested_headers)
+        {
+            debug!("Cors: refused preflight from {origin} for {method} {requested_headers}");
+            return HttpResponse::new(req.metadata.protocol, HttpStatus::Forbidden, headers, None);
+        }
+        self.add_origin_headers(&mut headers, origin);
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<String>>();
+        headers.insert(
+            "Access-Control-Allow-Methods".to_string(),
+            methods.join(", "),
+        );
+        let allowed_headers = match self.any_header {
+            true => requested_headers.to_string(),
+            false => self.headers.join(", "),
+        };
+        if !allowed_headers.is_empty() {
+            headers.insert("Access-Control-Allow-Headers".to_string(), allowed_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(
+                "Access-Control-Max-Age".to_string(),
+                max_age.as_secs().to_string(),
+            );
+        }
+        HttpResponse::new(req.metadata.protocol, HttpStatus::NoContent, headers, None)
+    }
+
+    /// Applies the policy around `next`, answering preflight requests without calling it.
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+        next: &HttpRouterFunc,
+    ) -> Result<HttpResponse, HttpError> {
+        let origin = match req.metadata.header("Origin") {
+            Some(origin) => origin,
+            None => return next(req, params),
+        };
+        let preflight_method = req.metadata.header("Access-Control-Request-Method");
+        if let (HttpMethod::OPTIONS, Some(method)) = (req.metadata.method, preflight_method) {
+            return Ok(self.preflight(req, origin, method));
+        }
+        let allowed = self.allows_origin(origin);
+        let add_headers = |headers: &mut HttpHeaders| {
+            if self.varies_by_origin() {
+                append_vary(headers, "Origin");
+            }
+            if allowed {
+                self.add_origin_headers(headers, origin);
+                if !self.expose_headers.is_empty() {
+                    headers.insert(
+                        "Access-Control-Expose-Headers".to_string(),
+                        self.expose_headers.join(", "),
+                    );
+                }
+            }
+        };
+        match next(req, params) {
+            Ok(mut response) => {
+                add_headers(&mut response.metadata.headers);
+                Ok(response)
+            }
+            // Browsers only expose error responses to scripts when they carry the headers too.
+            Err(mut e) if allowed => {
+                add_headers(&mut e.headers);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::HttpRequestMetaData, router::HttpRouterBuilder};
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut raw = format!("{method} /users HTTP/1.1\r\nHost: api.example.com\r\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    fn router(cors: Cors) -> crate::router::HttpRouter {
+        HttpRouterBuilder::new()
+            .add_cors(cors)
+            .add_route(HttpMethod::GET, "/users", |r, _| {
+                let mut headers = HttpHeaders::new();
+                headers.insert("Vary".to_string(), "Accept".to_string());
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    headers,
+                    None,
+                ))
+            })
+            .add_route(HttpMethod::DELETE, "/users", |_, _| {
+                Err(HttpError::new(HttpStatus::NotFound, ""))
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn policy() -> Cors {
+        Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_origin_pattern(
+                Regex::new(r"^https://[a-z0-9]+\.preview\.example\.com$").unwrap(),
+            )
+            .allow_methods(&[HttpMethod::GET, HttpMethod::DELETE])
+            .allow_headers(&["Content-Type", "Authorization"])
+            .expose_headers(&["X-Total-Count"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600))
+    }
+
+    #[test]
+    fn test_append_vary() {
+        let mut headers = HttpHeaders::new();
+        append_vary(&mut headers, "Origin");
+        assert_eq!(headers["Vary"], "Origin");
+        append_vary(&mut headers, "origin");
+        append_vary(&mut headers, "Accept");
+        assert_eq!(headers["Vary"], "Origin, Accept");
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let router = router(policy());
+        let r = request(
+            "OPTIONS",
+            &[
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Method", "DELETE"),
+                (
+                    "Access-Control-Request-Headers",
+                    "content-type, authorization",
+                ),
+            ],
+        );
+        let response = router.route(&r).unwrap();
+        let headers = &response.metadata.headers;
+        assert_eq!(response.metadata.status, HttpStatus::NoContent);
+        assert_eq!(
+            headers["Access-Control-Allow-Origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(headers["Access-Control-Allow-Methods"], "GET, DELETE");
+        assert_eq!(
+            headers["Access-Control-Allow-Headers"],
+            "Content-Type, Authorization"
+        );
+        assert_eq!(headers["Access-Control-Allow-Credentials"], "true");
+        assert_eq!(headers["Access-Control-Max-Age"], "600");
+        assert_eq!(
+            headers["Vary"],
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers"
+        );
+
+        for refused in [
+            [
+                ("Origin", "https://evil.example.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+            [
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Method", "PUT"),
+            ],
+            [
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Headers", "X-Secret"),
+            ],
+        ] {
+            let mut headers = refused.to_vec();
+            if headers[1].0 != "Access-Control-Request-Method" {
+                headers.push(("Access-Control-Request-Method", "GET"));
+            }
+            let response = router.route(&request("OPTIONS", &headers)).unwrap();
+            assert_eq!(response.metadata.status, HttpStatus::Forbidden);
+            assert!(!response
+                .metadata
+                .headers
+                .contains_key("Access-Control-Allow-Origin"));
+        }
+        // Without a preflight, OPTIONS keeps reaching the router.
+        assert_eq!(
+            router.route(&request("OPTIONS", &[])).err().unwrap().status,
+            HttpStatus::MethodNotAllowed
+        );
+    }
+
+    #[test]
+    fn test_cors_actual_request() {
+        let router = router(policy());
+        let r = request("GET", &[("Origin", "https://pr1.preview.example.com")]);
+        let response = router.route(&r).unwrap();
+        let headers = &response.metadata.headers;
+        assert_eq!(
+            headers["Access-Control-Allow-Origin"],
+            "https://pr1.preview.example.com"
+        );
+        assert_eq!(headers["Access-Control-Expose-Headers"], "X-Total-Count");
+        assert_eq!(headers["Vary"], "Accept, Origin");
+
+        let r = request("DELETE", &[("Origin", "https://app.example.com")]);
+        let e = router.route(&r).err().unwrap();
+        assert_eq!(e.status, HttpStatus::NotFound);
+        assert!(e.headers.contains_key("Access-Control-Allow-Origin"));
+
+        let r = request("GET", &[("Origin", "https://evil.example.com")]);
+        let response = router.route(&r).unwrap();
+        assert!(!response
+            .metadata
+            .headers
+            .contains_key("Access-Control-Allow-Origin"));
+        assert_eq!(response.metadata.headers["Vary"], "Accept, Origin");
+
+        let response = router.route(&request("GET", &[])).unwrap();
+        assert_eq!(response.metadata.headers["Vary"], "Accept");
+    }
+
+    #[test]
+    fn test_cors_group_preflight() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/health", |_, _| {
+                Err(HttpError::new(HttpStatus::ImATeapot, ""))
+            })
+            .group("/api", |api| {
+                api.add_cors(Cors::new().allow_any_origin()).add_route(
+                    HttpMethod::POST,
+                    "/users",
+                    |_, _| Err(HttpError::new(HttpStatus::ImATeapot, "")),
+                );
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            router.route_table(),
+            "OPTIONS,POST /api/users\nGET          /health\n"
+        );
+        let preflight = |uri: &str| {
+            let raw = format!(
+                "OPTIONS {uri} HTTP/1.1\nOrigin: https://a.test\nAccess-Control-Request-Method: POST\n"
+            );
+            router.route(&HttpRequest {
+                metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+                body: None,
+                stream: None,
+            })
+        };
+        assert_eq!(
+            preflight("/api/users").unwrap().metadata.status,
+            HttpStatus::NoContent
+        );
+        assert_eq!(
+            preflight("/health").err().unwrap().status,
+            HttpStatus::MethodNotAllowed
+        );
+    }
+
+    #[test]
+    fn test_cors_any_origin() {
+        let router = router(Cors::new().allow_any_origin().allow_any_header());
+        let r = request("GET", &[("Origin", "https://anywhere.test")]);
+        let response = router.route(&r).unwrap();
+        assert_eq!(
+            response.metadata.headers["Access-Control-Allow-Origin"],
+            "*"
+        );
+        assert_eq!(response.metadata.headers["Vary"], "Accept");
+        let r = request(
+            "OPTIONS",
+            &[
+                ("Origin", "https://anywhere.test"),
+                ("Access-Control-Request-Method", "GET"),
+                ("Access-Control-Request-Headers", "X-Anything"),
+            ],
+        );
+        let response = router.route(&r).unwrap();
+        assert_eq!(
+            response.metadata.headers["Access-Control-Allow-Headers"],
+            "X-Anything"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "credentials cannot be allowed to any origin")]
+    fn test_cors_any_origin_refuses_credentials() {
+        let _ = Cors::new().allow_credentials(true).allow_any_origin();
+    }
+}
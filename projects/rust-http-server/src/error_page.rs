@@ -0,0 +1,248 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use serde_json::json;
+
+use crate::{
+    common::{HttpError, HttpProtocol, HttpStatus},
+    cors::append_vary,
+    request::HttpRequestMetaData,
+    request_id::REQUEST_ID_HEADER,
+    response::HttpResponse,
+    static_files::content_type,
+};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+const HTML: &str = "text/html; charset=utf-8";
+const TEXT: &str = "text/plain; charset=utf-8";
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A page replacing the generated body of the errors of one status.
+#[derive(Clone, Debug)]
+struct ErrorPage {
+    content_type: String,
+    template: String,
+}
+
+/// Renders handler errors into response bodies, negotiated on the `Accept` header between
+/// `application/problem+json` (RFC 9457), HTML and plain text.
+///
+/// Error messages can reveal internals, so they are only rendered once `expose_messages` is
+/// set. Custom pages are templates where `{status}`, `{title}`, `{detail}` and `{request_id}`
+/// are replaced, HTML-escaped for HTML pages.
+#[derive(Clone, Debug, Default)]
+pub struct ErrorPages {
+    expose_messages: bool,
+    pages: HashMap<u16, ErrorPage>,
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        ErrorPages::default()
+    }
+
+    pub fn expose_messages(mut self, expose: bool) -> Self {
+        self.expose_messages = expose;
+        self
+    }
+
+    pub fn page(mut self, status: HttpStatus, content_type: &str, template: &str) -> Self {
+        self.pages.insert(
+            status as u16,
+            ErrorPage {
+                content_type: content_type.to_string(),
+                template: template.to_string(),
+            },
+        );
+        self
+    }
+
+    /// Serves the file at `path` for errors of `status`, its type guessed from its extension.
+    pub fn page_file(self, status: HttpStatus, path: &Path) -> io::Result<Self> {
+        let template = fs::read_to_string(path)?;
+        Ok(self.page(status, content_type(path), &template))
+    }
+
+    fn render_page(page: &ErrorPage, status: HttpStatus, detail: &str, id: &str) -> String {
+        let escape = |s: &str| match page.content_type.starts_with("text/html") {
+            true => escape_html(s),
+            false => s.to_string(),
+        };
+        page.template
+            .replace("{status}", &(status as u16).to_string())
+            .replace("{title}", &escape(status.message()))
+            .replace("{detail}", &escape(detail))
+            .replace("{request_id}", &escape(id))
+    }
+
+    fn problem_json(
+        status: HttpStatus,
+        detail: Option<&str>,
+        id: Option<&str>,
+        uri: Option<&str>,
+    ) -> String {
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": status.message(),
+            "status": status as u16,
+        });
+        if let Some(detail) = detail {
+            problem["detail"] = json!(detail);
+        }
+        if let Some(uri) = uri {
+            problem["instance"] = json!(uri.split(['?', '#']).next().unwrap_or(uri));
+        }
+        if let Some(id) = id {
+            problem["request_id"] = json!(id);
+        }
+        problem.to_string()
+    }
+
+    fn html(status: HttpStatus, detail: Option<&str>, id: Option<&str>) -> String {
+        let title = format!("{} {}", status as u16, status.message());
+        let mut body = format!(
+            "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n"
+        );
+        if let Some(detail) = detail {
+            body.push_str(&format!("<p>{}</p>\n", escape_html(detail)));
+        }
+        if let Some(id) = id {
+            body.push_str(&format!(
+                "<p><small>Request ID: {}</small></p>\n",
+                escape_html(id)
+            ));
+        }
+        body.push_str("</body>\n</html>\n");
+        body
+    }
+
+    fn text(status: HttpStatus, detail: Option<&str>, id: Option<&str>) -> String {
+        let mut body = format!("{} {}\n", status as u16, status.message());
+        if let Some(detail) = detail {
+            body.push_str(&format!("{detail}\n"));
+        }
+        if let Some(id) = id {
+            body.push_str(&format!("Request ID: {id}\n"));
+        }
+        body
+    }
+
+    /// Renders `err` into the response sent for `request`, keeping the headers of the error.
+    /// Without the request, when it could not be parsed, the body is problem+json.
+    pub fn render(&self, err: HttpError, request: Option<&HttpRequestMetaData>) -> HttpResponse {
+        let status = err.status;
+        let detail = Some(err.message()).filter(|m| self.expose_messages && !m.is_empty());
+        let id = request.and_then(|r| r.header(REQUEST_ID_HEADER));
+        let mut headers = err.headers.clone();
+        let (content_type, body) = match self.pages.get(&(status as u16)) {
+            Some(page) => (
+                page.content_type.clone(),
+                Self::render_page(page, status, detail.unwrap_or(""), id.unwrap_or("")),
+            ),
+            None => {
+                append_vary(&mut headers, "Accept");
+                let offers = [PROBLEM_JSON, "application/json", "text/html", "text/plain"];
+                let preferred = request.and_then(|r| r.preferred_type(&offers));
+                match preferred {
+                    Some("text/html") => (HTML.to_string(), Self::html(status, detail, id)),
+                    Some("text/plain") => (TEXT.to_string(), Self::text(status, detail, id)),
+                    _ => (
+                        PROBLEM_JSON.to_string(),
+                        Self::problem_json(status, detail, id, request.map(|r| r.uri.as_str())),
+                    ),
+                }
+            }
+        };
+        headers.insert("Content-Type".to_string(), content_type);
+        headers.insert("Content-Length".to_string(), body.len().to_string());
+        let protocol = request.map_or(HttpProtocol::Http1_1, |r| r.protocol);
+        HttpResponse::new(protocol, status, headers, Some(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn metadata(headers: &[(&str, &str)]) -> HttpRequestMetaData {
+        let mut raw = "GET /items/7?full=1 HTTP/1.1\nHost: localhost\n".to_string();
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\n"));
+        }
+        HttpRequestMetaData::parse(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_render_negotiates_format() {
+        let pages = ErrorPages::new().expose_messages(true);
+        let err =
+            || HttpError::new(HttpStatus::NotFound, "no item <7>").with_header("X-Reason", "gone");
+
+        let m = metadata(&[("Accept", "*/*"), (REQUEST_ID_HEADER, "abc")]);
+        let response = pages.render(err(), Some(&m));
+        let headers = &response.metadata.headers;
+        assert_eq!(response.metadata.status, HttpStatus::NotFound);
+        assert_eq!(headers["Content-Type"], PROBLEM_JSON);
+        assert_eq!(headers["X-Reason"], "gone");
+        assert_eq!(headers["Vary"], "Accept");
+        let body = response.body.unwrap();
+        assert_eq!(headers["Content-Length"], body.len().to_string());
+        let problem: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank", "title": "Not Found", "status": 404,
+                "detail": "no item <7>", "instance": "/items/7", "request_id": "abc"
+            })
+        );
+
+        let m = metadata(&[("Accept", "text/html,*/*;q=0.8")]);
+        let response = pages.render(err(), Some(&m));
+        assert_eq!(response.metadata.headers["Content-Type"], HTML);
+        assert!(response.body.unwrap().contains("<p>no item &lt;7&gt;</p>"));
+
+        let m = metadata(&[("Accept", "text/plain"), (REQUEST_ID_HEADER, "abc")]);
+        let response = pages.render(err(), Some(&m));
+        assert_eq!(
+            response.body.unwrap(),
+            "404 Not Found\nno item <7>\nRequest ID: abc\n"
+        );
+
+        let response = pages.render(err(), None);
+        assert_eq!(response.metadata.headers["Content-Type"], PROBLEM_JSON);
+    }
+
+    #[test]
+    fn test_render_hides_messages_by_default() {
+        let m = metadata(&[("Accept", "text/plain")]);
+        let e = HttpError::new(HttpStatus::InternalServerError, "db password is hunter2");
+        let response = ErrorPages::new().render(e, Some(&m));
+        assert_eq!(response.body.unwrap(), "500 Internal Server Error\n");
+    }
+
+    #[test]
+    fn test_render_custom_page() {
+        let pages = ErrorPages::new().expose_messages(true).page(
+            HttpStatus::NotFound,
+            HTML,
+            "<h1>{status} {title}</h1><p>{detail}</p><!-- {request_id} -->",
+        );
+        let m = metadata(&[("Accept", "application/json"), (REQUEST_ID_HEADER, "<id>")]);
+        let response = pages.render(HttpError::new(HttpStatus::NotFound, "a & b"), Some(&m));
+        assert_eq!(response.metadata.headers["Content-Type"], HTML);
+        assert!(!response.metadata.headers.contains_key("Vary"));
+        assert_eq!(
+            response.body.unwrap(),
+            "<h1>404 Not Found</h1><p>a &amp; b</p><!-- &lt;id&gt; -->"
+        );
+        let response = pages.render(HttpError::new(HttpStatus::Gone, ""), Some(&m));
+        assert_eq!(response.metadata.headers["Content-Type"], PROBLEM_JSON);
+    }
+}
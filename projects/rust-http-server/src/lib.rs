@@ -0,0 +1,29 @@
+pub mod access_log;
+pub mod auth;
+pub mod chunked;
+pub mod cli;
+pub mod client;
+pub mod common;
+pub mod config;
+pub mod connection;
+pub mod cookie;
+pub mod cors;
+pub mod error_page;
+pub mod metrics;
+pub mod openapi;
+pub mod proxy;
+pub mod rate_limit;
+pub mod reload;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod static_files;
+#[cfg(test)]
+mod testing;
+pub mod thread_pool;
+pub mod tls;
+pub mod upstream;
+pub mod vhost;
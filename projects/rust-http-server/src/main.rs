@@ -1,62 +1,28 @@
-use common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext};
-use log::{debug, error, info};
-use request::HttpRequest;
-use response::HttpResponse;
-use router::HttpRouterBuilder;
-use server::HttpServer;
+use clap::Parser;
+use log::{debug, error, info, warn};
+use rust_http_server::{
+    cli::Cli,
+    common::{self, HttpError, HttpHeaders, HttpMethod, HttpServerContext},
+    config::{Config, ConfigError, DEFAULT_CONFIG},
+    openapi::HttpRouteDoc,
+    reload,
+    request::HttpRequest,
+    response::HttpResponse,
+    router::{HttpPathParams, HttpRouterBuilder},
+};
 use std::{
     fs::File,
     io::{BufReader, Read},
-    net::TcpListener,
+    process,
 };
 
-mod common;
-mod request;
-mod response;
-mod router;
-mod server;
-
-fn handle_static_content(
-    r: &HttpRequest,
-    c: &HttpServerContext,
-) -> Result<HttpResponse, HttpError> {
-    debug!("handle_static_content: called!");
-    if r.metadata.method != HttpMethod::GET {
-        error!("handle_static_content: method not allowed");
-        return Err(HttpError::new(common::HttpStatus::MethodNotAllowed, ""));
-    }
-    let uri = match r.metadata.uri.strip_prefix("/") {
-        Some(h) => h,
-        None => r.metadata.uri.as_str(),
-    };
-    match File::open(uri) {
-        Ok(f) => {
-            let body = BufReader::new(f)
-                .bytes()
-                .map(|x| x.unwrap() as char)
-                .map(String::from)
-                .collect::<String>();
-            Ok(HttpResponse::new(
-                r.metadata.protocol,
-                common::HttpStatus::Ok,
-                HttpHeaders::new(),
-                Some(body),
-            ))
-        }
-        Err(e) => {
-            error!("Static Content Handler: error accessing resource {e}");
-            Err(HttpError::new(common::HttpStatus::NotFound, ""))
-        }
-    }
-}
-
 fn handle_pics(r: &HttpRequest, c: &HttpServerContext) -> Result<HttpResponse, HttpError> {
     debug!("handle_pics: called");
     if r.metadata.method != HttpMethod::GET {
         error!("handle_pics: method not allowed");
         return Err(HttpError::new(common::HttpStatus::MethodNotAllowed, ""));
     }
-    let pic_path = c.get("pic").unwrap();
+    let pic_path: String = c.parse("pic")?;
     match File::open(format!("pics/{pic_path}")) {
         Ok(f) => {
             let body = BufReader::new(f)
@@ -78,19 +44,71 @@ fn handle_pics(r: &HttpRequest, c: &HttpServerContext) -> Result<HttpResponse, H
     }
 }
 
-fn start_server() {
-    let server = HttpServer::new(
-        HttpRouterBuilder::new()
-            .add_route(HttpMethod::GET, "/*", handle_static_content)
-            .add_route(HttpMethod::GET, "/pics/:pic", handle_pics)
-            .build(),
+fn app_routes(builder: &mut HttpRouterBuilder) {
+    builder.add_documented_route(
+        HttpMethod::GET,
+        r"/pics/:pic([\w-]+\.\w+)",
+        HttpRouteDoc::new()
+            .summary("Fetch a picture from the `pics` directory")
+            .tag("pics")
+            .response(common::HttpStatus::Ok, Some("The picture"))
+            .response(common::HttpStatus::NotFound, None),
+        handle_pics,
     );
-    let listener =
-        TcpListener::bind("127.0.0.1:18000").expect("binding address was in use, could not bind.");
-    server.serve(&listener);
+}
+
+/// Reads the configuration file, or the built-in defaults, and applies the command line flags.
+fn load_config(cli: &Cli) -> Result<Config, ConfigError> {
+    let mut config = match cli.config.as_ref() {
+        Some(path) => Config::load(path)?,
+        None => Config::parse(DEFAULT_CONFIG)?,
+    };
+    cli.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+fn start_server(cli: Cli, config: Config) -> Result<(), ConfigError> {
+    let router = config.build_router(app_routes)?;
+    for line in router.route_table().lines() {
+        info!("route: {line}");
+    }
+    let (server, listeners) = config.build_server(router)?;
+    let server = server.with_reloader(move || {
+        let reloaded = load_config(&cli).map_err(|e| e.to_string())?;
+        for section in reloaded.restart_required(&config) {
+            warn!("reload: changes to `{section}` only take effect after a restart");
+        }
+        reloaded
+            .build_router(app_routes)
+            .and_then(|router| reloaded.build_settings(router))
+            .map_err(|e| e.to_string())
+    });
+    if let Some(handle) = server.reload_handle() {
+        if let Err(e) = reload::reload_on_sighup(handle) {
+            error!("cannot listen for SIGHUP, reloading is only available over HTTP -> {e}");
+        }
+    }
+    server.serve_listeners(&listeners);
+    Ok(())
 }
 
 fn main() {
-    env_logger::init();
-    start_server();
+    let cli = Cli::parse();
+    let config = load_config(&cli).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(2);
+    });
+    if cli.check {
+        println!("configuration is valid");
+        return;
+    }
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.log_level().as_str()),
+    )
+    .init();
+    if let Err(e) = start_server(cli, config) {
+        error!("{e}");
+        process::exit(1);
+    }
 }
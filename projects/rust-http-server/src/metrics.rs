@@ -0,0 +1,283 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::common::{HttpMethod, HttpStatus};
+
+/// Upper bounds (in seconds) of the request latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label used for requests that did not match any registered route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// =========================================================
+// ===================== Histogram =========================
+// =========================================================
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        LATENCY_BUCKETS
+            .iter()
+            .zip(self.buckets.iter_mut())
+            .filter(|(bound, _)| value <= **bound)
+            .for_each(|(_, bucket)| *bucket += 1);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// =========================================================
+// ==================== HttpMetrics ========================
+// =========================================================
+type RequestKey = (String, String, String);
+type LatencyKey = (String, String);
+
+/// Registry of the server metrics, rendered in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct HttpMetrics {
+    requests: Mutex<BTreeMap<RequestKey, u64>>,
+    latencies: Mutex<BTreeMap<LatencyKey, Histogram>>,
+    open_connections: AtomicI64,
+    workers: AtomicUsize,
+    busy_workers: AtomicUsize,
+}
+
+impl HttpMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a served request. `route` is the matched route pattern and `method` is `None`
+    /// when the request could not be parsed.
+    pub fn observe_request(
+        &self,
+        route: Option<&str>,
+        method: Option<HttpMethod>,
+        status: HttpStatus,
+        latency: Duration,
+    ) {
+        let route = route.unwrap_or(UNMATCHED_ROUTE).to_string();
+        let method = match method {
+            Some(m) => m.to_string(),
+            None => "unknown".to_string(),
+        };
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((
+                route.clone(),
+                method.clone(),
+                status.status_type().to_string(),
+            ))
+            .or_insert(0) += 1;
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry((route, method))
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn connection_opened(&self) {
+        self.open_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn set_worker_pool_size(&self, workers: usize) {
+        self.workers.store(workers, Ordering::SeqCst);
+    }
+
+    pub fn worker_busy(&self) {
+        self.busy_workers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn worker_idle(&self) {
+        self.busy_workers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP http_requests_total Total number of HTTP requests served."
+        );
+        let _ = writeln!(out, "# TYPE http_requests_total counter");
+        for ((route, method, class), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                method,
+                class,
+                count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP http_request_duration_seconds HTTP request latency in seconds."
+        );
+        let _ = writeln!(out, "# TYPE http_request_duration_seconds histogram");
+        for ((route, method), histogram) in self.latencies.lock().unwrap().iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape_label(route), method);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+
+        for (name, help, value) in [
+            (
+                "http_open_connections",
+                "Number of accepted connections not yet closed.",
+                self.open_connections.load(Ordering::SeqCst),
+            ),
+            (
+                "http_worker_pool_size",
+                "Number of worker threads in the pool.",
+                self.workers.load(Ordering::SeqCst) as i64,
+            ),
+            (
+                "http_worker_pool_busy",
+                "Number of worker threads handling a connection.",
+                self.busy_workers.load(Ordering::SeqCst) as i64,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_observe() {
+        let mut h = Histogram::default();
+        h.observe(0.003);
+        h.observe(0.2);
+        h.observe(20.0);
+        assert_eq!(h.count, 3);
+        assert_eq!(h.buckets[0], 1);
+        assert_eq!(h.buckets[5], 2);
+        assert_eq!(h.buckets[LATENCY_BUCKETS.len() - 1], 2);
+    }
+
+    #[test]
+    fn test_metrics_render_request_counters() {
+        let metrics = HttpMetrics::new();
+        for status in [HttpStatus::Ok, HttpStatus::Created, HttpStatus::NotFound] {
+            metrics.observe_request(
+                Some("/pics/:pic"),
+                Some(HttpMethod::GET),
+                status,
+                Duration::from_millis(3),
+            );
+        }
+        metrics.observe_request(None, None, HttpStatus::BadRequest, Duration::ZERO);
+        let out = metrics.render();
+        assert!(out.contains("# TYPE http_requests_total counter\n"));
+        assert!(out.contains(
+            "http_requests_total{route=\"/pics/:pic\",method=\"GET\",status=\"2xx\"} 2\n"
+        ));
+        assert!(out.contains(
+            "http_requests_total{route=\"/pics/:pic\",method=\"GET\",status=\"4xx\"} 1\n"
+        ));
+        assert!(out.contains(
+            "http_requests_total{route=\"unmatched\",method=\"unknown\",status=\"4xx\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_metrics_render_latency_histogram() {
+        let metrics = HttpMetrics::new();
+        metrics.observe_request(
+            Some("/"),
+            Some(HttpMethod::POST),
+            HttpStatus::Ok,
+            Duration::from_millis(30),
+        );
+        let out = metrics.render();
+        let labels = "route=\"/\",method=\"POST\"";
+        assert!(out.contains(&format!(
+            "http_request_duration_seconds_bucket{{{labels},le=\"0.025\"}} 0\n"
+        )));
+        assert!(out.contains(&format!(
+            "http_request_duration_seconds_bucket{{{labels},le=\"0.05\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "http_request_duration_seconds_count{{{labels}}} 1\n"
+        )));
+    }
+
+    #[test]
+    fn test_metrics_render_gauges() {
+        let metrics = HttpMetrics::new();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.set_worker_pool_size(8);
+        metrics.worker_busy();
+        metrics.worker_busy();
+        metrics.worker_idle();
+        let out = metrics.render();
+        assert!(out.contains("# TYPE http_open_connections gauge\nhttp_open_connections 1\n"));
+        assert!(out.contains("http_worker_pool_size 8\n"));
+        assert!(out.contains("http_worker_pool_busy 1\n"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::{BTreeMap, HashMap};
+
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    common::{HttpMethod, HttpStatus},
+    router::parse_constraints,
+};
+
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+// =========================================================
+// ===================== Route docs ========================
+// =========================================================
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpParamLocation {
+    Path,
+    Query,
+    Header,
+    Cookie,
+}
+
+impl HttpParamLocation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HttpParamLocation::Path => "path",
+            HttpParamLocation::Query => "query",
+            HttpParamLocation::Header => "header",
+            HttpParamLocation::Cookie => "cookie",
+        }
+    }
+}
+
+/// Documentation of a single route parameter.
+#[derive(Clone, Debug)]
+pub struct HttpParamDoc {
+    pub name: String,
+    pub location: HttpParamLocation,
+    pub description: Option<String>,
+    pub required: bool,
+    pub schema: Value,
+}
+
+impl HttpParamDoc {
+    fn new(name: &str, location: HttpParamLocation, required: bool) -> Self {
+        HttpParamDoc {
+            name: name.to_string(),
+            location,
+            description: None,
+            required,
+            schema: json!({ "type": "string" }),
+        }
+    }
+
+    pub fn path(name: &str) -> Self {
+        Self::new(name, HttpParamLocation::Path, true)
+    }
+
+    pub fn query(name: &str) -> Self {
+        Self::new(name, HttpParamLocation::Query, false)
+    }
+
+    pub fn header(name: &str) -> Self {
+        Self::new(name, HttpParamLocation::Header, false)
+    }
+
+    pub fn cookie(name: &str) -> Self {
+        Self::new(name, HttpParamLocation::Cookie, false)
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// Sets the JSON schema of the parameter, a string by default.
+    pub fn schema(mut self, schema: Value) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        let mut param = json!({
+            "name": self.name,
+            "in": self.location.as_str(),
+            "required": self.required,
+            "schema": self.schema,
+        });
+        if let Some(description) = self.description.as_ref() {
+            param["description"] = json!(description);
+        }
+        param
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HttpResponseDoc {
+    status: HttpStatus,
+    description: Option<String>,
+    content: Option<(String, Value)>,
+}
+
+/// Optional documentation of a route, rendered into the OpenAPI document of its router.
+#[derive(Clone, Debug, Default)]
+pub struct HttpRouteDoc {
+    summary: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    parameters: Vec<HttpParamDoc>,
+    request_body: Option<(String, Value)>,
+    responses: Vec<HttpResponseDoc>,
+}
+
+impl HttpRouteDoc {
+    pub fn new() -> Self {
+        HttpRouteDoc::default()
+    }
+
+    pub fn summary(mut self, summary: &str) -> Self {
+        self.summary = Some(summary.to_string());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Documents a parameter. Path parameters are documented from the route path already,
+    /// documenting one here replaces the generated entry.
+    pub fn param(mut self, param: HttpParamDoc) -> Self {
+        self.parameters.push(param);
+        self
+    }
+
+    pub fn request_body(mut self, content_type: &str, schema: Value) -> Self {
+        self.request_body = Some((content_type.to_string(), schema));
+        self
+    }
+
+    pub fn json_request(self, schema: Value) -> Self {
+        self.request_body("application/json", schema)
+    }
+
+    /// Documents a response without a body. The description defaults to the reason phrase.
+    pub fn response(mut self, status: HttpStatus, description: Option<&str>) -> Self {
+        self.responses.push(HttpResponseDoc {
+            status,
+            description: description.map(String::from),
+            content: None,
+        });
+        self
+    }
+
+    pub fn json_response(mut self, status: HttpStatus, description: &str, schema: Value) -> Self {
+        self.responses.push(HttpResponseDoc {
+            status,
+            description: Some(description.to_string()),
+            content: Some(("application/json".to_string(), schema)),
+        });
+        self
+    }
+}
+
+/// The `info` object of an OpenAPI document.
+#[derive(Clone, Debug)]
+pub struct OpenApiInfo {
+    pub title: String,
+    pub version: String,
+    pub description: Option<String>,
+}
+
+impl OpenApiInfo {
+    pub fn new(title: &str, version: &str) -> Self {
+        OpenApiInfo {
+            title: title.to_string(),
+            version: version.to_string(),
+            description: None,
+        }
+    }
+}
+
+// =========================================================
+// ====================== Document =========================
+// =========================================================
+/// Converts a router path into an OpenAPI path template, `/users/:id(\d+)/*` giving
+/// `/users/{id}/{path}`, along with the path parameters it declares.
+fn path_template(path: &str) -> (String, Vec<HttpParamDoc>) {
+    let (pattern, constraints) = parse_constraints(path).unwrap_or((path.to_string(), vec![]));
+    let param = Regex::new(r":([A-Za-z0-9_]+)[?+*]?").unwrap();
+    let mut params = Vec::new();
+    let template = pattern
+        .split('/')
+        .map(|segment| {
+            if segment == "*" {
+                params.push(HttpParamDoc::path("path").description("The remaining path"));
+                return "{path}".to_string();
+            }
+            for name in param.captures_iter(segment).map(|c| c[1].to_string()) {
+                let mut doc = HttpParamDoc::path(&name);
+                if let Some((_, re)) = constraints.iter().find(|(n, _)| *n == name) {
+                    doc = doc.schema(json!({ "type": "string", "pattern": re.as_str() }));
+                }
+                params.push(doc);
+            }
+            param.replace_all(segment, "{$1}").into_owned()
+        })
+        .collect::<Vec<String>>()
+        .join("/");
+    (template, params)
+}
+
+fn operation(path: &str, doc: Option<&HttpRouteDoc>) -> Value {
+    let (_, mut params) = path_template(path);
+    let default_doc = HttpRouteDoc::default();
+    let doc = doc.unwrap_or(&default_doc);
+    for param in doc.parameters.iter() {
+        params.retain(|p| p.name != param.name || p.location != param.location);
+        params.push(param.clone());
+    }
+    let mut operation = Map::new();
+    if let Some(summary) = doc.summary.as_ref() {
+        operation.insert("summary".to_string(), json!(summary));
+    }
+    if let Some(description) = doc.description.as_ref() {
+        operation.insert("description".to_string(), json!(description));
+    }
+    if !doc.tags.is_empty() {
+        operation.insert("tags".to_string(), json!(doc.tags));
+    }
+    if !params.is_empty() {
+        let params = params
+            .iter()
+            .map(HttpParamDoc::to_json)
+            .collect::<Vec<Value>>();
+        operation.insert("parameters".to_string(), json!(params));
+    }
+    if let Some((content_type, schema)) = doc.request_body.as_ref() {
+        operation.insert(
+            "requestBody".to_string(),
+            json!({ "required": true, "content": { content_type: { "schema": schema } } }),
+        );
+    }
+    let mut responses = Map::new();
+    for response in doc.responses.iter() {
+        let description = response
+            .description
+            .as_deref()
+            .unwrap_or(response.status.message());
+        let mut value = json!({ "description": description });
+        if let Some((content_type, schema)) = response.content.as_ref() {
+            value["content"] = json!({ content_type: { "schema": schema } });
+        }
+        responses.insert((response.status as u16).to_string(), value);
+    }
+    if responses.is_empty() {
+        responses.insert("default".to_string(), json!({ "description": "Response" }));
+    }
+    operation.insert("responses".to_string(), Value::Object(responses));
+    Value::Object(operation)
+}
+
+/// Renders the OpenAPI document of `routes`, using the documentation found in `docs`.
+pub fn document(
+    routes: &[(HttpMethod, String)],
+    docs: &HashMap<(HttpMethod, String), HttpRouteDoc>,
+    info: &OpenApiInfo,
+) -> Value {
+    let mut paths: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+    for (method, path) in routes.iter() {
+        let (template, _) = path_template(path);
+        let doc = docs.get(&(*method, path.clone()));
+        paths
+            .entry(template)
+            .or_default()
+            .insert(method.to_string().to_lowercase(), operation(path, doc));
+    }
+    let mut info_json = json!({ "title": info.title, "version": info.version });
+    if let Some(description) = info.description.as_ref() {
+        info_json["description"] = json!(description);
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": info_json,
+        "paths": paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_template() {
+        let (template, params) = path_template(r"/users/:id(\d+)/files/:name.png");
+        assert_eq!(template, "/users/{id}/files/{name}.png");
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].schema["pattern"], r"^(?:\d+)$");
+        assert_eq!(params[1].schema, json!({ "type": "string" }));
+        assert_eq!(path_template("/static/*").0, "/static/{path}");
+        assert_eq!(path_template("/a/:rest+").0, "/a/{rest}");
+        assert_eq!(path_template("/").0, "/");
+    }
+
+    #[test]
+    fn test_document() {
+        let routes = vec![
+            (HttpMethod::GET, r"/users/:id(\d+)".to_string()),
+            (HttpMethod::DELETE, r"/users/:id(\d+)".to_string()),
+            (HttpMethod::POST, "/users".to_string()),
+        ];
+        let mut docs = HashMap::new();
+        docs.insert(
+            (HttpMethod::GET, r"/users/:id(\d+)".to_string()),
+            HttpRouteDoc::new()
+                .summary("Fetch a user")
+                .tag("users")
+                .param(
+                    HttpParamDoc::path("id")
+                        .description("User id")
+                        .schema(json!({ "type": "integer" })),
+                )
+                .param(HttpParamDoc::query("fields"))
+                .json_response(HttpStatus::Ok, "The user", json!({ "type": "object" }))
+                .response(HttpStatus::NotFound, None),
+        );
+        docs.insert(
+            (HttpMethod::POST, "/users".to_string()),
+            HttpRouteDoc::new()
+                .json_request(json!({ "$ref": "#/components/schemas/User" }))
+                .response(HttpStatus::Created, Some("Created")),
+        );
+        let doc = document(&routes, &docs, &OpenApiInfo::new("users", "1.0.0"));
+        assert_eq!(doc["openapi"], OPENAPI_VERSION);
+        assert_eq!(doc["info"], json!({ "title": "users", "version": "1.0.0" }));
+        let get = &doc["paths"]["/users/{id}"]["get"];
+        assert_eq!(get["summary"], "Fetch a user");
+        assert_eq!(get["tags"], json!(["users"]));
+        assert_eq!(
+            get["parameters"],
+            json!([
+                {
+                    "name": "id", "in": "path", "required": true,
+                    "description": "User id", "schema": { "type": "integer" }
+                },
+                { "name": "fields", "in": "query", "required": false, "schema": { "type": "string" } },
+            ])
+        );
+        assert_eq!(
+            get["responses"]["200"]["content"]["application/json"]["schema"]["type"],
+            "object"
+        );
+        assert_eq!(get["responses"]["404"]["description"], "Not Found");
+        let delete = &doc["paths"]["/users/{id}"]["delete"];
+        assert_eq!(delete["parameters"][0]["schema"]["pattern"], r"^(?:\d+)$");
+        assert_eq!(delete["responses"]["default"]["description"], "Response");
+        let post = &doc["paths"]["/users"]["post"];
+        assert_eq!(
+            post["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/User"
+        );
+        assert_eq!(post["responses"]["201"]["description"], "Created");
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use log::error;
+
+use crate::{
+    chunked::ChunkedReader,
+    common::{HttpError, HttpHeaders, HttpServerContext, HttpStatus},
+    connection::HttpConnectionContext,
+    request::HttpRequest,
+    request_id::{HttpRequestIdContext, REQUEST_ID_HEADER},
+    response::{read_response_head, HttpResponse, HttpResponseMetaData, HttpResponseStream},
+};
+
+/// Headers that only describe the connection they are sent on and must not be forwarded.
+pub const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Removes the hop-by-hop headers, including the ones listed in the `Connection` header.
+pub fn strip_hop_by_hop_headers(headers: &mut HttpHeaders) {
+    let listed = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(',').map(|x| x.trim().to_string()))
+        .collect::<Vec<String>>();
+    headers.retain(|k, _| {
+        !HOP_BY_HOP_HEADERS
+            .iter()
+            .map(|h| h.to_string())
+            .chain(listed.iter().cloned())
+            .any(|h| h.eq_ignore_ascii_case(k))
+    });
+}
+
+fn header_value<'a>(headers: &'a HttpHeaders, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn map_io_error(upstream: &str, e: io::Error) -> HttpError {
+    error!("ProxyHandler: upstream {upstream} failed -> error: {e}");
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => HttpError::new(
+            HttpStatus::GatewayTimeout,
+            format!("upstream {upstream} timed out"),
+        ),
+        _ => HttpError::new(
+            HttpStatus::BadGateway,
+            format!("upstream {upstream} failed: {e}"),
+        ),
+    }
+}
+
+/// Forwards requests to an upstream `host:port` and streams the upstream response back.
+///
+/// Mount it on a path prefix with [`HttpRouterBuilder::add_prefix_route`]:
+///
+/// ```ignore
+/// let proxy = ProxyHandler::new("127.0.0.1:9000").strip_prefix("/legacy");
+/// builder.add_prefix_route("/legacy", move |r, c| proxy.handle(r, c));
+/// ```
+///
+/// [`HttpRouterBuilder::add_prefix_route`]: crate::router::HttpRouterBuilder::add_prefix_route
+#[derive(Clone, Debug)]
+pub struct ProxyHandler {
+    upstream: String,
+    strip_prefix: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+impl ProxyHandler {
+    pub fn new(upstream: &str) -> Self {
+        ProxyHandler {
+            upstream: upstream.to_string(),
+            strip_prefix: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+
+    /// Removes `prefix` from the request uri before forwarding it.
+    pub fn strip_prefix(mut self, prefix: &str) -> Self {
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    fn upstream_uri(&self, uri: &str) -> String {
+        let stripped = match self.strip_prefix.as_deref() {
+            Some(prefix) => uri.strip_prefix(prefix).unwrap_or(uri),
+            None => uri,
+        };
+        if stripped.starts_with('/') {
+            stripped.to_string()
+        } else {
+            format!("/{stripped}")
+        }
+    }
+
+    fn upstream_headers(
+        &self,
+        upstream: &str,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> HttpHeaders {
+        let mut headers = req.metadata.headers.clone();
+        strip_hop_by_hop_headers(&mut headers);
+        // The body was read off the client framing, chunked bodies included, and is forwarded
+        // as is: its length is the only framing the upstream gets.
+        headers.retain(|k, _| !k.eq_ignore_ascii_case("Content-Length"));
+        headers.insert(
+            "Content-Length".to_string(),
+            req.body
+                .as_ref()
+                .map_or(0, |b| b.chars().count())
+                .to_string(),
+        );
+        let conn = params.connection().unwrap_or_default();
+        if let Some(peer) = conn.peer_addr {
+            let forwarded_for = match header_value(&headers, "X-Forwarded-For") {
+                Some(previous) => format!("{previous}, {}", peer.ip()),
+                None => peer.ip().to_string(),
+            };
+            headers.retain(|k, _| !k.eq_ignore_ascii_case("X-Forwarded-For"));
+            headers.insert("X-Forwarded-For".to_string(), forwarded_for);
+        }
+        let original_host = header_value(&headers, "Host").map(String::from);
+        headers.retain(|k, _| !k.eq_ignore_ascii_case("Host"));
+        headers.insert("Host".to_string(), upstream.to_string());
+        if let Some(host) = original_host {
+            headers.insert("X-Forwarded-Host".to_string(), host);
+        }
+        let proto = if conn.is_tls() { "https" } else { "http" };
+        headers.insert("X-Forwarded-Proto".to_string(), proto.to_string());
+        if let Some(id) = params.request_id() {
+            headers.retain(|k, _| !k.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+            headers.insert(REQUEST_ID_HEADER.to_string(), id.to_string());
+        }
+        headers.insert("Connection".to_string(), "close".to_string());
+        headers
+    }
+
+    fn connect(&self, upstream: &str) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cannot resolve {upstream}"),
+        );
+        for addr in upstream.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(s) => {
+                    s.set_read_timeout(Some(self.read_timeout))?;
+                    s.set_write_timeout(Some(self.read_timeout))?;
+                    return Ok(s);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn send_request(
+        &self,
+        stream: &mut TcpStream,
+        upstream: &str,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> io::Result<()> {
+        let mut head = format!(
+            "{} {} {}\r\n",
+            req.metadata.method,
+            self.upstream_uri(&req.metadata.uri),
+            req.metadata.protocol
+        );
+        for (k, v) in self.upstream_headers(upstream, req, params) {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        if let Some(body) = req.body.as_ref() {
+            // Request bodies are decoded one byte per char, see `parse_request_body`.
+            stream.write_all(&body.chars().map(|c| c as u8).collect::<Vec<u8>>())?;
+        }
+        stream.flush()
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> Result<HttpResponse, HttpError> {
+        self.forward(&self.upstream, req, params)
+    }
+
+    /// Forwards `req` to `upstream` instead of the handler's own upstream, which lets
+    /// [`UpstreamPool`](crate::upstream::UpstreamPool) pick the target per request.
+    pub fn forward(
+        &self,
+        upstream: &str,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> Result<HttpResponse, HttpError> {
+        let mut stream = self
+            .connect(upstream)
+            .map_err(|e| map_io_error(upstream, e))?;
+        self.send_request(&mut stream, upstream, req, params)
+            .map_err(|e| map_io_error(upstream, e))?;
+        let mut reader = BufReader::new(stream);
+        let head = read_response_head(&mut reader).map_err(|e| map_io_error(upstream, e))?;
+        let mut metadata = HttpResponseMetaData::parse(&head)?;
+        let chunked = header_value(&metadata.headers, "Transfer-Encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        let content_length = header_value(&metadata.headers, "Content-Length")
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        strip_hop_by_hop_headers(&mut metadata.headers);
+        let body: HttpResponseStream = if chunked {
+            metadata
+                .headers
+                .retain(|k, _| !k.eq_ignore_ascii_case("Content-Length"));
+            Box::new(ChunkedReader::new(reader))
+        } else {
+            match content_length {
+                Some(length) => Box::new(reader.take(length)),
+                None => Box::new(reader),
+            }
+        };
+        Ok(HttpResponse::with_stream(
+            req.metadata.protocol,
+            metadata.status,
+            metadata.headers,
+            body,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::HttpConnectionInfo, request::parse_http_request, request_id::REQUEST_ID_KEY,
+        router::HttpRouterBuilder, server::HttpServer, testing::TestServer,
+    };
+    use std::{net::TcpListener, thread};
+
+    /// Starts an upstream that answers a single connection with `response` and returns its
+    /// address and a handle yielding the raw request it received.
+    fn stand_in_upstream(response: &'static str) -> (String, thread::JoinHandle<HttpRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = parse_http_request(&mut BufReader::new(&stream)).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (addr, handle)
+    }
+
+    fn request(raw: &str) -> HttpRequest {
+        parse_http_request(&mut BufReader::new(raw.as_bytes())).unwrap()
+    }
+
+    fn read_stream(response: HttpResponse) -> String {
+        let mut out = String::new();
+        response.stream.unwrap().read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_headers() {
+        let mut headers = HttpHeaders::new();
+        for (k, v) in [
+            ("connection", "close, X-Private"),
+            ("Keep-Alive", "timeout=5"),
+            ("Transfer-Encoding", "chunked"),
+            ("x-private", "secret"),
+            ("Content-Type", "text/plain"),
+        ] {
+            headers.insert(k.to_string(), v.to_string());
+        }
+        strip_hop_by_hop_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["Content-Type"], "text/plain");
+    }
+
+    #[test]
+    fn test_proxy_upstream_uri() {
+        let proxy = ProxyHandler::new("localhost:1").strip_prefix("/legacy/");
+        assert_eq!(proxy.upstream_uri("/legacy/users?id=1"), "/users?id=1");
+        assert_eq!(proxy.upstream_uri("/legacy"), "/");
+        assert_eq!(
+            ProxyHandler::new("localhost:1").upstream_uri("/legacy/a"),
+            "/legacy/a"
+        );
+    }
+
+    #[test]
+    fn test_proxy_forwards_request_and_rewrites_headers() {
+        let (addr, upstream) = stand_in_upstream(
+            "HTTP/1.1 201 Created\r\n\
+            Content-Length: 5\r\n\
+            Connection: close\r\n\
+            X-Upstream: yes\r\n\
+            \r\n\
+            hello trailing garbage",
+        );
+        let proxy = ProxyHandler::new(&addr).strip_prefix("/legacy");
+        let response = proxy
+            .handle(
+                &request(
+                    "POST /legacy/items HTTP/1.1\r\n\
+                    Host: example.com\r\n\
+                    Connection: keep-alive\r\n\
+                    Content-Length: 4\r\n\
+                    \r\n\
+                    data",
+                ),
+                &HttpServerContext::new(),
+            )
+            .unwrap();
+        assert_eq!(response.metadata.status, HttpStatus::Created);
+        assert_eq!(response.metadata.headers["X-Upstream"], "yes");
+        assert!(!response.metadata.headers.contains_key("Connection"));
+        assert_eq!(read_stream(response), "hello");
+
+        let forwarded = upstream.join().unwrap();
+        assert_eq!(forwarded.metadata.uri, "/items");
+        assert_eq!(forwarded.metadata.headers["Host"], addr);
+        assert_eq!(
+            forwarded.metadata.headers["X-Forwarded-Host"],
+            "example.com"
+        );
+        assert_eq!(forwarded.metadata.headers["X-Forwarded-Proto"], "http");
+        assert_eq!(forwarded.metadata.headers["Connection"], "close");
+        assert_eq!(forwarded.body.unwrap(), "data");
+    }
+
+    #[test]
+    fn test_proxy_frames_chunked_request_body_by_length() {
+        let (addr, upstream) = stand_in_upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let proxy = ProxyHandler::new(&addr);
+        let router = HttpRouterBuilder::new()
+            .add_prefix_route("/", move |r, c| proxy.handle(r, c))
+            .build()
+            .unwrap();
+        let server = TestServer::start(HttpServer::new(router).with_workers(1));
+        let response = server.send_raw(
+            b"POST /items HTTP/1.1\r\n\
+            Host: a\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Connection: close\r\n\
+            \r\n\
+            4\r\ndata\r\n3\r\n-42\r\n0\r\n\r\n",
+        );
+        assert!(response.starts_with(b"HTTP/1.1 204"));
+
+        let forwarded = upstream.join().unwrap();
+        assert_eq!(forwarded.metadata.header("Transfer-Encoding"), None);
+        assert_eq!(forwarded.metadata.headers["Content-Length"], "7");
+        assert_eq!(forwarded.body.unwrap(), "data-42");
+    }
+
+    #[test]
+    fn test_proxy_replaces_client_content_length() {
+        let (addr, upstream) = stand_in_upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let mut req = request("POST / HTTP/1.1\r\nHost: a\r\ncontent-length: 4\r\n\r\ndata");
+        req.body = Some("da".to_string());
+        ProxyHandler::new(&addr)
+            .handle(&req, &HttpServerContext::new())
+            .unwrap();
+        let forwarded = upstream.join().unwrap();
+        assert_eq!(forwarded.metadata.header("content-length"), Some("2"));
+        let lengths = forwarded.metadata.headers.keys();
+        assert_eq!(
+            lengths
+                .filter(|k| k.eq_ignore_ascii_case("Content-Length"))
+                .count(),
+            1
+        );
+        assert_eq!(forwarded.body.unwrap(), "da");
+    }
+
+    #[test]
+    fn test_proxy_appends_forwarded_for() {
+        let (addr, upstream) = stand_in_upstream("HTTP/1.1 204 No Content\r\n\r\n");
+        let conn = HttpConnectionInfo {
+            peer_addr: Some("203.0.113.5:41000".parse().unwrap()),
+            tls_version: Some("TLSv1.3".to_string()),
+            requests: 1,
+            ..HttpConnectionInfo::default()
+        };
+        let mut params = conn.with_context(&HttpServerContext::new());
+        params.insert(REQUEST_ID_KEY.to_string(), "req-1".to_string());
+        ProxyHandler::new(&addr)
+            .handle(
+                &request("GET / HTTP/1.1\r\nHost: a\r\nx-forwarded-for: 198.51.100.1\r\n\r\n"),
+                &params,
+            )
+            .unwrap();
+        let forwarded = upstream.join().unwrap();
+        assert_eq!(
+            forwarded.metadata.header("X-Forwarded-For"),
+            Some("198.51.100.1, 203.0.113.5")
+        );
+        assert_eq!(forwarded.metadata.headers["X-Forwarded-Proto"], "https");
+        assert_eq!(forwarded.metadata.headers[REQUEST_ID_HEADER], "req-1");
+    }
+
+    #[test]
+    fn test_proxy_decodes_chunked_upstream_response() {
+        let (addr, upstream) = stand_in_upstream(
+            "HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            3\r\nfoo\r\n3\r\nbar\r\n0\r\n\r\n",
+        );
+        let response = ProxyHandler::new(&addr)
+            .handle(
+                &request("GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n"),
+                &HttpServerContext::new(),
+            )
+            .unwrap();
+        assert!(!response.metadata.headers.contains_key("Transfer-Encoding"));
+        assert_eq!(read_stream(response), "foobar");
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_proxy_bad_gateway_when_upstream_is_down() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let err = ProxyHandler::new(&addr)
+            .handle(
+                &request("GET / HTTP/1.1\r\n\r\n"),
+                &HttpServerContext::new(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.status, HttpStatus::BadGateway);
+    }
+
+    #[test]
+    fn test_proxy_bad_gateway_on_malformed_response() {
+        let (addr, upstream) = stand_in_upstream("garbage\r\n\r\n");
+        let err = ProxyHandler::new(&addr)
+            .handle(
+                &request("GET / HTTP/1.1\r\n\r\n"),
+                &HttpServerContext::new(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.status, HttpStatus::BadGateway);
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn test_proxy_gateway_timeout_on_slow_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let upstream = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(300));
+            drop(stream);
+        });
+        let err = ProxyHandler::new(&addr)
+            .read_timeout(Duration::from_millis(50))
+            .handle(
+                &request("GET / HTTP/1.1\r\n\r\n"),
+                &HttpServerContext::new(),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err.status, HttpStatus::GatewayTimeout);
+        upstream.join().unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use path_tree::PathTree;
+use regex::Regex;
+
+use crate::{
+    auth::HttpAuthContext,
+    common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext, HttpStatus},
+    connection::CLIENT_IP_KEY,
+    request::HttpRequest,
+    response::HttpResponse,
+    router::{parse_constraints, HttpRouterFunc},
+};
+
+/// Number of buckets a rule keeps. Past it the full ones, which hold no state worth keeping,
+/// are dropped, then the least recently used one.
+const MAX_BUCKETS: usize = 4096;
+
+/// Allows `requests` requests per `period`, refilled continuously.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn new(requests: u32, period: Duration) -> Self {
+        Quota { requests, period }
+    }
+
+    pub fn per_second(requests: u32) -> Self {
+        Quota::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        Quota::new(requests, Duration::from_secs(60))
+    }
+
+    /// Tokens added to a bucket every second.
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// What a client is identified by. Requests without the key fall back to the client address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RateLimitKey {
+    Peer,
+    Header(String),
+    Principal,
+}
+
+// =========================================================
+// ===================== TokenBucket =======================
+// =========================================================
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The outcome of taking a token from a bucket.
+#[derive(Debug, PartialEq)]
+struct Decision {
+    allowed: bool,
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next token, when refused.
+    retry_after: u64,
+}
+
+struct Rule {
+    quota: Quota,
+    /// The constraints of the path parameters of the route, as in the router.
+    constraints: Vec<(String, Regex)>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Rule {
+    fn new(quota: Quota) -> Self {
+        Rule {
+            quota,
+            constraints: Vec::new(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn accepts(&self, params: &HttpServerContext) -> bool {
+        self.constraints
+            .iter()
+            .all(|(name, re)| params.get(name).is_some_and(|v| re.is_match(v)))
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Decision {
+        let capacity = self.quota.requests as f64;
+        let rate = self.quota.rate();
+        let refill = |b: &Bucket| {
+            let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+            (b.tokens + elapsed * rate).min(capacity)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, b| refill(b) < capacity);
+            if buckets.len() >= MAX_BUCKETS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.updated)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let seconds = |tokens: f64| (tokens.max(0.0) / rate).ceil() as u64;
+        Decision {
+            allowed,
+            remaining: bucket.tokens.floor() as u32,
+            reset: seconds(capacity - bucket.tokens),
+            retry_after: match allowed {
+                true => 0,
+                false => seconds(1.0 - bucket.tokens).max(1),
+            },
+        }
+    }
+}
+
+// =========================================================
+// ====================== RateLimit ========================
+// =========================================================
+/// Middleware limiting how often a client may call the routes, using token buckets. Routes
+/// can be given their own quota, with their own buckets, on top of the default one.
+#[derive(Clone)]
+pub struct RateLimit {
+    key: RateLimitKey,
+    default: Option<Arc<Rule>>,
+    routes: Arc<HashMap<Option<HttpMethod>, PathTree<Arc<Rule>>>>,
+    /// The path each route was given, by its method and id in `routes`.
+    paths: HashMap<(Option<HttpMethod>, usize), String>,
+}
+
+impl RateLimit {
+    pub fn new(key: RateLimitKey) -> Self {
+        RateLimit {
+            key,
+            default: None,
+            routes: Arc::new(HashMap::new()),
+            paths: HashMap::new(),
+        }
+    }
+
+    /// The quota of the routes without one of their own. Without it they are not limited.
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.default = Some(Arc::new(Rule::new(quota)));
+        self
+    }
+
+    /// Gives the routes matching `path`, in the syntax of the router, their own quota. It
+    /// applies to every method when `method` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when `path` is malformed or matches the same requests as a path
+    /// given before for `method`.
+    pub fn route(
+        mut self,
+        method: Option<HttpMethod>,
+        path: &str,
+        quota: Quota,
+    ) -> Result<Self, String> {
+        let (pattern, constraints) = parse_constraints(path)?;
+        let rule = Rule {
+            constraints,
+            ..Rule::new(quota)
+        };
+        let routes = Arc::make_mut(&mut self.routes);
+        let id = routes
+            .entry(method)
+            .or_default()
+            .insert(&pattern, Arc::new(rule));
+        match self.paths.insert((method, id), path.to_string()) {
+            Some(by) => Err(format!("{path} matches the same requests as {by}")),
+            None => Ok(self),
+        }
+    }
+
+    /// The rule of the route matching `path`, whose parameters meet its constraints, or the
+    /// default one.
+    fn rule(&self, method: HttpMethod, path: &str) -> Option<&Rule> {
+        [Some(method), None]
+            .iter()
+            .filter_map(|m| self.routes.get(m)?.find(path))
+            .find(|(rule, path)| {
+                let params = path
+                    .params_iter()
+                    .map(|(x, y)| (x.to_string(), y.to_string()))
+                    .collect();
+                rule.accepts(&params)
+            })
+            .map(|(rule, _)| rule.as_ref())
+            .or(self.default.as_deref())
+    }
+
+    fn client_key(&self, req: &HttpRequest, params: &HttpServerContext) -> Option<String> {
+        let key = match &self.key {
+            RateLimitKey::Peer => None,
+            RateLimitKey::Header(name) => req.metadata.header(name).map(|v| format!("h:{v}")),
+            RateLimitKey::Principal => params.principal().map(|p| format!("p:{}", p.name)),
+        };
+        key.or_else(|| Some(format!("ip:{}", params.get(CLIENT_IP_KEY)?)))
+    }
+
+    fn headers(quota: &Quota, decision: &Decision) -> HttpHeaders {
+        let mut headers = HttpHeaders::new();
+        let mut set = |name: &str, value: String| headers.insert(name.to_string(), value);
+        set("RateLimit-Limit", quota.requests.to_string());
+        set("RateLimit-Remaining", decision.remaining.to_string());
+        set("RateLimit-Reset", decision.reset.to_string());
+        set(
+            "RateLimit-Policy",
+            format!("{};w={}", quota.requests, quota.period.as_secs()),
+        );
+        if !decision.allowed {
+            set("Retry-After", decision.retry_after.to_string());
+        }
+        headers
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+        next: &HttpRouterFunc,
+    ) -> Result<HttpResponse, HttpError> {
+        let path = req.metadata.uri.split(['?', '#']).next().unwrap_or("");
+        let (Some(rule), Some(key)) = (
+            self.rule(req.metadata.method, path),
+            self.client_key(req, params),
+        ) else {
+            return next(req, params);
+        };
+        let decision = rule.take(&key, Instant::now());
+        let headers = Self::headers(&rule.quota, &decision);
+        if !decision.allowed {
+            debug!("RateLimit: {key} refused on {}", req.metadata.uri);
+            let mut e = HttpError::new(HttpStatus::TooManyRequests, "rate limit exceeded");
+            e.headers.extend(headers);
+            return Err(e);
+        }
+        match next(req, params) {
+            Ok(mut response) => {
+                response.metadata.headers.extend(headers);
+                Ok(response)
+            }
+            Err(mut e) => {
+                e.headers.extend(headers);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::HttpProtocol,
+        request::HttpRequestMetaData,
+        router::{HttpRouter, HttpRouterBuilder},
+    };
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut raw = format!("{method} {uri} HTTP/1.1\nHost: localhost\nAccept: */*\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\n"));
+        }
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    fn router(limit: RateLimit) -> HttpRouter {
+        let ok = |_: &HttpRequest, _: &HttpServerContext| {
+            Ok(HttpResponse::new(
+                HttpProtocol::Http1_1,
+                HttpStatus::Ok,
+                HttpHeaders::new(),
+                None,
+            ))
+        };
+        HttpRouterBuilder::new()
+            .add_middleware(move |r, c, next| {
+                let mut c = c.clone();
+                c.insert(CLIENT_IP_KEY.to_string(), "10.0.0.1".to_string());
+                limit.handle(r, &c, next)
+            })
+            .add_route(HttpMethod::GET, "/items/:id", ok)
+            .add_route(HttpMethod::POST, "/login", ok)
+            .add_route(HttpMethod::GET, "/login", ok)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let rule = Rule::new(Quota::per_minute(2));
+        let now = Instant::now();
+        let first = rule.take("a", now);
+        assert_eq!(
+            first,
+            Decision {
+                allowed: true,
+                remaining: 1,
+                reset: 30,
+                retry_after: 0
+            }
+        );
+        assert!(rule.take("a", now).allowed);
+        let refused = rule.take("a", now);
+        assert!(!refused.allowed);
+        assert_eq!(
+            (refused.remaining, refused.reset, refused.retry_after),
+            (0, 60, 30)
+        );
+        assert!(rule.take("b", now).allowed);
+        assert!(rule.take("a", now + Duration::from_secs(30)).allowed);
+        assert!(!rule.take("a", now + Duration::from_secs(31)).allowed);
+    }
+
+    #[test]
+    fn test_rate_limit_per_route() {
+        let router = router(
+            RateLimit::new(RateLimitKey::Peer)
+                .quota(Quota::per_minute(3))
+                .route(Some(HttpMethod::POST), "/login", Quota::per_minute(1))
+                .unwrap(),
+        );
+        let response = router.route(&request("GET", "/items/1", &[])).unwrap();
+        assert_eq!(response.metadata.headers["RateLimit-Limit"], "3");
+        assert_eq!(response.metadata.headers["RateLimit-Remaining"], "2");
+        assert_eq!(response.metadata.headers["RateLimit-Policy"], "3;w=60");
+
+        assert!(router.route(&request("POST", "/login", &[])).is_ok());
+        let e = router.route(&request("POST", "/login", &[])).err().unwrap();
+        assert_eq!(e.status, HttpStatus::TooManyRequests);
+        assert_eq!(e.headers["Retry-After"], "60");
+        assert_eq!(e.headers["RateLimit-Remaining"], "0");
+
+        // GET /login shares the default buckets with the other routes.
+        assert!(router.route(&request("GET", "/login", &[])).is_ok());
+        assert!(router.route(&request("GET", "/items/2", &[])).is_ok());
+        let e = router
+            .route(&request("GET", "/items/3", &[]))
+            .err()
+            .unwrap();
+        assert_eq!(e.headers["Retry-After"], "20");
+    }
+
+    #[test]
+    fn test_rate_limit_by_header() {
+        let router = router(
+            RateLimit::new(RateLimitKey::Header("X-Api-Key".to_string()))
+                .quota(Quota::per_second(1)),
+        );
+        let with_key = |key: &str| request("GET", "/items/1", &[("X-Api-Key", key)]);
+        assert!(router.route(&with_key("a")).is_ok());
+        assert!(router.route(&with_key("a")).is_err());
+        assert!(router.route(&with_key("b")).is_ok());
+        // Requests without the header are limited by their address.
+        assert!(router.route(&request("GET", "/items/1", &[])).is_ok());
+        assert!(router.route(&request("GET", "/items/1", &[])).is_err());
+    }
+
+    #[test]
+    fn test_token_buckets_are_capped() {
+        let rule = Rule::new(Quota::per_minute(1));
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let at = now + Duration::from_millis(i as u64);
+            assert!(rule.take(&format!("drained-{i}"), at).allowed);
+        }
+        let later = now + Duration::from_secs(1);
+        assert!(rule.take("new", later).allowed);
+        let buckets = rule.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS);
+        assert!(!buckets.contains_key("drained-0"));
+        assert!(buckets.contains_key("drained-1"));
+    }
+
+    #[test]
+    fn test_rate_limit_route_errors() {
+        let limit = RateLimit::new(RateLimitKey::Peer);
+        let quota = Quota::per_minute(1);
+        assert!(limit.clone().route(None, "/a/:id(\\d+", quota).is_err());
+        let limit = limit.route(None, "/a/:id", quota).unwrap();
+        let e = limit.clone().route(None, "/a/:name", quota).err().unwrap();
+        assert_eq!(e, "/a/:name matches the same requests as /a/:id");
+        assert!(limit.route(Some(HttpMethod::GET), "/a/:id", quota).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limit_route_constraints() {
+        let router = router(
+            RateLimit::new(RateLimitKey::Peer)
+                .quota(Quota::per_minute(3))
+                .route(
+                    Some(HttpMethod::GET),
+                    "/items/:id(\\d+)",
+                    Quota::per_minute(1),
+                )
+                .unwrap(),
+        );
+        assert!(router.route(&request("GET", "/items/1", &[])).is_ok());
+        let e = router
+            .route(&request("GET", "/items/2", &[]))
+            .err()
+            .unwrap();
+        assert_eq!(e.headers["RateLimit-Limit"], "1");
+
+        // Ids the constraint refuses are limited by the default quota.
+        let response = router.route(&request("GET", "/items/abc", &[])).unwrap();
+        assert_eq!(response.metadata.headers["RateLimit-Limit"], "3");
+    }
+}
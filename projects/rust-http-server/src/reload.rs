@@ -0,0 +1,119 @@
+use std::{
+    io,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+};
+
+use log::{error, info};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+use crate::server::HttpServerSettings;
+
+/// Builds a fresh set of server settings, typically by reading the configuration again.
+pub type HttpSettingsLoader = dyn Fn() -> Result<HttpServerSettings, String> + Send + Sync;
+
+/// Triggers reloads of a running server.
+///
+/// A reload builds the new settings first and only then swaps them in, so a failing reload
+/// leaves the server untouched and connections never see partially applied settings.
+#[derive(Clone)]
+pub struct HttpReloadHandle {
+    settings: Arc<RwLock<Arc<HttpServerSettings>>>,
+    loader: Arc<HttpSettingsLoader>,
+    reloading: Arc<Mutex<()>>,
+}
+
+impl HttpReloadHandle {
+    pub(crate) fn new(
+        settings: Arc<RwLock<Arc<HttpServerSettings>>>,
+        loader: Arc<HttpSettingsLoader>,
+    ) -> Self {
+        HttpReloadHandle {
+            settings,
+            loader,
+            reloading: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        let _reloading = self.reloading.lock().unwrap();
+        let settings = (self.loader)().map_err(|e| {
+            error!("HttpServer: reload failed, keeping the current configuration -> {e}");
+            e
+        })?;
+        *self.settings.write().unwrap() = Arc::new(settings);
+        info!("HttpServer: configuration reloaded");
+        Ok(())
+    }
+}
+
+/// Reloads the server through `handle` every time the process receives `SIGHUP`.
+pub fn reload_on_sighup(handle: HttpReloadHandle) -> io::Result<thread::JoinHandle<()>> {
+    let mut signals = Signals::new([SIGHUP])?;
+    Ok(thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("HttpServer: SIGHUP received, reloading");
+            let _ = handle.reload();
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::HttpRouterBuilder;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    fn handle(loader: Arc<HttpSettingsLoader>) -> HttpReloadHandle {
+        let settings = HttpServerSettings::new(HttpRouterBuilder::new().build().unwrap());
+        HttpReloadHandle::new(Arc::new(RwLock::new(Arc::new(settings))), loader)
+    }
+
+    fn max_body_size(handle: &HttpReloadHandle) -> Option<usize> {
+        handle.settings.read().unwrap().max_body_size
+    }
+
+    #[test]
+    fn test_reload_swaps_settings() {
+        let handle = handle(Arc::new(|| {
+            Ok(HttpServerSettings {
+                max_body_size: Some(42),
+                ..HttpServerSettings::new(HttpRouterBuilder::new().build().unwrap())
+            })
+        }));
+        let before = Arc::clone(&handle.settings.read().unwrap());
+        handle.reload().unwrap();
+        assert_eq!(max_body_size(&handle), Some(42));
+        // Connections holding the previous snapshot keep using it.
+        assert_eq!(before.max_body_size, None);
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_settings() {
+        let handle = handle(Arc::new(|| Err("broken config".to_string())));
+        assert_eq!(handle.reload().err().unwrap(), "broken config");
+        assert_eq!(max_body_size(&handle), None);
+    }
+
+    #[test]
+    fn test_reload_on_sighup() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let handle = handle(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(HttpServerSettings::new(
+                HttpRouterBuilder::new().build().unwrap(),
+            ))
+        }));
+        let _ = reload_on_sighup(handle).unwrap();
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        let started = Instant::now();
+        while calls.load(Ordering::SeqCst) == 0 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
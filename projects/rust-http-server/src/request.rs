@@ -1,13 +1,24 @@
 use std::{
     collections::{BTreeMap, HashMap},
     fmt::Display,
-    io::{BufRead, BufReader, Read},
+    io::{self, BufRead, BufReader, Read},
     str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
 };
 
-use crate::common::{HttpBody, HttpError, HttpHeaders, HttpMethod, HttpProtocol, HttpStatus};
+use crate::{
+    chunked::ChunkedReader,
+    common::{HttpBody, HttpError, HttpHeaders, HttpMethod, HttpProtocol, HttpStatus},
+    cookie::parse_cookie_header,
+    static_files::percent_decode,
+};
 
-#[derive(Clone)]
+/// Longest request line or header line accepted, line ending included.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// Most bytes accepted for the request line and the headers together.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct HttpRequestMetaData {
     pub protocol: HttpProtocol,
     pub uri: String,
@@ -34,6 +45,8 @@ impl Display for HttpRequestMetaData {
 pub struct HttpRequest {
     pub metadata: HttpRequestMetaData,
     pub body: HttpBody,
+    /// The body of a request to a streaming route, which is never buffered in `body`.
+    pub stream: Option<HttpRequestStream>,
 }
 
 impl Display for HttpRequest {
@@ -65,6 +78,7 @@ impl HttpRequestMetaData {
     fn parse_headers(lines: &[String]) -> Result<HttpHeaders, HttpError> {
         lines
             .iter()
+            .filter(|x| !x.is_empty())
             .map(|x: &String| -> Result<(String, String), HttpError> {
                 let vec: Vec<&str> = x.split(": ").collect();
                 match vec.as_slice() {
@@ -92,13 +106,9 @@ impl HttpRequestMetaData {
             Err(e) => return Err(e),
         };
 
-        let request_headers = if metadata_lines.len() > 2 {
-            match HttpRequestMetaData::parse_headers(&metadata_lines[1..]) {
-                Ok(h) => h,
-                Err(e) => return Err(e),
-            }
-        } else {
-            HttpHeaders::new()
+        let request_headers = match HttpRequestMetaData::parse_headers(&metadata_lines[1..]) {
+            Ok(h) => h,
+            Err(e) => return Err(e),
         };
         Ok(HttpRequestMetaData {
             method: method,
@@ -107,65 +117,383 @@ impl HttpRequestMetaData {
             headers: request_headers,
         })
     }
-    pub fn content_length(self: &Self) -> Result<usize, HttpError> {
-        let zero_content_length = "0".to_string();
-        let x = self
-            .headers
-            .get("Content-Length")
-            .unwrap_or(&zero_content_length);
-        match x.parse::<u32>() {
-            Ok(s) => Ok(s as usize),
-            Err(_) => Err(HttpError::new(
+    /// Looks up a header value ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    /// Whether the client waits for `100 Continue` before sending the body. Expectations
+    /// other than `100-continue` are refused with `417 Expectation Failed`, and HTTP/1.0
+    /// clients are never waited on.
+    pub fn expects_continue(&self) -> Result<bool, HttpError> {
+        match self.header("Expect") {
+            None => Ok(false),
+            Some(e) if e.trim().eq_ignore_ascii_case("100-continue") => {
+                Ok(self.protocol == HttpProtocol::Http1_1)
+            }
+            Some(e) => Err(HttpError::new(
+                HttpStatus::ExpectationFailed,
+                format!("unsupported expectation: {e}"),
+            )),
+        }
+    }
+    /// Returns the decoded value of the query parameter `name`, the first one when repeated.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let (_, query) = self.uri.split_once('?')?;
+        query
+            .split('#')
+            .next()?
+            .split('&')
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |s: &str| percent_decode(&s.replace('+', " "));
+                Some((decode(key)?, decode(value)?))
+            })
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+    /// Returns the media type of `offers` the `Accept` header prefers, the first offer when
+    /// the header is missing and `None` when it accepts none of them. Ties go to the offer
+    /// listed first.
+    pub fn preferred_type<'a>(&self, offers: &[&'a str]) -> Option<&'a str> {
+        let Some(accept) = self.header("Accept") else {
+            return offers.first().copied();
+        };
+        let ranges = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media = params.next()?.trim().to_ascii_lowercase();
+                let q = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((media, q))
+            })
+            .collect::<Vec<(String, f32)>>();
+        let quality = |offer: &str| {
+            let offer = offer.to_ascii_lowercase();
+            let kind = offer.split('/').next().unwrap_or("");
+            // The most specific range matching the offer decides its quality.
+            ranges
+                .iter()
+                .filter_map(|(media, q)| match media.as_str() {
+                    m if m == offer => Some((2, *q)),
+                    m if m.strip_suffix("/*") == Some(kind) => Some((1, *q)),
+                    "*/*" => Some((0, *q)),
+                    _ => None,
+                })
+                .max_by(|a, b| a.0.cmp(&b.0))
+                .map_or(0.0, |(_, q)| q)
+        };
+        offers
+            .iter()
+            .map(|offer| (*offer, quality(offer)))
+            .filter(|(_, q)| *q > 0.0)
+            .fold(None, |best: Option<(&str, f32)>, (offer, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((offer, q)),
+            })
+            .map(|(offer, _)| offer)
+    }
+    /// Returns the cookies sent with the request.
+    pub fn cookies(&self) -> BTreeMap<String, String> {
+        self.header("Cookie")
+            .map(parse_cookie_header)
+            .unwrap_or_default()
+    }
+    /// Returns the value of the cookie `name`.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+    /// Rewrites an absolute-form target (`GET http://host/path`) into origin-form. The
+    /// authority of the target replaces the `Host` header, as it takes precedence over it.
+    pub fn normalize_target(&mut self) {
+        let lower = self.uri.to_ascii_lowercase();
+        let scheme = match ["http://", "https://"]
+            .iter()
+            .find(|s| lower.starts_with(*s))
+        {
+            Some(scheme) => scheme.len(),
+            None => return,
+        };
+        let target = self.uri.split_off(scheme);
+        let (authority, path) = target.split_at(target.find(['/', '?']).unwrap_or(target.len()));
+        self.uri = match path.starts_with('/') {
+            true => path.to_string(),
+            false => format!("/{path}"),
+        };
+        self.headers.retain(|k, _| !k.eq_ignore_ascii_case("Host"));
+        self.headers
+            .insert("Host".to_string(), authority.to_string());
+    }
+    /// How the body is delimited: chunked when it is the last transfer coding, otherwise by
+    /// the `Content-Length`. Requests carrying both are refused, as a proxy in front of the
+    /// server could frame them differently.
+    pub fn body_framing(&self) -> Result<HttpBodyFraming, HttpError> {
+        match self.header("Transfer-Encoding") {
+            Some(_) if self.header("Content-Length").is_some() => Err(HttpError::new(
                 HttpStatus::BadRequest,
-                "bad 'Content-Length'",
+                "both Transfer-Encoding and Content-Length are set",
             )),
+            Some(codings) => match codings.rsplit(',').next().map(str::trim) {
+                Some(c) if c.eq_ignore_ascii_case("chunked") => Ok(HttpBodyFraming::Chunked),
+                _ => Err(HttpError::new(
+                    HttpStatus::NotImplemented,
+                    format!("unsupported transfer coding: {codings}"),
+                )),
+            },
+            None => self.content_length().map(HttpBodyFraming::Length),
+        }
+    }
+    /// The `Content-Length` of the request, `0` when missing. Repeated headers or lists must
+    /// all carry the same length.
+    pub fn content_length(&self) -> Result<usize, HttpError> {
+        let bad_length = || HttpError::new(HttpStatus::BadRequest, "bad 'Content-Length'");
+        let mut lengths = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|v| match v.trim() {
+                v if !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()) => {
+                    v.parse::<usize>().map_err(|_| bad_length())
+                }
+                _ => Err(bad_length()),
+            });
+        let length = match lengths.next() {
+            Some(length) => length?,
+            None => return Ok(0),
+        };
+        match lengths.all(|other| other.is_ok_and(|other| other == length)) {
+            true => Ok(length),
+            false => Err(bad_length()),
         }
     }
 }
 
 impl HttpRequest {
+    /// Reads a body of `content_length` bytes, decoding them one byte per char.
+    ///
+    /// # Errors    HttpError
+    ///
+    /// This function will return a `BadRequest` error if the body cannot be read or ends
+    /// before `content_length` bytes, and a `RequestTimeout` one if reading it times out.
     pub fn parse_request_body<R: Read>(
         buffer: &mut BufReader<R>,
         content_length: usize,
-    ) -> HttpBody {
+    ) -> Result<HttpBody, HttpError> {
         if content_length == 0 {
-            None
-        } else {
-            Some(
-                buffer
-                    .bytes()
-                    .take(content_length)
-                    .map(|x| x.ok().unwrap() as char)
-                    .collect::<String>(),
-            )
+            return Ok(None);
+        }
+        let mut bytes = Vec::new();
+        buffer
+            .take(content_length as u64)
+            .read_to_end(&mut bytes)
+            .map_err(read_error)?;
+        if bytes.len() < content_length {
+            return Err(HttpError::new(
+                HttpStatus::BadRequest,
+                format!(
+                    "request body ended after {} of {content_length} bytes",
+                    bytes.len()
+                ),
+            ));
         }
+        Ok(Some(bytes.into_iter().map(char::from).collect()))
     }
 }
 
+// =========================================================
+// =================== HttpBodyReader ======================
+// =========================================================
+/// How the body of a request is delimited on the connection.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HttpBodyFraming {
+    Length(usize),
+    Chunked,
+}
+
+/// The error reading a body longer than the limit of its [`HttpBodyReader`].
+#[derive(thiserror::Error, Debug)]
+#[error("request body larger than {0} bytes")]
+pub struct BodyTooLarge(pub usize);
+
+enum Framed<R: BufRead> {
+    Length(io::Take<R>),
+    Chunked(ChunkedReader<R>),
+}
+
+/// Reads the body of a request off its connection, stopping where the body ends so the
+/// connection can be used afterwards.
+pub struct HttpBodyReader<R: BufRead> {
+    inner: Framed<R>,
+    read: usize,
+    limit: Option<usize>,
+}
+
+impl<R: BufRead> HttpBodyReader<R> {
+    /// Reads a body delimited by `framing`, failing with [`BodyTooLarge`] once more than
+    /// `limit` bytes were read.
+    pub fn new(inner: R, framing: HttpBodyFraming, limit: Option<usize>) -> Self {
+        let inner = match framing {
+            HttpBodyFraming::Length(length) => Framed::Length(inner.take(length as u64)),
+            HttpBodyFraming::Chunked => Framed::Chunked(ChunkedReader::new(inner)),
+        };
+        HttpBodyReader {
+            inner,
+            read: 0,
+            limit,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            Framed::Length(take) => take.into_inner(),
+            Framed::Chunked(chunked) => chunked.into_inner(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for HttpBodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.inner {
+            Framed::Length(take) => {
+                let expected = take.limit();
+                let read = take.read(buf)?;
+                if read == 0 && expected > 0 && !buf.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "request body ended unexpectedly",
+                    ));
+                }
+                read
+            }
+            Framed::Chunked(chunked) => chunked.read(buf)?,
+        };
+        self.read += read;
+        match self.limit {
+            Some(limit) if self.read > limit => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                BodyTooLarge(limit),
+            )),
+            _ => Ok(read),
+        }
+    }
+}
+
+/// The body of a request handed to a streaming route, read as it arrives. Clones read from
+/// the same body.
+#[derive(Clone)]
+pub struct HttpRequestStream(Arc<Mutex<Box<dyn Read + Send>>>);
+
+impl HttpRequestStream {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> Self {
+        HttpRequestStream(Arc::new(Mutex::new(Box::new(reader))))
+    }
+}
+
+impl Read for HttpRequestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read(buf)
+    }
+}
+
+/// Reads a whole body off `reader`, bytes mapped to chars like
+/// [`HttpRequest::parse_request_body`].
+pub fn read_request_body<R: Read>(mut reader: R) -> Result<HttpBody, HttpError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(body_error)?;
+    Ok(match bytes.is_empty() {
+        true => None,
+        false => Some(bytes.iter().map(|&b| b as char).collect()),
+    })
+}
+
+/// The error answered for a body that could not be read.
+pub fn body_error(e: io::Error) -> HttpError {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<BodyTooLarge>())
+    {
+        Some(too_large) => HttpError::new(HttpStatus::PayloadTooLarge, too_large.to_string()),
+        None => read_error(e),
+    }
+}
+
+fn read_error(e: io::Error) -> HttpError {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            HttpError::new(HttpStatus::RequestTimeout, "timed out reading the request")
+        }
+        _ => HttpError::new(HttpStatus::BadRequest, format!("cannot read request: {e}")),
+    }
+}
+
+/// Reads the information line and the headers of a request, leaving the body in `buffer`.
+/// Lines longer than [`MAX_LINE_LENGTH`] and heads larger than [`MAX_HEAD_SIZE`] are refused.
+pub fn parse_http_request_metadata<R: Read>(
+    buffer: &mut BufReader<R>,
+) -> Result<HttpRequestMetaData, HttpError> {
+    let mut metadata_lines: Vec<String> = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = buffer
+            .by_ref()
+            .take(MAX_LINE_LENGTH as u64)
+            .read_line(&mut line)
+            .map_err(read_error)?;
+        if read == MAX_LINE_LENGTH && !line.ends_with('\n') {
+            return Err(match metadata_lines.is_empty() {
+                true => HttpError::new(HttpStatus::URITooLong, "request line too long"),
+                false => HttpError::new(
+                    HttpStatus::RequestHeaderFieldsTooLarge,
+                    "request header line too long",
+                ),
+            });
+        }
+        size += read;
+        if size > MAX_HEAD_SIZE {
+            return Err(HttpError::new(
+                HttpStatus::RequestHeaderFieldsTooLarge,
+                "request headers too large",
+            ));
+        }
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() {
+            break;
+        }
+        metadata_lines.push(line.to_string());
+    }
+    HttpRequestMetaData::parse(metadata_lines.join("\n").as_str())
+}
+
+/// Reads a whole request off `buffer`, its body framed by `Content-Length` or decoded from
+/// chunks like the server does.
 pub fn parse_http_request<R: Read>(buffer: &mut BufReader<R>) -> Result<HttpRequest, HttpError> {
-    let metadata_str = buffer
-        .lines()
-        .map(|x| x.unwrap())
-        .take_while(|x| !x.is_empty())
-        .collect::<Vec<String>>()
-        .join("\n");
-    let metadata = match HttpRequestMetaData::parse(metadata_str.as_str()) {
-        Ok(m) => m,
-        Err(e) => return Err(e),
-    };
-    let body = match metadata.content_length() {
-        Ok(c) => HttpRequest::parse_request_body(buffer, c),
-        Err(e) => return Err(e),
+    let metadata = parse_http_request_metadata(buffer)?;
+    let body = match metadata.body_framing()? {
+        HttpBodyFraming::Length(length) => HttpRequest::parse_request_body(buffer, length)?,
+        framing => read_request_body(HttpBodyReader::new(buffer, framing, None))?,
     };
     Ok(HttpRequest {
         metadata: metadata,
         body: body,
+        stream: None,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     fn collect_into_lines(raw: &str) -> Vec<String> {
         raw.lines().map(|s| s.to_string()).collect::<Vec<String>>()
@@ -214,11 +542,186 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_header_lookup_is_case_insensitive() {
+        let metadata = HttpRequestMetaData::parse(
+            "GET / HTTP/1.1\n\
+            Host: localhost\n\
+            user-agent: curl/8.0",
+        )
+        .unwrap();
+        assert_eq!(metadata.header("User-Agent"), Some("curl/8.0"));
+        assert_eq!(metadata.header("HOST"), Some("localhost"));
+        assert_eq!(metadata.header("Referer"), None);
+    }
+
+    #[test]
+    fn test_query_param() {
+        let metadata =
+            HttpRequestMetaData::parse("GET /a?x=1&api_key=a%2Bb+c&x=2&flag#frag HTTP/1.1")
+                .unwrap();
+        assert_eq!(metadata.query_param("x").unwrap(), "1");
+        assert_eq!(metadata.query_param("api_key").unwrap(), "a+b c");
+        assert_eq!(metadata.query_param("flag").unwrap(), "");
+        assert!(metadata.query_param("missing").is_none());
+        let metadata = HttpRequestMetaData::parse("GET /a HTTP/1.1").unwrap();
+        assert!(metadata.query_param("x").is_none());
+    }
+
+    #[test]
+    fn test_preferred_type() {
+        let offers = ["application/json", "text/html", "text/plain"];
+        let preferred = |accept: &str| {
+            HttpRequestMetaData::parse(&format!("GET / HTTP/1.1\nHost: a\nAccept: {accept}"))
+                .unwrap()
+                .preferred_type(&offers)
+        };
+        assert_eq!(preferred("*/*"), Some("application/json"));
+        assert_eq!(
+            preferred("text/html,application/xhtml+xml,*/*;q=0.8"),
+            Some("text/html")
+        );
+        assert_eq!(preferred("text/*"), Some("text/html"));
+        assert_eq!(preferred("text/*;q=0.5, text/plain"), Some("text/plain"));
+        assert_eq!(
+            preferred("text/plain; q=0, */*;q=0.1"),
+            Some("application/json")
+        );
+        assert_eq!(preferred("image/png"), None);
+        let metadata = HttpRequestMetaData::parse("GET / HTTP/1.1\nHost: a\nX: y").unwrap();
+        assert_eq!(metadata.preferred_type(&offers), Some("application/json"));
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        let expects = |protocol: &str, expect: &str| {
+            HttpRequestMetaData::parse(&format!(
+                "PUT /f {protocol}\nHost: a\nContent-Length: 3\n{expect}"
+            ))
+            .unwrap()
+            .expects_continue()
+        };
+        assert_eq!(expects("HTTP/1.1", "Expect: 100-Continue"), Ok(true));
+        assert_eq!(expects("HTTP/1", "Expect: 100-continue"), Ok(false));
+        assert_eq!(expects("HTTP/1.1", "X-Other: 1"), Ok(false));
+        assert_eq!(
+            expects("HTTP/1.1", "Expect: 200-ok").err().unwrap().status,
+            HttpStatus::ExpectationFailed
+        );
+    }
+
+    #[test]
+    fn test_body_framing() {
+        let framing = |headers: &str| {
+            HttpRequestMetaData::parse(&format!("POST /f HTTP/1.1\nHost: a\n{headers}"))
+                .unwrap()
+                .body_framing()
+        };
+        assert_eq!(
+            framing("Content-Length: 12"),
+            Ok(HttpBodyFraming::Length(12))
+        );
+        assert_eq!(framing("X: y"), Ok(HttpBodyFraming::Length(0)));
+        assert_eq!(
+            framing("Transfer-Encoding: gzip, Chunked"),
+            Ok(HttpBodyFraming::Chunked)
+        );
+        assert_eq!(
+            framing("Transfer-Encoding: gzip").err().unwrap().status,
+            HttpStatus::NotImplemented
+        );
+        assert_eq!(
+            framing("Transfer-Encoding: chunked\nContent-Length: 3")
+                .err()
+                .unwrap()
+                .status,
+            HttpStatus::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_content_length() {
+        let length = |headers: &str| {
+            HttpRequestMetaData::parse(&format!("POST /f HTTP/1.1\nHost: a\n{headers}"))
+                .unwrap()
+                .content_length()
+        };
+        assert_eq!(length("content-length: 12"), Ok(12));
+        assert_eq!(length("Content-Length: 7, 7"), Ok(7));
+        assert_eq!(length("Content-Length: 7\ncontent-length: 7"), Ok(7));
+        assert_eq!(length("Content-Length: 5000000000"), Ok(5_000_000_000));
+        for conflicting in [
+            "Content-Length: 7, 8",
+            "Content-Length: 7\ncontent-length: 8",
+            "Content-Length: 7,",
+            "Content-Length: +7",
+        ] {
+            assert_eq!(
+                length(conflicting).err().unwrap().status,
+                HttpStatus::BadRequest,
+                "{conflicting}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_body_reader_stops_at_body_end() {
+        let mut raw = BufReader::new("hello worldGET".as_bytes());
+        let mut body = String::new();
+        let mut reader = HttpBodyReader::new(&mut raw, HttpBodyFraming::Length(11), None);
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello world");
+        let mut rest = String::new();
+        reader.into_inner().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET");
+
+        let chunked = "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let reader = HttpBodyReader::new(chunked.as_bytes(), HttpBodyFraming::Chunked, None);
+        assert_eq!(read_request_body(reader).unwrap().unwrap(), "hello world");
+
+        let reader = HttpBodyReader::new(chunked.as_bytes(), HttpBodyFraming::Chunked, Some(8));
+        let e = read_request_body(reader).err().unwrap();
+        assert_eq!(e.status, HttpStatus::PayloadTooLarge);
+        let reader = HttpBodyReader::new("abc".as_bytes(), HttpBodyFraming::Length(5), None);
+        assert_eq!(
+            read_request_body(reader).err().unwrap().status,
+            HttpStatus::BadRequest
+        );
+    }
+
+    #[test]
+    fn test_normalize_target() {
+        let mut metadata = HttpRequestMetaData::parse(
+            "GET HTTP://Example.com:8080/a/b?c=d HTTP/1.1\n\
+            host: other.com\n\
+            Accept: */*",
+        )
+        .unwrap();
+        metadata.normalize_target();
+        assert_eq!(metadata.uri, "/a/b?c=d");
+        assert_eq!(metadata.header("Host"), Some("Example.com:8080"));
+        assert_eq!(metadata.headers.len(), 2);
+        for (target, uri) in [
+            ("http://example.com", "/"),
+            ("https://example.com?x", "/?x"),
+        ] {
+            let mut metadata =
+                HttpRequestMetaData::parse(&format!("GET {target} HTTP/1.1")).unwrap();
+            metadata.normalize_target();
+            assert_eq!(metadata.uri, uri);
+            assert_eq!(metadata.header("Host"), Some("example.com"));
+        }
+        let mut metadata = HttpRequestMetaData::parse("GET /http://x HTTP/1.1").unwrap();
+        metadata.normalize_target();
+        assert_eq!(metadata.uri, "/http://x");
+    }
+
     #[test]
     fn test_parse_body_request_body_none_when_content_length_zero() {
         let correct_body = "This is a body".to_string();
         assert!(
             HttpRequest::parse_request_body(&mut BufReader::new(correct_body.as_bytes()), 0)
+                .unwrap()
                 .is_none()
         );
     }
@@ -228,7 +731,8 @@ mod tests {
         let parsed_body = HttpRequest::parse_request_body(
             &mut BufReader::new(correct_body.as_bytes()),
             correct_body.len(),
-        );
+        )
+        .unwrap();
         assert!(parsed_body.is_some());
         assert_eq!(parsed_body.unwrap(), correct_body)
     }
@@ -328,4 +832,141 @@ mod tests {
             body.len()
         );
     }
+
+    #[test]
+    fn test_parse_request_rejects_malformed_input() {
+        let parse = |raw: &[u8]| parse_http_request(&mut BufReader::new(raw));
+        for raw in [
+            &b""[..],
+            b"\r\n\r\n",
+            b"GET\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\xfe\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -4\r\n\r\nbody",
+        ] {
+            let e = parse(raw).err();
+            assert_eq!(e.map(|e| e.status), Some(HttpStatus::BadRequest), "{raw:?}");
+        }
+        let truncated = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4294967295\r\n\r\nab";
+        let e = parse(truncated).err().unwrap();
+        assert_eq!(e.status, HttpStatus::BadRequest);
+        assert_eq!(
+            e.message(),
+            "request body ended after 2 of 4294967295 bytes"
+        );
+        let request = parse(b"GET / HTTP/1.1\r\nHost: a").unwrap();
+        assert_eq!(request.metadata.header("Host"), Some("a"));
+    }
+
+    #[test]
+    fn test_parse_request_decodes_chunked_bodies() {
+        let raw = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\nTrailer: x\r\n\r\nNEXT";
+        let mut reader = BufReader::new(&raw[..]);
+        let request = parse_http_request(&mut reader).unwrap();
+        assert_eq!(request.body.as_deref(), Some("abcde"));
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+
+        let parse = |raw: &[u8]| parse_http_request(&mut BufReader::new(raw)).err();
+        let truncated = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab";
+        assert_eq!(
+            parse(truncated).map(|e| e.status),
+            Some(HttpStatus::BadRequest)
+        );
+        let gzip = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert_eq!(
+            parse(gzip).map(|e| e.status),
+            Some(HttpStatus::NotImplemented)
+        );
+    }
+
+    #[test]
+    fn test_parse_request_limits_head_size() {
+        let parse = |raw: String| parse_http_request(&mut BufReader::new(raw.as_bytes())).err();
+        let long = "a".repeat(MAX_LINE_LENGTH);
+        assert_eq!(
+            parse(format!("GET /{long} HTTP/1.1\r\n\r\n")).map(|e| e.status),
+            Some(HttpStatus::URITooLong)
+        );
+        assert_eq!(
+            parse(format!("GET / HTTP/1.1\r\nX-Long: {long}\r\n\r\n")).map(|e| e.status),
+            Some(HttpStatus::RequestHeaderFieldsTooLarge)
+        );
+        let headers = (0..MAX_HEAD_SIZE / 64)
+            .map(|i| format!("X-{i}: {}\r\n", "b".repeat(60)))
+            .collect::<String>();
+        assert_eq!(
+            parse(format!("GET / HTTP/1.1\r\n{headers}\r\n")).map(|e| e.status),
+            Some(HttpStatus::RequestHeaderFieldsTooLarge)
+        );
+        let header = "c".repeat(MAX_LINE_LENGTH - 16);
+        assert!(parse(format!("GET / HTTP/1.1\r\nX-Long: {header}\r\n\r\n")).is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_http_request_never_panics(raw in any::<Vec<u8>>()) {
+            let _ = parse_http_request(&mut BufReader::new(raw.as_slice()));
+        }
+
+        #[test]
+        fn test_parse_http_request_never_panics_on_mangled_requests(
+            raw in "[A-Z]{1,7} /[ -~]{0,20} HTTP/1(\\.1)?(\r?\n[!-~]{1,10}(: ?[ -~]{0,10})?){0,4}\r?\n\r?\n[ -~]{0,8}"
+        ) {
+            if let Ok(mut request) = parse_http_request(&mut BufReader::new(raw.as_bytes())) {
+                request.metadata.normalize_target();
+                let _ = (request.metadata.body_framing(), request.metadata.expects_continue());
+                let _ = (request.metadata.query_param("a"), request.metadata.cookies());
+            }
+        }
+
+        // Bodies are ASCII as the parser decodes them one byte per char, and header values
+        // hold no `: ` as it separates names from values. Bodies are sent chunked when a
+        // chunk size is drawn.
+        #[test]
+        fn test_request_display_parse_round_trip(
+            method in prop::sample::select(HttpMethod::ALL.to_vec()),
+            uri in "/[A-Za-z0-9._~%!$&'()*+,;=:@/?-]{0,30}",
+            protocol in prop::sample::select(vec![HttpProtocol::Http1, HttpProtocol::Http1_1]),
+            headers in prop::collection::btree_map(
+                "[A-Za-z][A-Za-z0-9-]{0,15}"
+                    .prop_filter("Content-Length is set by the test", |n| {
+                        !n.eq_ignore_ascii_case("Content-Length")
+                    }),
+                "[!-9;-~]{1,16}( [!-9;-~]{1,16}){0,2}",
+                0..6,
+            ),
+            body in proptest::option::of("[ -~\r\n\t]{1,64}"),
+            chunk_size in proptest::option::of(1usize..16),
+        ) {
+            let mut headers = headers;
+            let mut encoded = body.clone().unwrap_or_default();
+            match (body.as_ref(), chunk_size) {
+                (Some(b), Some(size)) => {
+                    headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+                    encoded = b
+                        .as_bytes()
+                        .chunks(size)
+                        .map(|c| format!("{:x}\r\n{}\r\n", c.len(), String::from_utf8_lossy(c)))
+                        .chain(["0\r\n\r\n".to_string()])
+                        .collect();
+                }
+                (Some(b), None) => {
+                    headers.insert("Content-Length".to_string(), b.len().to_string());
+                }
+                (None, _) => {}
+            }
+            let expected = HttpRequest {
+                metadata: HttpRequestMetaData { protocol, uri, method, headers },
+                body,
+                stream: None,
+            };
+            let raw = format!("{}\r\n{encoded}", expected.metadata);
+            let parsed = parse_http_request(&mut BufReader::new(raw.as_bytes())).unwrap();
+            prop_assert_eq!(parsed.metadata, expected.metadata);
+            prop_assert_eq!(parsed.body, expected.body);
+        }
+    }
 }
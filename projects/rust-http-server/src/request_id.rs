@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::{common::HttpServerContext, request::HttpRequestMetaData};
+
+/// Header carrying the id of a request, accepted from clients and echoed on responses.
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+/// Context key of the id of the request being handled.
+pub const REQUEST_ID_KEY: &str = "request.id";
+
+/// Longest incoming id that is kept, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// How the ids of requests arriving without a usable `X-Request-ID` are generated.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum RequestIdFormat {
+    /// A random UUID v4, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    #[default]
+    Uuid,
+    /// A ULID, sortable by creation time, e.g. `01ARZ3NDEKTSV4RRFFQ69G5FAV`.
+    Ulid,
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid" => Ok(RequestIdFormat::Uuid),
+            "ulid" => Ok(RequestIdFormat::Ulid),
+            _ => Err(format!("unknown request id format: {s}")),
+        }
+    }
+}
+
+impl RequestIdFormat {
+    pub fn generate(&self) -> String {
+        match self {
+            RequestIdFormat::Uuid => Uuid::new_v4().to_string(),
+            RequestIdFormat::Ulid => Ulid::new().to_string(),
+        }
+    }
+
+    /// Returns the id sent by the client in `metadata`, or a new one when there is none or it
+    /// could be used to forge headers or log lines.
+    pub fn resolve(&self, metadata: Option<&HttpRequestMetaData>) -> String {
+        metadata
+            .and_then(|m| m.header(REQUEST_ID_HEADER))
+            .filter(|id| is_valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(|| self.generate())
+    }
+}
+
+/// Ids are kept when they are short runs of visible ASCII characters.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Sets the `X-Request-ID` header of `metadata` to `id`, replacing the one sent by the client
+/// whatever its case.
+pub fn set_request_id(metadata: &mut HttpRequestMetaData, id: &str) {
+    metadata
+        .headers
+        .retain(|k, _| !k.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+    metadata
+        .headers
+        .insert(REQUEST_ID_HEADER.to_string(), id.to_string());
+}
+
+/// Gives handlers access to the id of the request they serve.
+pub trait HttpRequestIdContext {
+    fn request_id(&self) -> Option<&str>;
+}
+
+impl HttpRequestIdContext for HttpServerContext {
+    fn request_id(&self) -> Option<&str> {
+        self.get(REQUEST_ID_KEY).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(id: &str) -> HttpRequestMetaData {
+        let raw = format!("GET / HTTP/1.1\nHost: localhost\nx-request-id: {id}\n");
+        HttpRequestMetaData::parse(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_generate_request_ids() {
+        let uuid = RequestIdFormat::Uuid.generate();
+        assert!(Uuid::parse_str(&uuid).is_ok());
+        let ulid = RequestIdFormat::Ulid.generate();
+        assert!(Ulid::from_string(&ulid).is_ok());
+        assert_ne!(RequestIdFormat::Uuid.generate(), uuid);
+        assert_eq!(
+            "ulid".parse::<RequestIdFormat>().unwrap(),
+            RequestIdFormat::Ulid
+        );
+        assert!("snowflake".parse::<RequestIdFormat>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_request_id() {
+        let format = RequestIdFormat::Uuid;
+        assert_eq!(format.resolve(Some(&metadata("abc-123"))), "abc-123");
+        for forged in ["a b", "\"quoted\"x\u{7f}", &"a".repeat(129)] {
+            let id = format.resolve(Some(&metadata(forged)));
+            assert!(Uuid::parse_str(&id).is_ok(), "{forged}");
+        }
+        assert!(Uuid::parse_str(&format.resolve(None)).is_ok());
+
+        let mut m = metadata("abc-123");
+        set_request_id(&mut m, "def");
+        assert_eq!(m.header(REQUEST_ID_HEADER), Some("def"));
+        assert_eq!(m.headers.len(), 2);
+    }
+}
@@ -1,27 +1,105 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read},
+    str::FromStr,
+};
 
-use crate::common::{HttpBody, HttpError, HttpHeaders, HttpProtocol, HttpStatus};
+use crate::{
+    chunked::ChunkedReader,
+    common::{HttpBody, HttpError, HttpHeaders, HttpProtocol, HttpStatus},
+    cookie::{add_cookie, SetCookie},
+};
 
-#[derive(Clone)]
+/// A response body that is copied to the client as it is read instead of being buffered.
+pub type HttpResponseStream = Box<dyn Read + Send>;
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct HttpResponseMetaData {
     pub protocol: HttpProtocol,
     pub status: HttpStatus,
     pub headers: HttpHeaders,
 }
 
+/// Headers holding several lines are written once per line, as `Set-Cookie` values cannot
+/// be folded into a single header.
 impl Display for HttpResponseMetaData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let headers_str = self
             .headers
             .iter()
-            .map(|item| format!("{}: {}\r\n", item.0, item.1))
+            .flat_map(|(k, v)| v.lines().map(move |v| format!("{k}: {v}\r\n")))
             .collect::<String>();
         write!(f, "{} {}\r\n{}", self.protocol, self.status, headers_str)
     }
 }
+
+impl HttpResponseMetaData {
+    fn parse_status_line(first_line: &str) -> Result<(HttpProtocol, HttpStatus), HttpError> {
+        let mut split = first_line.splitn(3, ' ');
+        let (protocol, code) = match (split.next(), split.next()) {
+            (Some(p), Some(c)) => (p, c),
+            _ => {
+                return Err(HttpError::new(
+                    HttpStatus::BadGateway,
+                    format!("malformed status line: {first_line}"),
+                ))
+            }
+        };
+        let protocol = match protocol {
+            "HTTP/1.0" => HttpProtocol::Http1,
+            p => HttpProtocol::from_str(p)
+                .map_err(|e| HttpError::new(HttpStatus::BadGateway, e.to_string()))?,
+        };
+        match code.parse::<u16>() {
+            Ok(c) if (100..600).contains(&c) => Ok((protocol, HttpStatus::from_code(c))),
+            _ => Err(HttpError::new(
+                HttpStatus::BadGateway,
+                format!("malformed status code: {code}"),
+            )),
+        }
+    }
+
+    /// Parses the status line and headers of a raw http response. Repeated headers are
+    /// joined with `, `, or with line breaks for `Set-Cookie`.
+    ///
+    /// # Errors    HttpError
+    ///
+    /// This function will return a `BadGateway` error if the status line or any of the header
+    /// lines are malformed.
+    pub fn parse(raw: &str) -> Result<HttpResponseMetaData, HttpError> {
+        let mut lines = raw.lines();
+        let (protocol, status) = match lines.next() {
+            Some(l) => HttpResponseMetaData::parse_status_line(l)?,
+            None => return Err(HttpError::new(HttpStatus::BadGateway, "empty response")),
+        };
+        let mut headers = HttpHeaders::new();
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                HttpError::new(HttpStatus::BadGateway, format!("malformed header: {line}"))
+            })?;
+            let (name, value) = (name.trim().to_string(), value.trim());
+            let separator = match name.eq_ignore_ascii_case("Set-Cookie") {
+                true => "\n",
+                false => ", ",
+            };
+            headers
+                .entry(name)
+                .and_modify(|v| *v = format!("{v}{separator}{value}"))
+                .or_insert_with(|| value.to_string());
+        }
+        Ok(HttpResponseMetaData {
+            protocol,
+            status,
+            headers,
+        })
+    }
+}
+
 pub struct HttpResponse {
     pub metadata: HttpResponseMetaData,
     pub body: HttpBody,
+    /// Written after `body` when set, see [`HttpResponse::with_stream`].
+    pub stream: Option<HttpResponseStream>,
 }
 
 impl Display for HttpResponse {
@@ -48,23 +126,137 @@ impl HttpResponse {
                 headers: headers,
             },
             body: body,
+            stream: None,
+        }
+    }
+
+    /// Creates a response whose body is streamed from `stream` when written to the client.
+    pub fn with_stream(
+        protocol: HttpProtocol,
+        status: HttpStatus,
+        headers: HttpHeaders,
+        stream: HttpResponseStream,
+    ) -> Self {
+        HttpResponse {
+            stream: Some(stream),
+            ..HttpResponse::new(protocol, status, headers, None)
         }
     }
 
+    /// Adds a `Set-Cookie` header, keeping the cookies already set.
+    pub fn add_cookie(&mut self, cookie: &SetCookie) {
+        add_cookie(&mut self.metadata.headers, cookie);
+    }
+
     pub fn from_err(err: HttpError, protocol: Option<HttpProtocol>) -> HttpResponse {
         HttpResponse {
             metadata: HttpResponseMetaData {
                 protocol: protocol.unwrap_or(HttpProtocol::Http1_1),
                 status: err.status,
-                headers: HttpHeaders::new(),
+                headers: err.headers,
             },
             body: None,
+            stream: None,
+        }
+    }
+}
+/// Reads the status line and headers of a response off `reader`, up to the empty line ending
+/// them.
+pub(crate) fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before a response was received",
+            ));
+        }
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            return Ok(head);
         }
+        head.push_str(&line);
     }
 }
+
+/// Reads the body of the response described by `metadata` off `reader`. The body is
+/// delimited by `Transfer-Encoding: chunked`, `Content-Length` or the end of the stream, in
+/// that order, and `204` and `304` responses have none.
+///
+/// Returns the body along with whether its end was announced rather than marked by the end
+/// of the stream, in which case the connection cannot carry another response.
+pub(crate) fn read_response_body<R: Read>(
+    reader: &mut BufReader<R>,
+    metadata: &HttpResponseMetaData,
+) -> io::Result<(Vec<u8>, bool)> {
+    let header = |name: &str| {
+        metadata
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let mut body = Vec::new();
+    if matches!(
+        metadata.status,
+        HttpStatus::NoContent | HttpStatus::NotModified
+    ) {
+        return Ok((body, true));
+    }
+    let chunked = header("Transfer-Encoding").is_some_and(|v| {
+        v.rsplit(',')
+            .next()
+            .is_some_and(|c| c.trim().eq_ignore_ascii_case("chunked"))
+    });
+    if chunked {
+        ChunkedReader::new(reader).read_to_end(&mut body)?;
+        return Ok((body, true));
+    }
+    let Some(length) = header("Content-Length") else {
+        reader.read_to_end(&mut body)?;
+        return Ok((body, false));
+    };
+    let length = length.trim().parse::<u64>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed Content-Length: {length}"),
+        )
+    })?;
+    reader.take(length).read_to_end(&mut body)?;
+    if (body.len() as u64) < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "response body ended unexpectedly",
+        ));
+    }
+    Ok((body, true))
+}
+
+/// Parses a raw http response from a readable buffer, the counterpart of
+/// [`parse_http_request`](crate::request::parse_http_request). The body is decoded as UTF-8,
+/// an empty body is `None`.
+///
+/// # Errors    HttpError
+///
+/// This function will return a `BadGateway` error if the response is malformed or truncated.
+pub fn parse_http_response<R: Read>(buffer: &mut BufReader<R>) -> Result<HttpResponse, HttpError> {
+    let read_error =
+        |e: io::Error| HttpError::new(HttpStatus::BadGateway, format!("cannot read response: {e}"));
+    let head = read_response_head(buffer).map_err(read_error)?;
+    let metadata = HttpResponseMetaData::parse(&head)?;
+    let (body, _) = read_response_body(buffer, &metadata).map_err(read_error)?;
+    let body = (!body.is_empty()).then(|| String::from_utf8_lossy(&body).into_owned());
+    Ok(HttpResponse {
+        metadata,
+        body,
+        stream: None,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     fn collect_into_lines(raw: &str) -> Vec<String> {
         raw.lines().map(|s| s.to_string()).collect::<Vec<String>>()
@@ -111,4 +303,120 @@ mod tests {
         );
         assert_eq!(raw_reponse, format!("{m}"));
     }
+
+    #[test]
+    fn test_response_metadata_parse() {
+        let m = HttpResponseMetaData::parse(
+            "HTTP/1.1 404 Not Found\r\n\
+            Content-Length: 0\r\n\
+            Server:upstream\r\n",
+        )
+        .unwrap();
+        assert_eq!(m.protocol, HttpProtocol::Http1_1);
+        assert_eq!(m.status, HttpStatus::NotFound);
+        assert_eq!(m.headers["Content-Length"], "0");
+        assert_eq!(m.headers["Server"], "upstream");
+    }
+
+    #[test]
+    fn test_response_metadata_parse_errors() {
+        assert!(HttpResponseMetaData::parse("").is_err());
+        assert!(HttpResponseMetaData::parse("HTTP/1.1").is_err());
+        assert!(HttpResponseMetaData::parse("HTTP/1.1 abc OK").is_err());
+        assert!(HttpResponseMetaData::parse("SPDY/3 200 OK").is_err());
+        let e = HttpResponseMetaData::parse("HTTP/1.1 200 OK\r\nbroken header").err();
+        assert_eq!(e.unwrap().status, HttpStatus::BadGateway);
+    }
+    #[test]
+    fn test_response_set_cookies() {
+        let mut response = HttpResponse::new(
+            HttpProtocol::Http1_1,
+            HttpStatus::Ok,
+            HttpHeaders::new(),
+            None,
+        );
+        response.add_cookie(&SetCookie::new("a", "1").unwrap().path("/").unwrap());
+        response.add_cookie(&SetCookie::new("b", "2").unwrap());
+        let raw = response.metadata.to_string();
+        assert_eq!(
+            raw,
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1; Path=/\r\nSet-Cookie: b=2\r\n"
+        );
+        let parsed = HttpResponseMetaData::parse(&format!("{raw}Vary: A\r\nVary: B\r\n")).unwrap();
+        assert_eq!(parsed.headers["Set-Cookie"], "a=1; Path=/\nb=2");
+        assert_eq!(parsed.headers["Vary"], "A, B");
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let parse = |raw: &str| parse_http_response(&mut BufReader::new(raw.as_bytes()));
+        let response = parse("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef").unwrap();
+        assert_eq!(response.metadata.status, HttpStatus::Ok);
+        assert_eq!(response.body.as_deref(), Some("abc"));
+
+        let response = parse(
+            "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body.as_deref(), Some("abc"));
+
+        let response = parse("HTTP/1 500 Internal Server Error\r\n\r\nuntil the end").unwrap();
+        assert_eq!(response.metadata.protocol, HttpProtocol::Http1);
+        assert_eq!(response.body.as_deref(), Some("until the end"));
+
+        let response = parse("HTTP/1.1 304 Not Modified\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert_eq!(response.body, None);
+
+        for raw in [
+            "",
+            "HTTP/1.1 200 OK\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc",
+            "HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n",
+        ] {
+            let e = parse(raw).err();
+            assert_eq!(e.map(|e| e.status), Some(HttpStatus::BadGateway), "{raw:?}");
+        }
+    }
+
+    fn header_name() -> impl Strategy<Value = String> {
+        "[A-Za-z][A-Za-z0-9-]{0,15}".prop_filter("framing headers are set by the test", |n| {
+            !n.eq_ignore_ascii_case("Content-Length")
+                && !n.eq_ignore_ascii_case("Transfer-Encoding")
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_response_display_parse_round_trip(
+            protocol in prop::sample::select(vec![HttpProtocol::Http1, HttpProtocol::Http1_1]),
+            code in 200u16..600,
+            headers in prop::collection::btree_map(
+                header_name(),
+                "[!-~]{1,16}( [!-~]{1,16}){0,2}",
+                0..6,
+            ),
+            body in any::<String>(),
+        ) {
+            let status = HttpStatus::from_code(code);
+            let mut headers = headers;
+            let body = match status {
+                HttpStatus::NoContent | HttpStatus::NotModified => None,
+                _ => Some(body).filter(|b| !b.is_empty()),
+            };
+            if let Some(b) = body.as_ref() {
+                headers.insert("Content-Length".to_string(), b.len().to_string());
+            }
+            let expected = HttpResponse::new(protocol, status, headers, body);
+            let raw = expected.to_string();
+            let parsed = parse_http_response(&mut BufReader::new(raw.as_bytes())).unwrap();
+            prop_assert_eq!(parsed.metadata, expected.metadata);
+            prop_assert_eq!(parsed.body, expected.body);
+        }
+
+        #[test]
+        fn test_parse_http_response_never_panics(raw in any::<Vec<u8>>()) {
+            let _ = parse_http_response(&mut BufReader::new(raw.as_slice()));
+        }
+    }
 }
@@ -1,44 +1,170 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+};
 
 use crate::{
-    common::{HttpError, HttpMethod, HttpServerContext, HttpStatus},
+    auth::Auth,
+    common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext, HttpStatus},
+    cors::Cors,
+    openapi::{self, HttpRouteDoc, OpenApiInfo},
     request::HttpRequest,
     response::HttpResponse,
 };
 use log::{debug, info};
 use path_tree::PathTree;
+use regex::Regex;
+
+pub type HttpRouterFunc =
+    Arc<dyn Fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError> + Send + Sync>;
+
+/// Runs around the handlers of a router or route group. It receives the request, its path
+/// parameters and the next handler, and decides whether and how to call it.
+pub type HttpMiddleware = Arc<
+    dyn Fn(&HttpRequest, &HttpServerContext, &HttpRouterFunc) -> Result<HttpResponse, HttpError>
+        + Send
+        + Sync,
+>;
+
+/// Checks a request before its body is read, such as its credentials, and returns the context
+/// the handler runs with. Guards run as middlewares and, for `Expect: 100-continue` requests,
+/// before the client is told to send the body.
+pub type HttpGuard = Arc<
+    dyn Fn(&HttpRequest, &HttpServerContext) -> Result<HttpServerContext, HttpError> + Send + Sync,
+>;
 
-pub type HttpRouterFunc = fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError>;
+/// Context key set once the guards of a route passed, so that their middlewares do not run
+/// them a second time. Path parameter names cannot contain a dot, so they never collide with
+/// it.
+pub const GUARDED_KEY: &str = "router.guarded";
 
-type HttpMethodRouter = PathTree<HttpRouterFunc>;
+/// A registered handler along with the patterns its path parameters must match.
+#[derive(Clone)]
+struct HttpRoute {
+    handler: HttpRouterFunc,
+    guards: Vec<HttpGuard>,
+    streaming: bool,
+    constraints: Vec<(String, Regex)>,
+}
+
+impl HttpRoute {
+    fn accepts(&self, params: &HttpServerContext) -> bool {
+        self.constraints
+            .iter()
+            .all(|(name, re)| params.get(name).is_some_and(|v| re.is_match(v)))
+    }
+}
+
+type HttpMethodRouter = PathTree<HttpRoute>;
 type HttpRouterMap = HashMap<HttpMethod, HttpMethodRouter>;
 #[derive(Clone)]
 pub struct HttpRouter {
     router_map: HttpRouterMap,
+    /// Every registered method and path, sorted, as given to the builder.
+    routes: Vec<(HttpMethod, String)>,
+    docs: Arc<HashMap<(HttpMethod, String), HttpRouteDoc>>,
+}
+
+// =========================================================
+// =================== HttpRouterError =====================
+// =========================================================
+/// A route refused by [`HttpRouterBuilder::build`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum HttpRouteError {
+    #[error("duplicate route {method} {path}")]
+    Duplicate { method: HttpMethod, path: String },
+    /// Both routes match exactly the same requests, so `path` can never be reached.
+    #[error("route {method} {path} is shadowed by {method} {by}")]
+    Shadowed {
+        method: HttpMethod,
+        path: String,
+        by: String,
+    },
+    #[error("malformed route {method} {path}: {reason}")]
+    Malformed {
+        method: HttpMethod,
+        path: String,
+        reason: String,
+    },
+}
+
+/// Every route error found while building a router.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub struct HttpRouterError {
+    pub errors: Vec<HttpRouteError>,
+}
+
+impl Display for HttpRouterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors = self
+            .errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "invalid routes: {}", errors.join("; "))
+    }
+}
+
+/// A route resolved for a request: the handler, the extracted path parameters and the
+/// pattern the handler was registered with.
+pub struct HttpRouteMatch<'a> {
+    pub handler: &'a HttpRouterFunc,
+    /// The guards the handler runs, outermost first.
+    pub guards: &'a [HttpGuard],
+    /// Whether the handler reads the body from [`HttpRequest::stream`].
+    pub streaming: bool,
+    pub params: HttpServerContext,
+    pub pattern: String,
+}
+
+impl HttpRouteMatch<'_> {
+    /// Runs the guards of the route on `req`, which needs no body, and returns the context to
+    /// call the handler with. The guards are not run again by the handler.
+    pub fn check_guards(&self, req: &HttpRequest) -> Result<HttpServerContext, HttpError> {
+        let mut params = self
+            .guards
+            .iter()
+            .try_fold(self.params.clone(), |params, guard| guard(req, &params))?;
+        params.insert(GUARDED_KEY.to_string(), "true".to_string());
+        Ok(params)
+    }
 }
 
 impl HttpRouter {
-    pub fn parse_request_route(
-        self: &Self,
-        req: &HttpRequest,
-    ) -> Option<(&HttpRouterFunc, HttpServerContext)> {
+    pub fn find_route(&self, req: &HttpRequest) -> Option<HttpRouteMatch<'_>> {
         debug!(
             "HttpRouter: parsing request route protocol: {} # method: {} # uri: {}",
             req.metadata.protocol, req.metadata.method, req.metadata.uri
         );
-        let item = match self.router_map.get(&req.metadata.method) {
-            Some(router) => router.find(&req.metadata.uri),
-            None => None,
-        };
-        match item {
-            Some((func, path)) => Some((
-                func,
-                path.params_iter()
-                    .map(|(x, y)| (x.to_string(), y.to_string()))
-                    .collect(),
-            )),
-            None => None,
+        let router = self.router_map.get(&req.metadata.method)?;
+        let target = req.metadata.uri.split(['?', '#']).next().unwrap_or("");
+        let (route, path) = router.find(target)?;
+        let params = path
+            .params_iter()
+            .map(|(x, y)| (x.to_string(), y.to_string()))
+            .collect();
+        if !route.accepts(&params) {
+            debug!(
+                "HttpRouter: {} rejected by the route constraints",
+                req.metadata.uri
+            );
+            return None;
         }
+        Some(HttpRouteMatch {
+            handler: &route.handler,
+            guards: &route.guards,
+            streaming: route.streaming,
+            params,
+            pattern: path.pattern(),
+        })
+    }
+    pub fn parse_request_route(
+        self: &Self,
+        req: &HttpRequest,
+    ) -> Option<(&HttpRouterFunc, HttpServerContext)> {
+        self.find_route(req).map(|m| (m.handler, m.params))
     }
     pub fn route(self: &Self, req: &HttpRequest) -> Result<HttpResponse, HttpError> {
         let (handler, params) = match self.parse_request_route(req) {
@@ -47,39 +173,438 @@ impl HttpRouter {
         };
         (handler)(req, &params)
     }
+    /// Returns every registered method and path, sorted by method then path.
+    pub fn routes(&self) -> &[(HttpMethod, String)] {
+        &self.routes
+    }
+    /// Lists the routes one path per line along with the methods it accepts, for startup logs.
+    pub fn route_table(&self) -> String {
+        let mut paths: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (method, path) in self.routes.iter() {
+            paths.entry(path).or_default().push(method.to_string());
+        }
+        let width = paths.values().map(|m| m.join(",").len()).max().unwrap_or(0);
+        paths
+            .iter()
+            .map(|(path, methods)| format!("{:<width$} {path}\n", methods.join(",")))
+            .collect()
+    }
+    /// Renders the OpenAPI 3 document of the routes, documented or not.
+    pub fn openapi(&self, info: &OpenApiInfo) -> serde_json::Value {
+        openapi::document(&self.routes, &self.docs, info)
+    }
+}
+
+/// Typed access to the path parameters of a request.
+pub trait HttpPathParams {
+    /// Converts the parameter `name` to `T`, failing with `400 BadRequest` when it does not
+    /// parse. A parameter missing from the route is a server error.
+    fn parse<T: FromStr>(&self, name: &str) -> Result<T, HttpError>;
+}
+
+impl HttpPathParams for HttpServerContext {
+    fn parse<T: FromStr>(&self, name: &str) -> Result<T, HttpError> {
+        let value = self.get(name).ok_or_else(|| {
+            HttpError::new(
+                HttpStatus::InternalServerError,
+                format!("route has no path parameter `{name}`"),
+            )
+        })?;
+        value.parse::<T>().map_err(|_| {
+            HttpError::new(
+                HttpStatus::BadRequest,
+                format!("invalid path parameter `{name}`: {value}"),
+            )
+        })
+    }
+}
+
+/// Splits the inline constraints off a route path, `/users/:id(\d+)` giving `/users/:id` and a
+/// constraint requiring `id` to match `\d+` as a whole.
+pub(crate) fn parse_constraints(path: &str) -> Result<(String, Vec<(String, Regex)>), String> {
+    let mut pattern = String::with_capacity(path.len());
+    let mut constraints = Vec::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        pattern.push(c);
+        if c != ':' {
+            continue;
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        pattern.push_str(&name);
+        if chars.next_if_eq(&'(').is_none() {
+            continue;
+        }
+        let (mut depth, mut re) = (1, String::new());
+        while depth > 0 {
+            let c = chars
+                .next()
+                .ok_or_else(|| format!("unclosed constraint on `:{name}` in {path}"))?;
+            match c {
+                '\\' => {
+                    re.push(c);
+                    re.extend(chars.next());
+                    continue;
+                }
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth > 0 {
+                re.push(c);
+            }
+        }
+        if name.is_empty() {
+            return Err(format!("constraint without a parameter name in {path}"));
+        }
+        let re = Regex::new(&format!("^(?:{re})$"))
+            .map_err(|e| format!("invalid constraint on `:{name}` in {path}: {e}"))?;
+        constraints.push((name, re));
+    }
+    Ok((pattern, constraints))
+}
+
+/// Joins a mount prefix and a route path, `/api` and `/` giving `/api`.
+fn join_path(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let prefix = match prefix.is_empty() || prefix.starts_with('/') {
+        true => prefix.to_string(),
+        false => format!("/{prefix}"),
+    };
+    match path.trim_start_matches('/') {
+        "" if prefix.is_empty() => "/".to_string(),
+        "" => prefix,
+        path => format!("{prefix}/{path}"),
+    }
+}
+
+/// Paths with at least one route but no `OPTIONS` route, which need one for CORS preflight
+/// requests to reach the middlewares.
+fn preflight_paths<'a, I>(routes: I) -> BTreeSet<String>
+where
+    I: Iterator<Item = (&'a HttpMethod, &'a String)>,
+{
+    let (options, others): (Vec<_>, Vec<_>) = routes.partition(|(m, _)| **m == HttpMethod::OPTIONS);
+    let options = options.into_iter().map(|(_, p)| p).collect::<BTreeSet<_>>();
+    others
+        .into_iter()
+        .filter(|(_, p)| !options.contains(p))
+        .map(|(_, p)| p.clone())
+        .collect()
+}
+
+fn method_not_allowed(_: &HttpRequest, _: &HttpServerContext) -> Result<HttpResponse, HttpError> {
+    Err(HttpError::new(HttpStatus::MethodNotAllowed, ""))
 }
 
 pub struct HttpRouterBuilder {
     routers: HashMap<(HttpMethod, String), HttpRouterFunc>,
+    middlewares: Vec<HttpMiddleware>,
+    guards: Vec<HttpGuard>,
+    /// Guards of the routers mounted in this one, which only apply to their own routes.
+    route_guards: HashMap<(HttpMethod, String), Vec<HttpGuard>>,
+    streaming: HashSet<(HttpMethod, String)>,
+    errors: Vec<HttpRouteError>,
+    docs: HashMap<(HttpMethod, String), HttpRouteDoc>,
+    openapi: Option<(String, OpenApiInfo)>,
+    preflight: bool,
+}
+
+impl Default for HttpRouterBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HttpRouterBuilder {
     pub fn new() -> Self {
         HttpRouterBuilder {
             routers: HashMap::new(),
+            middlewares: Vec::new(),
+            guards: Vec::new(),
+            route_guards: HashMap::new(),
+            streaming: HashSet::new(),
+            errors: Vec::new(),
+            docs: HashMap::new(),
+            openapi: None,
+            preflight: false,
         }
     }
-    pub fn add_route(
-        self: &mut Self,
+    pub fn add_route<F>(self: &mut Self, method: HttpMethod, path: &str, func: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.insert_route(method, path, Arc::new(func))
+    }
+    /// Registers `func` like [`HttpRouterBuilder::add_route`], but leaves the body unread so
+    /// the handler can read it from [`HttpRequest::stream`] as it arrives, with constant
+    /// memory. `HttpRequest::body` is always `None`.
+    pub fn add_streaming_route<F>(&mut self, method: HttpMethod, path: &str, func: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.streaming.insert((method, path.to_string()));
+        self.insert_route(method, path, Arc::new(func))
+    }
+    /// Registers `func` like [`HttpRouterBuilder::add_route`] and describes it in the OpenAPI
+    /// document with `doc`.
+    pub fn add_documented_route<F>(
+        &mut self,
         method: HttpMethod,
         path: &str,
-        func: HttpRouterFunc,
-    ) -> &mut Self {
+        doc: HttpRouteDoc,
+        func: F,
+    ) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.docs.insert((method, path.to_string()), doc);
+        self.insert_route(method, path, Arc::new(func))
+    }
+    /// Serves the OpenAPI document of the built router as JSON on `GET path`.
+    pub fn serve_openapi(&mut self, path: &str, info: OpenApiInfo) -> &mut Self {
+        self.openapi = Some((path.to_string(), info));
+        self
+    }
+    /// Registers `func` for every method on `prefix` and on every path below it.
+    pub fn add_prefix_route<F>(&mut self, prefix: &str, func: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext) -> Result<HttpResponse, HttpError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let func: HttpRouterFunc = Arc::new(func);
+        let prefix = prefix.trim_end_matches('/');
+        for method in HttpMethod::ALL {
+            if !prefix.is_empty() {
+                self.insert_route(method, prefix, Arc::clone(&func));
+            }
+            self.insert_route(method, &format!("{prefix}/*"), Arc::clone(&func));
+        }
+        self
+    }
+    /// Runs `func` around every route of this builder, including the ones registered after it
+    /// and the ones of mounted routers. Middlewares run in the order they were added.
+    pub fn add_middleware<F>(&mut self, func: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext, &HttpRouterFunc) -> Result<HttpResponse, HttpError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.middlewares.push(Arc::new(func));
+        self
+    }
+    /// Refuses the requests `guard` returns an error for. It runs as a middleware, in the order
+    /// it was added, and also before the body of `Expect: 100-continue` requests is sent.
+    pub fn add_guard<F>(&mut self, guard: F) -> &mut Self
+    where
+        F: Fn(&HttpRequest, &HttpServerContext) -> Result<(), HttpError> + Send + Sync + 'static,
+    {
+        let guard = Arc::new(guard);
+        let checked = Arc::clone(&guard);
+        self.guards
+            .push(Arc::new(move |r, c| checked(r, c).map(|_| c.clone())));
+        self.add_middleware(move |r, c, next| {
+            if !c.contains_key(GUARDED_KEY) {
+                guard(r, c)?;
+            }
+            next(r, c)
+        })
+    }
+    /// Authenticates the requests of every route of this builder with `auth`, which also
+    /// guards their bodies.
+    pub fn add_auth(&mut self, auth: Auth) -> &mut Self {
+        let guard = auth.clone();
+        self.guards.push(Arc::new(move |r, c| guard.check(r, c)));
+        self.add_middleware(move |r, c, next| match c.contains_key(GUARDED_KEY) {
+            true => next(r, c),
+            false => auth.handle(r, c, next),
+        })
+    }
+    /// Applies the CORS policy `cors` to every route of this builder, registering `OPTIONS`
+    /// routes where needed so preflight requests are answered. Add it before the other
+    /// middlewares so preflight requests do not go through them.
+    pub fn add_cors(&mut self, cors: Cors) -> &mut Self {
+        self.preflight = true;
+        self.add_middleware(move |r, c, next| cors.handle(r, c, next))
+    }
+    /// Registers the routes of `router` below `prefix`. The middlewares of `router` only apply
+    /// to its own routes, and parameters in `prefix` are passed to all of them.
+    pub fn mount(&mut self, prefix: &str, mut router: HttpRouterBuilder) -> &mut Self {
+        if router.preflight {
+            for path in preflight_paths(router.routers.keys().map(|(m, p)| (m, p))) {
+                router
+                    .routers
+                    .insert((HttpMethod::OPTIONS, path), Arc::new(method_not_allowed));
+            }
+        }
+        self.errors.extend(router.errors.iter().cloned());
+        for ((method, path), doc) in router.docs.iter() {
+            self.docs
+                .insert((*method, join_path(prefix, path)), doc.clone());
+        }
+        for ((method, path), func) in router.routers.iter() {
+            let func = Self::wrap(&router.middlewares, Arc::clone(func));
+            let mut guards = router.guards.clone();
+            if let Some(inner) = router.route_guards.get(&(*method, path.clone())) {
+                guards.extend(inner.iter().cloned());
+            }
+            let streaming = router.streaming.contains(&(*method, path.clone()));
+            let path = join_path(prefix, path);
+            if streaming {
+                self.streaming.insert((*method, path.clone()));
+            }
+            if !guards.is_empty() {
+                self.route_guards.insert((*method, path.clone()), guards);
+            }
+            self.insert_route(*method, &path, func);
+        }
+        self
+    }
+    /// Builds a route group with `routes` and mounts it below `prefix`.
+    pub fn group<F>(&mut self, prefix: &str, routes: F) -> &mut Self
+    where
+        F: FnOnce(&mut HttpRouterBuilder),
+    {
+        let mut group = HttpRouterBuilder::new();
+        routes(&mut group);
+        self.mount(prefix, group)
+    }
+    fn wrap(middlewares: &[HttpMiddleware], handler: HttpRouterFunc) -> HttpRouterFunc {
+        middlewares.iter().rev().fold(handler, |next, middleware| {
+            let middleware = Arc::clone(middleware);
+            Arc::new(move |r, c| (middleware)(r, c, &next))
+        })
+    }
+    fn insert_route(&mut self, method: HttpMethod, path: &str, func: HttpRouterFunc) -> &mut Self {
         if self.routers.contains_key(&(method, path.to_string())) {
-            panic!("dupliate endpoint decleration method: {method} path:{path})")
+            self.errors.push(HttpRouteError::Duplicate {
+                method,
+                path: path.to_string(),
+            });
+            return self;
         }
         self.routers.insert((method, path.to_string()), func);
         self
     }
-    pub fn build(self: &Self) -> HttpRouter {
+    /// Builds the router, reporting every duplicate, shadowed or malformed route.
+    ///
+    /// Overlapping patterns such as `/*` and `/pics/:pic` are not errors: the most specific
+    /// one wins, static segments first, then parameters, then wildcards. Only routes that can
+    /// never be reached are refused.
+    pub fn build(self: &Self) -> Result<HttpRouter, HttpRouterError> {
+        let mut errors = self.errors.clone();
+        let mut docs = self.docs.clone();
+        let mut routes = self
+            .routers
+            .iter()
+            .map(|((m, p), f)| (*m, p.clone(), Arc::clone(f)))
+            .collect::<Vec<_>>();
+        routes.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        if let Some((path, info)) = self.openapi.as_ref() {
+            match self.routers.contains_key(&(HttpMethod::GET, path.clone())) {
+                true => errors.push(HttpRouteError::Duplicate {
+                    method: HttpMethod::GET,
+                    path: path.clone(),
+                }),
+                false => {
+                    docs.insert(
+                        (HttpMethod::GET, path.clone()),
+                        HttpRouteDoc::new()
+                            .summary("This OpenAPI document")
+                            .response(HttpStatus::Ok, None),
+                    );
+                    let mut documented = routes
+                        .iter()
+                        .map(|(m, p, _)| (*m, p.clone()))
+                        .collect::<Vec<_>>();
+                    documented.push((HttpMethod::GET, path.clone()));
+                    let document = openapi::document(&documented, &docs, info).to_string();
+                    let handler: HttpRouterFunc = Arc::new(move |r, _| {
+                        let mut headers = HttpHeaders::new();
+                        headers.insert("Content-Type".to_string(), "application/json".to_string());
+                        Ok(HttpResponse::new(
+                            r.metadata.protocol,
+                            HttpStatus::Ok,
+                            headers,
+                            Some(document.clone()),
+                        ))
+                    });
+                    routes.push((HttpMethod::GET, path.clone(), handler));
+                    routes.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+                }
+            }
+        }
+        if self.preflight {
+            for path in preflight_paths(routes.iter().map(|(m, p, _)| (m, p))) {
+                routes.push((HttpMethod::OPTIONS, path, Arc::new(method_not_allowed)));
+            }
+            routes.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        }
         let mut router_map: HttpRouterMap = HttpRouterMap::new();
-        self.routers.iter().for_each(|((m, p), f)| -> () {
-            if !router_map.contains_key(m) {
-                router_map.insert(*m, PathTree::new());
+        let mut inserted: HashMap<(HttpMethod, usize), String> = HashMap::new();
+        for (m, p, f) in routes.iter() {
+            let malformed = |reason: String| HttpRouteError::Malformed {
+                method: *m,
+                path: p.clone(),
+                reason,
+            };
+            if !p.starts_with('/') {
+                errors.push(malformed("path must start with `/`".to_string()));
+                continue;
+            }
+            let (pattern, constraints) = match parse_constraints(p) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    errors.push(malformed(e));
+                    continue;
+                }
             };
-            let _ = router_map.get_mut(m).unwrap().insert(p, *f);
-        });
-        return HttpRouter { router_map };
+            if pattern.contains(char::is_whitespace) {
+                errors.push(malformed("path cannot contain whitespace".to_string()));
+                continue;
+            }
+            let mut guards = self.guards.clone();
+            if let Some(inner) = self.route_guards.get(&(*m, p.clone())) {
+                guards.extend(inner.iter().cloned());
+            }
+            let route = HttpRoute {
+                handler: Self::wrap(&self.middlewares, Arc::clone(f)),
+                guards,
+                streaming: self.streaming.contains(&(*m, p.clone())),
+                constraints,
+            };
+            let id = router_map.entry(*m).or_default().insert(&pattern, route);
+            if let Some(by) = inserted.insert((*m, id), p.clone()) {
+                errors.push(HttpRouteError::Shadowed {
+                    method: *m,
+                    path: by,
+                    by: p.clone(),
+                });
+            }
+        }
+        if !errors.is_empty() {
+            return Err(HttpRouterError { errors });
+        }
+        Ok(HttpRouter {
+            router_map,
+            routes: routes.into_iter().map(|(m, p, _)| (m, p)).collect(),
+            docs: Arc::new(docs),
+        })
     }
 }
 
@@ -107,6 +632,7 @@ mod tests {
     ) -> Result<HttpResponse, HttpError> {
         Ok(HttpResponse {
             body: None,
+            stream: None,
             metadata: HttpResponseMetaData {
                 status: HttpStatus::Ok,
                 protocol: HttpProtocol::Http1_1,
@@ -119,6 +645,7 @@ mod tests {
         HttpRequest {
             metadata: HttpRequestMetaData::parse("GET / HTTP/1.1").unwrap(),
             body: None,
+            stream: None,
         }
     }
 
@@ -132,11 +659,17 @@ mod tests {
             .unwrap();
     }
     #[test]
-    #[should_panic]
     fn test_http_router_builder_add_router_twice_fail() {
         let mut builder = HttpRouterBuilder::new();
         builder.add_route(HttpMethod::GET, "/", emit_error);
         builder.add_route(HttpMethod::GET, "/", emit_error);
+        assert_eq!(
+            builder.build().err().unwrap().errors,
+            [HttpRouteError::Duplicate {
+                method: HttpMethod::GET,
+                path: "/".to_string()
+            }]
+        );
     }
     #[test]
     fn test_http_router_builder_add_router_twice_with_different_methods_pass() {
@@ -157,14 +690,15 @@ mod tests {
         builder.add_route(HttpMethod::PUT, "/", emit_error);
         builder.add_route(HttpMethod::PATCH, "/", emit_error);
 
-        let _ = builder.build();
+        assert!(builder.build().is_ok());
     }
 
     #[test]
     fn test_http_router_parse_request_route_pass() {
         let router = HttpRouterBuilder::new()
             .add_route(HttpMethod::GET, "/", emit_success_response)
-            .build();
+            .build()
+            .unwrap();
         let r = test_request();
         let (handler, params) = router.parse_request_route(&r).unwrap();
         assert_eq!(params.capacity(), 0);
@@ -174,4 +708,473 @@ mod tests {
             HttpStatus::Ok
         );
     }
+
+    #[test]
+    fn test_http_router_find_route_returns_pattern() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/pics/:pic", emit_success_response)
+            .build()
+            .unwrap();
+        let r = HttpRequest {
+            metadata: HttpRequestMetaData::parse("GET /pics/cat.png HTTP/1.1").unwrap(),
+            body: None,
+            stream: None,
+        };
+        let m = router.find_route(&r).unwrap();
+        assert_eq!(m.pattern, "/pics/:pic");
+        assert_eq!(m.params["pic"], "cat.png");
+        assert!(router.find_route(&test_request()).is_none());
+    }
+
+    #[test]
+    fn test_http_router_add_prefix_route() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/*", emit_error)
+            .add_prefix_route("/api/", emit_success_response)
+            .build()
+            .unwrap();
+        for (method, uri) in [
+            (HttpMethod::GET, "/api"),
+            (HttpMethod::POST, "/api/"),
+            (HttpMethod::DELETE, "/api/users/1"),
+        ] {
+            let r = HttpRequest {
+                metadata: HttpRequestMetaData::parse(&format!("{method} {uri} HTTP/1.1")).unwrap(),
+                body: None,
+                stream: None,
+            };
+            let m = router.find_route(&r).unwrap();
+            assert!((m.handler)(&r, &m.params).is_ok());
+        }
+        let r = HttpRequest {
+            metadata: HttpRequestMetaData::parse("GET /apis HTTP/1.1").unwrap(),
+            body: None,
+            stream: None,
+        };
+        assert_eq!(router.find_route(&r).unwrap().pattern, "/*");
+    }
+
+    fn request(method: HttpMethod, uri: &str) -> HttpRequest {
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&format!("{method} {uri} HTTP/1.1")).unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    fn echo_params(r: &HttpRequest, c: &HttpServerContext) -> Result<HttpResponse, HttpError> {
+        let mut params = c
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>();
+        params.sort();
+        Ok(HttpResponse::new(
+            r.metadata.protocol,
+            HttpStatus::Ok,
+            HttpHeaders::new(),
+            Some(params.join(",")),
+        ))
+    }
+
+    /// Appends `tag` to the `X-Trace` header of the response of the next handler.
+    fn trace(
+        tag: &'static str,
+    ) -> impl Fn(&HttpRequest, &HttpServerContext, &HttpRouterFunc) -> Result<HttpResponse, HttpError>
+    {
+        move |r, c, next| {
+            let mut response = next(r, c)?;
+            let trace = response
+                .metadata
+                .headers
+                .remove("X-Trace")
+                .map_or(tag.to_string(), |t| format!("{tag},{t}"));
+            response
+                .metadata
+                .headers
+                .insert("X-Trace".to_string(), trace);
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("/api/v1", "/users"), "/api/v1/users");
+        assert_eq!(join_path("/api/v1/", "/"), "/api/v1");
+        assert_eq!(join_path("api", "users/:id"), "/api/users/:id");
+        assert_eq!(join_path("", "/"), "/");
+        assert_eq!(join_path("/", "/*"), "/*");
+    }
+
+    #[test]
+    fn test_http_router_mount_shares_prefix_params() {
+        let mut posts = HttpRouterBuilder::new();
+        posts
+            .add_route(HttpMethod::GET, "/", echo_params)
+            .add_route(HttpMethod::GET, "/:post_id", echo_params);
+        let mut users = HttpRouterBuilder::new();
+        users
+            .add_route(HttpMethod::GET, "/:user_id", echo_params)
+            .mount("/:user_id/posts", posts);
+        let router = HttpRouterBuilder::new()
+            .mount("/api/v1/users", users)
+            .build()
+            .unwrap();
+
+        for (uri, body) in [
+            ("/api/v1/users/7", "user_id=7"),
+            ("/api/v1/users/7/posts", "user_id=7"),
+            ("/api/v1/users/7/posts/42", "post_id=42,user_id=7"),
+        ] {
+            let r = request(HttpMethod::GET, uri);
+            let m = router.find_route(&r).unwrap();
+            assert_eq!((m.handler)(&r, &m.params).unwrap().body.unwrap(), body);
+        }
+        let r = request(HttpMethod::GET, "/api/v1/users/7/posts/42");
+        assert_eq!(
+            router.find_route(&r).unwrap().pattern,
+            "/api/v1/users/:user_id/posts/:post_id"
+        );
+    }
+
+    #[test]
+    fn test_http_router_group_middleware_scope() {
+        let router = HttpRouterBuilder::new()
+            .add_middleware(trace("outer"))
+            .add_route(HttpMethod::GET, "/health", emit_success_response)
+            .group("/admin", |admin| {
+                admin
+                    .add_route(HttpMethod::GET, "/stats", emit_success_response)
+                    .add_middleware(trace("first"))
+                    .add_middleware(trace("second"));
+            })
+            .build()
+            .unwrap();
+        let trace_of = |uri: &str| {
+            let r = request(HttpMethod::GET, uri);
+            let m = router.find_route(&r).unwrap();
+            (m.handler)(&r, &m.params).unwrap().metadata.headers["X-Trace"].clone()
+        };
+        assert_eq!(trace_of("/admin/stats"), "outer,first,second");
+        assert_eq!(trace_of("/health"), "outer");
+    }
+
+    #[test]
+    fn test_http_router_group_middleware_short_circuits() {
+        let router = HttpRouterBuilder::new()
+            .group("/private", |private| {
+                private
+                    .add_middleware(|r, c, next| match r.metadata.header("Authorization") {
+                        Some(_) => next(r, c),
+                        None => Err(HttpError::new(HttpStatus::Unauthorized, "")),
+                    })
+                    .add_route(HttpMethod::GET, "/", emit_success_response);
+            })
+            .add_route(HttpMethod::GET, "/public", emit_success_response)
+            .build()
+            .unwrap();
+        assert_eq!(
+            router
+                .route(&request(HttpMethod::GET, "/private"))
+                .err()
+                .unwrap()
+                .status,
+            HttpStatus::Unauthorized
+        );
+        assert!(router.route(&request(HttpMethod::GET, "/public")).is_ok());
+    }
+
+    #[test]
+    fn test_http_router_guards() {
+        let authorized =
+            |r: &HttpRequest, _: &HttpServerContext| match r.metadata.header("Authorization") {
+                Some(_) => Ok(()),
+                None => Err(HttpError::new(HttpStatus::Unauthorized, "")),
+            };
+        let admin_only =
+            |r: &HttpRequest, _: &HttpServerContext| match r.metadata.header("Authorization") {
+                Some("admin") => Ok(()),
+                _ => Err(HttpError::new(HttpStatus::Forbidden, "")),
+            };
+        let router = HttpRouterBuilder::new()
+            .add_guard(authorized)
+            .add_route(HttpMethod::GET, "/me", emit_success_response)
+            .group("/admin", |admin| {
+                admin.add_guard(admin_only).add_route(
+                    HttpMethod::GET,
+                    "/stats",
+                    emit_success_response,
+                );
+            })
+            .build()
+            .unwrap();
+        let with_auth = |uri: &str, auth: &str| {
+            let mut r = request(HttpMethod::GET, uri);
+            r.metadata
+                .headers
+                .insert("Authorization".to_string(), auth.to_string());
+            r
+        };
+        let status = |r: &HttpRequest| router.route(r).err().map(|e| e.status);
+        assert_eq!(
+            router
+                .find_route(&with_auth("/me", "x"))
+                .unwrap()
+                .guards
+                .len(),
+            1
+        );
+        assert_eq!(
+            status(&request(HttpMethod::GET, "/me")),
+            Some(HttpStatus::Unauthorized)
+        );
+        assert_eq!(status(&with_auth("/me", "bob")), None);
+
+        let r = with_auth("/admin/stats", "bob");
+        let m = router.find_route(&r).unwrap();
+        assert_eq!(m.guards.len(), 2);
+        assert_eq!(
+            m.check_guards(&r).err().unwrap().status,
+            HttpStatus::Forbidden
+        );
+        let r = with_auth("/admin/stats", "admin");
+        let m = router.find_route(&r).unwrap();
+        let guarded = m.check_guards(&r).unwrap();
+        assert!(guarded.contains_key(GUARDED_KEY));
+        // Once guarded, the guard middlewares let the request through as is.
+        let mut r = request(HttpMethod::GET, "/admin/stats");
+        r.metadata
+            .headers
+            .insert("Authorization".to_string(), "bob".to_string());
+        assert!((m.handler)(&r, &guarded).is_ok());
+        assert_eq!(status(&r), Some(HttpStatus::Forbidden));
+        assert_eq!(status(&with_auth("/admin/stats", "admin")), None);
+    }
+
+    #[test]
+    fn test_http_router_streaming_routes() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::POST, "/form", emit_success_response)
+            .group("/files", |files| {
+                files.add_streaming_route(HttpMethod::PUT, "/:name", emit_success_response);
+            })
+            .build()
+            .unwrap();
+        let streaming = |method, uri| router.find_route(&request(method, uri)).unwrap().streaming;
+        assert!(streaming(HttpMethod::PUT, "/files/a.iso"));
+        assert!(!streaming(HttpMethod::POST, "/form"));
+    }
+
+    #[test]
+    fn test_http_router_mount_duplicate_fail() {
+        let mut group = HttpRouterBuilder::new();
+        group.add_route(HttpMethod::GET, "/", emit_error);
+        let err = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/api", emit_error)
+            .mount("/api", group)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "invalid routes: duplicate route GET /api");
+    }
+
+    #[test]
+    fn test_parse_constraints() {
+        let (pattern, constraints) =
+            parse_constraints(r"/users/:id(\d+)/files/:name([a-z]+\.(png|jpg))").unwrap();
+        assert_eq!(pattern, "/users/:id/files/:name");
+        assert_eq!(constraints.len(), 2);
+        assert_eq!(constraints[0].0, "id");
+        assert!(constraints[0].1.is_match("42"));
+        assert!(!constraints[0].1.is_match("42a"));
+        assert!(constraints[1].1.is_match("cat.png"));
+        assert!(!constraints[1].1.is_match("cat.gif"));
+        let (pattern, constraints) = parse_constraints(r"/a/:x(\))/:y").unwrap();
+        assert_eq!(pattern, "/a/:x/:y");
+        assert!(constraints[0].1.is_match(")"));
+        assert_eq!(parse_constraints("/pics/:pic").unwrap().0, "/pics/:pic");
+        assert!(parse_constraints(r"/a/:id(\d+").is_err());
+        assert!(parse_constraints("/a/:id([)").is_err());
+        assert!(parse_constraints("/a/:(x)").is_err());
+    }
+
+    #[test]
+    fn test_http_router_constraints() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, r"/users/:id(\d+)", echo_params)
+            .build()
+            .unwrap();
+        let r = request(HttpMethod::GET, "/users/42");
+        let m = router.find_route(&r).unwrap();
+        assert_eq!(m.pattern, "/users/:id");
+        assert_eq!(m.params["id"], "42");
+        let r = request(HttpMethod::GET, "/users/42?fields=name#top");
+        assert_eq!(router.find_route(&r).unwrap().params["id"], "42");
+        assert!(router
+            .find_route(&request(HttpMethod::GET, "/users/bob"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_http_router_malformed_routes_fail() {
+        let err = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/users/:id([0-9)", emit_error)
+            .add_route(HttpMethod::GET, "users", emit_error)
+            .add_route(HttpMethod::POST, "/a b", emit_error)
+            .add_route(HttpMethod::PUT, "/ok", emit_error)
+            .build()
+            .err()
+            .unwrap();
+        let malformed = err
+            .errors
+            .iter()
+            .map(|e| match e {
+                HttpRouteError::Malformed { method, path, .. } => format!("{method} {path}"),
+                e => panic!("unexpected error {e}"),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            malformed,
+            ["GET /users/:id([0-9)", "GET users", "POST /a b"]
+        );
+        assert!(err.errors[0]
+            .to_string()
+            .contains("invalid constraint on `:id`"));
+    }
+
+    #[test]
+    fn test_http_router_shadowed_routes_fail() {
+        let err = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/users/:id", emit_error)
+            .add_route(HttpMethod::GET, "/users/:name", emit_error)
+            .add_route(HttpMethod::GET, r"/files/:id(\d+)", emit_error)
+            .add_route(HttpMethod::GET, "/files/:id", emit_error)
+            .add_route(HttpMethod::POST, "/users/:name", emit_error)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.errors,
+            [
+                HttpRouteError::Shadowed {
+                    method: HttpMethod::GET,
+                    path: "/files/:id".to_string(),
+                    by: r"/files/:id(\d+)".to_string(),
+                },
+                HttpRouteError::Shadowed {
+                    method: HttpMethod::GET,
+                    path: "/users/:id".to_string(),
+                    by: "/users/:name".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_http_router_overlapping_routes_pass() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/*", emit_error)
+            .add_route(HttpMethod::GET, "/pics/:pic", emit_success_response)
+            .add_route(HttpMethod::GET, "/pics/index", emit_error)
+            .build()
+            .unwrap();
+        let r = request(HttpMethod::GET, "/pics/cat.png");
+        assert_eq!(router.find_route(&r).unwrap().pattern, "/pics/:pic");
+    }
+
+    #[test]
+    fn test_http_router_route_table() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/pics/:pic", emit_error)
+            .add_route(HttpMethod::POST, "/", emit_error)
+            .add_prefix_route("/api", emit_error)
+            .build()
+            .unwrap();
+        assert_eq!(router.routes().len(), 14);
+        assert_eq!(router.routes()[0], (HttpMethod::DELETE, "/api".to_string()));
+        assert_eq!(
+            router.route_table(),
+            "POST                              /\n\
+            DELETE,GET,OPTIONS,PATCH,POST,PUT /api\n\
+            DELETE,GET,OPTIONS,PATCH,POST,PUT /api/*\n\
+            GET                               /pics/:pic\n"
+        );
+    }
+
+    #[test]
+    fn test_http_router_serve_openapi() {
+        let mut users = HttpRouterBuilder::new();
+        users.add_documented_route(
+            HttpMethod::GET,
+            r"/:id(\d+)",
+            HttpRouteDoc::new().summary("Fetch a user"),
+            echo_params,
+        );
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::POST, "/", emit_error)
+            .mount("/users", users)
+            .serve_openapi("/openapi.json", OpenApiInfo::new("test", "1.0"))
+            .build()
+            .unwrap();
+        let response = router
+            .route(&request(HttpMethod::GET, "/openapi.json"))
+            .unwrap();
+        assert_eq!(
+            response.metadata.headers["Content-Type"],
+            "application/json"
+        );
+        let document: serde_json::Value = serde_json::from_str(&response.body.unwrap()).unwrap();
+        assert_eq!(document, router.openapi(&OpenApiInfo::new("test", "1.0")));
+        assert_eq!(
+            document["paths"]["/users/{id}"]["get"]["summary"],
+            "Fetch a user"
+        );
+        assert!(document["paths"]["/"]["post"].is_object());
+        assert!(document["paths"]["/openapi.json"]["get"].is_object());
+    }
+
+    #[test]
+    fn test_http_router_serve_openapi_duplicate_fail() {
+        let err = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/openapi.json", emit_error)
+            .serve_openapi("/openapi.json", OpenApiInfo::new("test", "1.0"))
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.errors,
+            [HttpRouteError::Duplicate {
+                method: HttpMethod::GET,
+                path: "/openapi.json".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_path_params_parse() {
+        let mut params = HttpServerContext::new();
+        params.insert("id".to_string(), "42".to_string());
+        params.insert("name".to_string(), "cat".to_string());
+        params.insert(
+            "uuid".to_string(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8".to_string(),
+        );
+        assert_eq!(params.parse::<u64>("id").unwrap(), 42);
+        assert_eq!(params.parse::<String>("name").unwrap(), "cat");
+        assert_eq!(
+            params.parse::<uuid::Uuid>("uuid").unwrap().to_string(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+        assert_eq!(
+            params.parse::<u64>("name").err().unwrap().status,
+            HttpStatus::BadRequest
+        );
+        assert_eq!(
+            params.parse::<uuid::Uuid>("id").err().unwrap().status,
+            HttpStatus::BadRequest
+        );
+        assert_eq!(
+            params.parse::<u64>("missing").err().unwrap().status,
+            HttpStatus::InternalServerError
+        );
+    }
 }
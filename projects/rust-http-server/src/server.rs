@@ -1,145 +1,924 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
+    any::Any,
+    io::{self, BufReader, BufWriter, Write},
     net::{TcpListener, TcpStream},
-    sync::{Arc, RwLock},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, PoisonError, RwLock},
     thread,
+    time::{Duration, Instant},
 };
 
-use log::error;
+use log::{error, info};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 
 use crate::{
-    common::{HttpError, HttpStatus},
-    request::parse_http_request,
+    access_log::{AccessLog, AccessLogRecord},
+    auth::{authorization, constant_time_eq},
+    common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext, HttpStatus},
+    connection::{read_proxy_header, HttpConnectionInfo, HttpStream, ProxyHeader, TrustedProxies},
+    error_page::ErrorPages,
+    metrics::HttpMetrics,
+    reload::{HttpReloadHandle, HttpSettingsLoader},
+    request::{
+        parse_http_request_metadata, read_request_body, HttpBodyFraming, HttpBodyReader,
+        HttpRequest, HttpRequestMetaData, HttpRequestStream,
+    },
+    request_id::{set_request_id, RequestIdFormat, REQUEST_ID_HEADER, REQUEST_ID_KEY},
     response::HttpResponse,
-    router::HttpRouter,
+    router::{HttpRouteMatch, HttpRouter, HttpRouterFunc},
+    thread_pool::ThreadPool,
+    vhost::{normalize_host, HostPattern, VirtualHosts},
 };
 
+/// The message of a panic payload, when it is a string as with `panic!` and `unwrap`.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload")
+}
+
+/// The body of the request served on a connection, shared with the streaming handler reading
+/// it so the connection can be taken back afterwards.
+struct SharedBody(Arc<Mutex<Option<HttpBodyReader<BufReader<HttpStream>>>>>);
+
+impl io::Read for SharedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            Some(body) => body.read(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the request was already answered",
+            )),
+        }
+    }
+}
+
+/// The interim response telling a client waiting on `Expect: 100-continue` to send its body.
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// How long a connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The part of the server configuration that can be replaced while it is running.
+///
+/// Every connection works on the snapshot that was current when it was accepted, so a reload
+/// never affects the requests already in flight.
+#[derive(Clone)]
+pub struct HttpServerSettings {
+    pub router: HttpRouter,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub max_body_size: Option<usize>,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Routers of the virtual hosts, requests for other hosts are served by `router`.
+    pub virtual_hosts: VirtualHosts,
+    /// Peers whose forwarding headers are used to find the client address.
+    pub trusted_proxies: TrustedProxies,
+    /// Renders the errors of the handlers and of the server itself.
+    pub error_pages: ErrorPages,
+    /// Generates the ids of the requests arriving without an `X-Request-ID`.
+    pub request_ids: RequestIdFormat,
+}
+
+impl HttpServerSettings {
+    pub fn new(router: HttpRouter) -> Self {
+        HttpServerSettings {
+            router,
+            read_timeout: None,
+            write_timeout: None,
+            max_body_size: None,
+            access_log: None,
+            virtual_hosts: VirtualHosts::new(),
+            trusted_proxies: TrustedProxies::new(),
+            error_pages: ErrorPages::new(),
+            request_ids: RequestIdFormat::default(),
+        }
+    }
+}
+
 pub struct HttpServer {
-    router: HttpRouter,
+    settings: Arc<RwLock<Arc<HttpServerSettings>>>,
+    workers: usize,
+    metrics: Option<HttpServerMetrics>,
+    loader: Option<Arc<HttpSettingsLoader>>,
+    admin_reload: Option<HttpAdminReload>,
+}
+
+/// A listening socket, serving TLS when it carries a TLS configuration.
+pub struct HttpListener {
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    proxy_protocol: bool,
+}
+
+impl HttpListener {
+    pub fn plain(listener: TcpListener) -> Self {
+        HttpListener {
+            listener,
+            tls: None,
+            proxy_protocol: false,
+        }
+    }
+
+    pub fn tls(listener: TcpListener, config: Arc<ServerConfig>) -> Self {
+        HttpListener {
+            listener,
+            tls: Some(config),
+            proxy_protocol: false,
+        }
+    }
+
+    /// Expects every connection to open with a PROXY protocol v1 or v2 header, whose source
+    /// address replaces the peer address. Connections without one are dropped.
+    pub fn with_proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+}
+
+/// The metrics registry of a server and the route it is exposed at.
+#[derive(Clone)]
+struct HttpServerMetrics {
+    route: String,
+    registry: Arc<HttpMetrics>,
+}
+
+/// The admin reload route of a server and the bearer token its callers must present.
+#[derive(Clone)]
+struct HttpAdminReload {
+    route: String,
+    token: String,
+}
+
+/// State shared between the connection handling threads of a running server.
+pub(crate) struct HttpServerState {
+    settings: Arc<RwLock<Arc<HttpServerSettings>>>,
+    metrics: Option<HttpServerMetrics>,
+    reload: Option<(HttpAdminReload, HttpReloadHandle)>,
+}
+
+impl HttpServerState {
+    #[cfg(test)]
+    fn new(router: HttpRouter) -> Self {
+        Self::with_settings(HttpServerSettings::new(router))
+    }
+    #[cfg(test)]
+    fn with_settings(settings: HttpServerSettings) -> Self {
+        HttpServerState {
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            metrics: None,
+            reload: None,
+        }
+    }
+    /// Returns the settings new connections are served with.
+    fn settings(&self) -> Arc<HttpServerSettings> {
+        Arc::clone(&self.settings.read().unwrap())
+    }
+    /// Serves `request`, whose body is already read, as if it was received on `conn`, going
+    /// through the same routing, middlewares and error pages as requests read off a socket.
+    #[cfg(test)]
+    pub(crate) fn serve(
+        &self,
+        mut request: HttpRequest,
+        conn: &HttpConnectionInfo,
+    ) -> HttpResponse {
+        let settings = self.settings();
+        request.metadata.normalize_target();
+        let id = settings.request_ids.resolve(Some(&request.metadata));
+        set_request_id(&mut request.metadata, &id);
+        let (mut response, _) = HttpServer::dispatch(self, &settings, &request, conn, None);
+        set_response_request_id(&mut response, &id);
+        response
+    }
+}
+
+/// Echoes the id of the request `response` answers.
+fn set_response_request_id(response: &mut HttpResponse, id: &str) {
+    let headers = &mut response.metadata.headers;
+    headers.retain(|k, _| !k.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+    headers.insert(REQUEST_ID_HEADER.to_string(), id.to_string());
 }
 
 impl HttpServer {
     pub fn new(router: HttpRouter) -> HttpServer {
-        HttpServer { router: router }
+        Self::from_settings(HttpServerSettings::new(router))
+    }
+    pub fn from_settings(settings: HttpServerSettings) -> HttpServer {
+        HttpServer {
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            metrics: None,
+            loader: None,
+            admin_reload: None,
+        }
+    }
+    fn update_settings<F: FnOnce(&mut HttpServerSettings)>(&mut self, update: F) {
+        update(Arc::make_mut(&mut self.settings.write().unwrap()));
+    }
+    /// Sets the number of worker threads handling connections, at least one is started.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+    /// Sets the socket timeouts of client connections, `None` waits forever.
+    pub fn with_timeouts(mut self, read: Option<Duration>, write: Option<Duration>) -> Self {
+        self.update_settings(|s| {
+            s.read_timeout = read;
+            s.write_timeout = write;
+        });
+        self
+    }
+    /// Rejects requests whose `Content-Length` exceeds `max_body_size` bytes with
+    /// `413 PayloadTooLarge` without reading their body.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.update_settings(|s| s.max_body_size = Some(max_body_size));
+        self
+    }
+    /// Records every response written by the server into `access_log`.
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.update_settings(|s| s.access_log = Some(Arc::new(access_log)));
+        self
+    }
+    /// Serves the requests whose `Host` matches `pattern` with `router` instead of the default
+    /// router.
+    pub fn with_virtual_host(mut self, pattern: HostPattern, router: HttpRouter) -> Self {
+        self.update_settings(|s| {
+            s.virtual_hosts.add(pattern, router);
+        });
+        self
+    }
+    /// Answers requests for hosts without a virtual host with `421 MisdirectedRequest`
+    /// instead of serving them with the default router.
+    pub fn with_strict_hosts(mut self, strict: bool) -> Self {
+        self.update_settings(|s| {
+            s.virtual_hosts.strict(strict);
+        });
+        self
+    }
+    /// Collects request metrics and serves them in the Prometheus text format on `GET route`.
+    pub fn with_metrics(mut self, route: &str) -> Self {
+        self.metrics = Some(HttpServerMetrics {
+            route: route.to_string(),
+            registry: Arc::new(HttpMetrics::new()),
+        });
+        self
+    }
+    pub fn metrics(&self) -> Option<Arc<HttpMetrics>> {
+        self.metrics.as_ref().map(|m| Arc::clone(&m.registry))
+    }
+    /// Uses `loader` to rebuild the settings of the server whenever a reload is requested.
+    pub fn with_reloader<F>(mut self, loader: F) -> Self
+    where
+        F: Fn() -> Result<HttpServerSettings, String> + Send + Sync + 'static,
+    {
+        self.loader = Some(Arc::new(loader));
+        self
+    }
+    /// Renders error responses with `error_pages` instead of the default ones.
+    pub fn with_error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.update_settings(|s| s.error_pages = error_pages);
+        self
+    }
+    /// Generates the ids of requests arriving without an `X-Request-ID` as `format`.
+    pub fn with_request_id_format(mut self, format: RequestIdFormat) -> Self {
+        self.update_settings(|s| s.request_ids = format);
+        self
+    }
+    /// Recovers the client address from the `Forwarded` and `X-Forwarded-For` headers of
+    /// requests sent by `proxies`.
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.update_settings(|s| s.trusted_proxies = proxies);
+        self
     }
-    fn write_response_to_stream(stream: &TcpStream, response: HttpResponse) {
+    /// Reloads the settings on `POST route` sent from a loopback address with an
+    /// `Authorization: Bearer <token>` header, requires a reloader. The token is what keeps
+    /// remote clients out behind a reverse proxy on the same host, whose requests all come
+    /// from a loopback address.
+    pub fn with_admin_reload(mut self, route: &str, token: &str) -> Self {
+        self.admin_reload = Some(HttpAdminReload {
+            route: route.to_string(),
+            token: token.to_string(),
+        });
+        self
+    }
+    /// Returns a handle triggering reloads of the running server, if it has a reloader.
+    pub fn reload_handle(&self) -> Option<HttpReloadHandle> {
+        self.loader
+            .as_ref()
+            .map(|loader| HttpReloadHandle::new(Arc::clone(&self.settings), Arc::clone(loader)))
+    }
+    /// Writes `response` to `stream` and returns the number of body bytes written.
+    fn write_response_to_stream<W: Write>(stream: W, mut response: HttpResponse) -> usize {
         let mut writer = BufWriter::new(stream);
+        let body_stream = response.stream.take();
+        let mut written = match response.body.as_ref() {
+            Some(b) => b.len(),
+            None => 0,
+        };
         let bytes = format!("{}", response).bytes().collect::<Vec<u8>>();
-        let _ = writer.write(&bytes);
+        if let Err(e) = writer.write_all(&bytes) {
+            error!("HttpServer: cannot write the response -> error: {e}");
+            return 0;
+        }
+        if let Some(mut body_stream) = body_stream {
+            match io::copy(&mut body_stream, &mut writer) {
+                Ok(n) => written += n as usize,
+                Err(e) => error!("HttpServer: cannot stream the response body -> error: {e}"),
+            }
+        }
+        let _ = writer.flush();
+        written
+    }
+    fn metrics_response(request: &HttpRequest, metrics: &HttpMetrics) -> HttpResponse {
+        let mut headers = HttpHeaders::new();
+        headers.insert(
+            "Content-Type".to_string(),
+            "text/plain; version=0.0.4".to_string(),
+        );
+        HttpResponse::new(
+            request.metadata.protocol,
+            HttpStatus::Ok,
+            headers,
+            Some(metrics.render()),
+        )
+    }
+    fn reload_response(
+        request: &HttpRequest,
+        conn: &HttpConnectionInfo,
+        admin: &HttpAdminReload,
+        handle: &HttpReloadHandle,
+    ) -> Result<HttpResponse, HttpError> {
+        if !conn.peer_addr.is_some_and(|p| p.ip().is_loopback()) {
+            return Err(HttpError::new(HttpStatus::Forbidden, ""));
+        }
+        let token = authorization(request, "Bearer").unwrap_or("");
+        if !constant_time_eq(token.as_bytes(), admin.token.as_bytes()) {
+            let mut e = HttpError::new(HttpStatus::Unauthorized, "");
+            e.headers
+                .insert("WWW-Authenticate".to_string(), "Bearer".to_string());
+            return Err(e);
+        }
+        let (status, body) = match handle.reload() {
+            Ok(()) => (HttpStatus::Ok, "reloaded\n".to_string()),
+            Err(e) => (HttpStatus::InternalServerError, format!("{e}\n")),
+        };
+        let mut headers = HttpHeaders::new();
+        headers.insert("Content-Type".to_string(), "text/plain".to_string());
+        Ok(HttpResponse::new(
+            request.metadata.protocol,
+            status,
+            headers,
+            Some(body),
+        ))
     }
-    fn handle_incoming_stream(router: Arc<RwLock<HttpRouter>>, s: &TcpStream) {
-        let request = match parse_http_request(&mut BufReader::new(s)) {
-            Ok(r) => r,
+    /// Calls `handler`, turning a panic into a `500 Internal Server Error` so that a faulty
+    /// handler only fails its own request.
+    fn call_handler(
+        handler: &HttpRouterFunc,
+        request: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> Result<HttpResponse, HttpError> {
+        panic::catch_unwind(AssertUnwindSafe(|| handler(request, params))).unwrap_or_else(|p| {
+            error!(
+                "HttpServer: handler panicked # request: {} {} {} # request_id: {} # panic: {}",
+                request.metadata.method,
+                request.metadata.uri,
+                request.metadata.protocol,
+                request.metadata.header(REQUEST_ID_HEADER).unwrap_or("-"),
+                panic_message(p.as_ref())
+            );
+            Err(HttpError::new(
+                HttpStatus::InternalServerError,
+                "handler panicked",
+            ))
+        })
+    }
+    /// Routes `request` and returns the response along with the matched route pattern. The
+    /// handler is called with `guarded`, the context returned by the guards of the route,
+    /// when they already passed.
+    fn dispatch(
+        state: &HttpServerState,
+        settings: &HttpServerSettings,
+        request: &HttpRequest,
+        conn: &HttpConnectionInfo,
+        guarded: Option<HttpServerContext>,
+    ) -> (HttpResponse, Option<String>) {
+        let path = request.metadata.uri.split(['?', '#']).next().unwrap_or("");
+        if let Some(metrics) = state.metrics.as_ref() {
+            if request.metadata.method == HttpMethod::GET && path == metrics.route {
+                return (
+                    Self::metrics_response(request, &metrics.registry),
+                    Some(metrics.route.clone()),
+                );
+            }
+        }
+        if let Some((admin, handle)) = state.reload.as_ref() {
+            if request.metadata.method == HttpMethod::POST && path == admin.route {
+                let response = Self::reload_response(request, conn, admin, handle)
+                    .unwrap_or_else(|e| settings.error_pages.render(e, Some(&request.metadata)));
+                return (response, Some(admin.route.clone()));
+            }
+        }
+        let router = match Self::select_router(settings, request) {
+            Ok(router) => router,
             Err(e) => {
-                error!("HttpServer: parse request error: {e}");
-                Self::write_response_to_stream(&s, HttpResponse::from_err(e, None));
-                return;
+                return (
+                    settings.error_pages.render(e, Some(&request.metadata)),
+                    None,
+                )
             }
         };
-        let router = router.read().unwrap();
-        let result = match router.parse_request_route(&request) {
-            Some((handler, params)) => (handler)(&request, &params),
-            None => {
+        let (result, pattern) = match router.find_route(request) {
+            Some(route) => {
+                let params = guarded.unwrap_or_else(|| Self::route_params(&route, request, conn));
+                (
+                    Self::call_handler(route.handler, request, &params),
+                    Some(route.pattern),
+                )
+            }
+            None => (Err(Self::route_not_found(request)), None),
+        };
+        let response =
+            result.unwrap_or_else(|e| settings.error_pages.render(e, Some(&request.metadata)));
+        (response, pattern)
+    }
+    /// The router serving `request`, picked by its `Host`.
+    fn select_router<'a>(
+        settings: &'a HttpServerSettings,
+        request: &HttpRequest,
+    ) -> Result<&'a HttpRouter, HttpError> {
+        let host = request.metadata.header("Host").and_then(normalize_host);
+        settings
+            .virtual_hosts
+            .select(host.as_deref(), &settings.router)
+            .ok_or_else(|| {
                 error!(
-                    "HttpServer: request routing error # method: {} # uri: {}",
-                    request.metadata.method, request.metadata.uri
+                    "HttpServer: no virtual host for {}",
+                    host.as_deref().unwrap_or("requests without Host")
                 );
-                Err(HttpError::new(HttpStatus::MethodNotAllowed, ""))
+                HttpError::new(HttpStatus::MisdirectedRequest, "")
+            })
+    }
+    fn route_not_found(request: &HttpRequest) -> HttpError {
+        error!(
+            "HttpServer: request routing error # method: {} # uri: {} # request_id: {}",
+            request.metadata.method,
+            request.metadata.uri,
+            request.metadata.header(REQUEST_ID_HEADER).unwrap_or("-")
+        );
+        HttpError::new(HttpStatus::MethodNotAllowed, "")
+    }
+    /// The context handed to the handler of `route`.
+    fn route_params(
+        route: &HttpRouteMatch,
+        request: &HttpRequest,
+        conn: &HttpConnectionInfo,
+    ) -> HttpServerContext {
+        let mut params = conn.with_context(&route.params);
+        if let Some(id) = request.metadata.header(REQUEST_ID_HEADER) {
+            params.insert(REQUEST_ID_KEY.to_string(), id.to_string());
+        }
+        params
+    }
+    /// Decides whether the client of an `Expect: 100-continue` request may send its body: the
+    /// route of `request`, which has no body yet, must be resolved and pass its guards. The
+    /// context the guards returned is handed to [`HttpServer::dispatch`], so that they do not
+    /// run twice.
+    fn check_continue(
+        state: &HttpServerState,
+        settings: &HttpServerSettings,
+        request: &HttpRequest,
+        conn: &HttpConnectionInfo,
+    ) -> Result<Option<HttpServerContext>, HttpError> {
+        let method = request.metadata.method;
+        let path = request.metadata.uri.split(['?', '#']).next().unwrap_or("");
+        let builtin = state
+            .metrics
+            .as_ref()
+            .is_some_and(|m| method == HttpMethod::GET && path == m.route)
+            || state
+                .reload
+                .as_ref()
+                .is_some_and(|(admin, _)| method == HttpMethod::POST && path == admin.route);
+        if builtin {
+            return Ok(None);
+        }
+        let router = Self::select_router(settings, request)?;
+        let mut route = router
+            .find_route(request)
+            .ok_or_else(|| Self::route_not_found(request))?;
+        route.params = Self::route_params(&route, request, conn);
+        route.check_guards(request).map(Some)
+    }
+    /// Reads the metadata of a request off `reader` along with the length of its body,
+    /// refusing unknown expectations and bodies larger than the configured limit. The
+    /// metadata is returned along with the error when it could be parsed.
+    fn read_request_metadata<R: io::Read>(
+        settings: &HttpServerSettings,
+        reader: &mut BufReader<R>,
+    ) -> Result<(HttpRequestMetaData, HttpBodyFraming), (Option<HttpRequestMetaData>, HttpError)>
+    {
+        let mut metadata = parse_http_request_metadata(reader).map_err(|e| (None, e))?;
+        metadata.normalize_target();
+        let framing = match metadata.body_framing() {
+            Ok(f) => f,
+            Err(e) => return Err((Some(metadata), e)),
+        };
+        if let Err(e) = metadata.expects_continue() {
+            return Err((Some(metadata), e));
+        }
+        if let (HttpBodyFraming::Length(length), Some(max)) = (framing, settings.max_body_size) {
+            if length > max {
+                return Err((
+                    Some(metadata),
+                    HttpError::new(HttpStatus::PayloadTooLarge, "request body too large"),
+                ));
+            }
+        }
+        Ok((metadata, framing))
+    }
+    /// Reads the body of `request` off `reader` and dispatches it. A client waiting for
+    /// `100 Continue` only gets it once the request passed [`HttpServer::check_continue`],
+    /// otherwise the error is answered without reading the body.
+    ///
+    /// Streaming routes get the body as a stream reading off `reader`, which is handed back
+    /// once they answered, unless it was lost.
+    fn serve_body(
+        state: &HttpServerState,
+        settings: &HttpServerSettings,
+        mut reader: BufReader<HttpStream>,
+        request: &mut HttpRequest,
+        framing: HttpBodyFraming,
+        conn: &HttpConnectionInfo,
+    ) -> (Option<BufReader<HttpStream>>, HttpResponse, Option<String>) {
+        let render = |e| settings.error_pages.render(e, Some(&request.metadata));
+        let mut guarded = None;
+        if framing != HttpBodyFraming::Length(0) && request.metadata.expects_continue() == Ok(true)
+        {
+            match Self::check_continue(state, settings, request, conn) {
+                Ok(params) => guarded = params,
+                Err(e) => return (Some(reader), render(e), None),
+            }
+            let stream = reader.get_mut();
+            if let Err(e) = stream.write_all(CONTINUE).and_then(|_| stream.flush()) {
+                error!("HttpServer: cannot send 100 Continue -> {e}");
             }
+        }
+        let streaming = settings
+            .virtual_hosts
+            .select(
+                request
+                    .metadata
+                    .header("Host")
+                    .and_then(normalize_host)
+                    .as_deref(),
+                &settings.router,
+            )
+            .and_then(|router| router.find_route(request))
+            .is_some_and(|route| route.streaming);
+        if !streaming {
+            let body = match framing {
+                HttpBodyFraming::Length(length) => {
+                    HttpRequest::parse_request_body(&mut reader, length)
+                }
+                HttpBodyFraming::Chunked => read_request_body(HttpBodyReader::new(
+                    &mut reader,
+                    framing,
+                    settings.max_body_size,
+                )),
+            };
+            let (response, pattern) = match body {
+                Ok(body) => {
+                    request.body = body;
+                    Self::dispatch(state, settings, request, conn, guarded)
+                }
+                Err(e) => (render(e), None),
+            };
+            return (Some(reader), response, pattern);
+        }
+        let body = Arc::new(Mutex::new(Some(HttpBodyReader::new(
+            reader,
+            framing,
+            settings.max_body_size,
+        ))));
+        request.stream = Some(HttpRequestStream::new(SharedBody(Arc::clone(&body))));
+        let (response, pattern) = Self::dispatch(state, settings, request, conn, guarded);
+        request.stream = None;
+        let reader = body
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .map(HttpBodyReader::into_inner);
+        (reader, response, pattern)
+    }
+    /// Describes the connection `request` was received on.
+    fn connection_info(
+        settings: &HttpServerSettings,
+        stream: &HttpStream,
+        proxied: Option<&ProxyHeader>,
+        request: &HttpRequest,
+    ) -> HttpConnectionInfo {
+        let peer = match proxied {
+            Some(header) => header.source,
+            None => stream.peer_addr().ok(),
         };
-        Self::write_response_to_stream(
-            &s,
-            result.unwrap_or_else(|e| HttpResponse::from_err(e, Some(request.metadata.protocol))),
-        );
+        let (tls_version, server_name) = stream.tls_info().unzip();
+        HttpConnectionInfo {
+            peer_addr: peer,
+            local_addr: stream.local_addr().ok(),
+            client_ip: peer.map(|p| {
+                settings
+                    .trusted_proxies
+                    .client_ip(p.ip(), &request.metadata)
+            }),
+            tls_version,
+            server_name: server_name.flatten(),
+            // Connections are closed after their first response.
+            requests: 1,
+        }
     }
-    pub fn serve(self: &Self, tcp_listener: &TcpListener) {
-        let router = Arc::new(RwLock::new(self.router.clone()));
-        let _ = for stream in tcp_listener.incoming() {
-            let _ = match stream {
+    /// Reads the PROXY protocol header of `stream` when `listener` expects one. The header
+    /// is read off the TCP socket, before any TLS record.
+    fn read_proxy_header(
+        proxy_protocol: bool,
+        stream: &HttpStream,
+    ) -> io::Result<Option<ProxyHeader>> {
+        if !proxy_protocol {
+            return Ok(None);
+        }
+        let mut tcp = stream.tcp();
+        let _ = tcp.set_read_timeout(Some(PROXY_HEADER_TIMEOUT));
+        read_proxy_header(&mut tcp).map(Some)
+    }
+    fn handle_incoming_stream(
+        state: Arc<HttpServerState>,
+        stream: HttpStream,
+        proxied: Option<ProxyHeader>,
+    ) {
+        let started = Instant::now();
+        let settings = state.settings();
+        let peer = match proxied.as_ref() {
+            Some(header) => header.source,
+            None => stream.peer_addr().ok(),
+        };
+        let _ = stream.tcp().set_read_timeout(settings.read_timeout);
+        let _ = stream.tcp().set_write_timeout(settings.write_timeout);
+        let mut reader = BufReader::new(stream);
+        let (reader, metadata, request_id, mut response, pattern) =
+            match Self::read_request_metadata(&settings, &mut reader) {
+                Ok((mut metadata, framing)) => {
+                    let id = settings.request_ids.resolve(Some(&metadata));
+                    set_request_id(&mut metadata, &id);
+                    let mut request = HttpRequest {
+                        metadata,
+                        body: None,
+                        stream: None,
+                    };
+                    let conn = Self::connection_info(
+                        &settings,
+                        reader.get_ref(),
+                        proxied.as_ref(),
+                        &request,
+                    );
+                    let (reader, response, pattern) =
+                        Self::serve_body(&state, &settings, reader, &mut request, framing, &conn);
+                    (reader, Some(request.metadata), id, response, pattern)
+                }
+                Err((mut metadata, e)) => {
+                    let id = settings.request_ids.resolve(metadata.as_ref());
+                    if let Some(m) = metadata.as_mut() {
+                        set_request_id(m, &id);
+                    }
+                    error!("HttpServer: parse request error # request_id: {id} # {e}");
+                    let response = settings.error_pages.render(e, metadata.as_ref());
+                    (Some(reader), metadata, id, response, None)
+                }
+            };
+        set_response_request_id(&mut response, &request_id);
+        // Every connection carries a single request, clients must not keep it for another one.
+        let headers = &mut response.metadata.headers;
+        headers.retain(|k, _| !k.eq_ignore_ascii_case("Connection"));
+        headers.insert("Connection".to_string(), "close".to_string());
+        let status = response.metadata.status;
+        let size = match reader {
+            Some(mut reader) => {
+                let size = Self::write_response_to_stream(reader.get_mut(), response);
+                if let HttpStream::Tls(s) = reader.get_mut() {
+                    s.conn.send_close_notify();
+                    let _ = s.flush();
+                }
+                size
+            }
+            None => {
+                error!("HttpServer: connection lost while streaming # request_id: {request_id}");
+                0
+            }
+        };
+        if let Some(metrics) = state.metrics.as_ref() {
+            metrics.registry.observe_request(
+                pattern.as_deref(),
+                metadata.as_ref().map(|m| m.method),
+                status,
+                started.elapsed(),
+            );
+        }
+        if let Some(access_log) = settings.access_log.as_ref() {
+            let mut record =
+                AccessLogRecord::new(peer, metadata.as_ref(), status, size, started.elapsed());
+            record.request_id = Some(request_id);
+            access_log.log(&record);
+        }
+    }
+    fn accept_stream(s: TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<HttpStream> {
+        match tls {
+            Some(config) => {
+                let connection =
+                    ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+                Ok(HttpStream::Tls(Box::new(StreamOwned::new(connection, s))))
+            }
+            None => Ok(HttpStream::Plain(s)),
+        }
+    }
+    fn accept_loop(state: &Arc<HttpServerState>, pool: &ThreadPool, listener: &HttpListener) {
+        for stream in listener.listener.incoming() {
+            match stream.and_then(|s| Self::accept_stream(s, listener.tls.as_ref())) {
                 Ok(s) => {
-                    let router = Arc::clone(&router);
-                    let _ = thread::spawn(move || {
-                        Self::handle_incoming_stream(router, &s);
+                    let state = Arc::clone(state);
+                    if let Some(metrics) = state.metrics.as_ref() {
+                        metrics.registry.connection_opened();
+                    }
+                    let proxy_protocol = listener.proxy_protocol;
+                    pool.execute(move || {
+                        if let Some(metrics) = state.metrics.as_ref() {
+                            metrics.registry.worker_busy();
+                        }
+                        match Self::read_proxy_header(proxy_protocol, &s) {
+                            Ok(proxied) => {
+                                Self::handle_incoming_stream(Arc::clone(&state), s, proxied)
+                            }
+                            Err(e) => error!("HttpServer: dropping connection -> {e}"),
+                        }
+                        if let Some(metrics) = state.metrics.as_ref() {
+                            metrics.registry.worker_idle();
+                            metrics.registry.connection_closed();
+                        }
                     });
                 }
                 Err(e) => {
-                    println!("critical: cannot read the tcp stream -> error: {e}");
+                    error!("HttpServer: cannot read the tcp stream -> {e}");
                     return;
                 }
-            };
-        };
+            }
+        }
+    }
+    pub fn serve(self: &Self, tcp_listener: &TcpListener) {
+        match tcp_listener.try_clone() {
+            Ok(l) => self.serve_listeners(&[HttpListener::plain(l)]),
+            Err(e) => error!("HttpServer: cannot use the tcp listener -> {e}"),
+        }
+    }
+    /// The state the connections of the server are served with.
+    pub(crate) fn state(&self) -> HttpServerState {
+        HttpServerState {
+            settings: Arc::clone(&self.settings),
+            metrics: self.metrics.clone(),
+            reload: self.admin_reload.clone().zip(self.reload_handle()),
+        }
+    }
+    /// Serves every listener from a shared worker pool until all of them fail.
+    pub fn serve_listeners(&self, listeners: &[HttpListener]) {
+        let state = Arc::new(self.state());
+        let pool = ThreadPool::new(self.workers);
+        if let Some(metrics) = state.metrics.as_ref() {
+            metrics.registry.set_worker_pool_size(pool.size());
+        }
+        thread::scope(|scope| {
+            for listener in listeners {
+                if let Ok(addr) = listener.listener.local_addr() {
+                    let scheme = if listener.tls.is_some() {
+                        "https"
+                    } else {
+                        "http"
+                    };
+                    info!("HttpServer: listening on {scheme}://{addr}");
+                }
+                let (state, pool) = (&state, &pool);
+                scope.spawn(move || Self::accept_loop(state, pool, listener));
+            }
+        });
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Read;
+    use std::{
+        io::{BufRead, Read},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
 
     use crate::{
+        access_log::{AccessLogFormat, RotatingFileWriter},
         common::{HttpHeaders, HttpMethod, HttpProtocol, HttpServerContext},
-        request::HttpRequest,
+        connection::HttpConnectionContext,
+        request::{body_error, HttpRequest},
+        request_id::HttpRequestIdContext,
+        response::parse_http_response,
         router::HttpRouterBuilder,
+        testing::{TestClient, TestResponse, TestServer},
     };
 
     use super::*;
-    use serial_test::serial;
 
-    const BIND_ADDRESS: &'static str = "127.0.0.1:38080";
+    /// Serves the connection `raw` is written on with `state` and returns the response, once
+    /// the server is done with the connection.
+    fn serve_raw(state: Arc<HttpServerState>, raw: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let stream = listener.accept().unwrap().0;
+        HttpServer::handle_incoming_stream(state, HttpStream::Plain(stream), None);
+        client.join().unwrap()
+    }
 
-    fn bind_tcp_listener() -> Result<TcpListener, Error> {
-        TcpListener::bind(BIND_ADDRESS)
+    /// Writes `raw` on a new connection to `server` and returns its answer.
+    fn send_raw(server: &TestServer, raw: &str) -> String {
+        String::from_utf8(server.send_raw(raw.as_bytes())).unwrap()
     }
 
     #[test]
-    #[serial]
     fn test_tcp_listener() {
-        assert!(bind_tcp_listener().is_ok())
+        assert!(TcpListener::bind("127.0.0.1:0").is_ok())
     }
 
     #[test]
-    #[serial]
     fn test_http_server_new() {
-        HttpServer::new(HttpRouterBuilder::new().build());
+        HttpServer::new(HttpRouterBuilder::new().build().unwrap());
+    }
+
+    #[test]
+    fn test_http_server_serves_with_at_least_one_worker() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some("served".to_string()),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = HttpServer::new(router).with_workers(0);
+        assert_eq!(server.workers, 1);
+        TestServer::start(server)
+            .get("/")
+            .send()
+            .assert_body("served");
     }
 
     #[test]
-    #[serial]
     fn test_http_server_write_response() {
+        let mut headers = HttpHeaders::new();
+        headers.insert("Content-Length".to_string(), "5".to_string());
+        headers.insert("Content-Type".to_string(), "text/plain".to_string());
         let expected = HttpResponse::new(
             HttpProtocol::Http1,
             HttpStatus::Accepted,
-            HttpHeaders::new(),
-            None,
+            headers,
+            Some("hello".to_string()),
         );
-        let expected_len = format!("{}", expected).bytes().len();
-        let listener = bind_tcp_listener().unwrap();
-        thread::spawn(|| {
-            let stream = TcpStream::connect(BIND_ADDRESS).unwrap();
+        let (metadata, body) = (expected.metadata.clone(), expected.body.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
             HttpServer::write_response_to_stream(&stream, expected);
         });
         let (s, _) = listener.accept().unwrap();
-        let bytes: Vec<u8> = BufReader::new(s)
-            .bytes()
-            .map(|x| x.unwrap())
-            .collect::<Vec<u8>>();
-        assert_eq!(bytes.len(), expected_len);
+        let response = parse_http_response(&mut BufReader::new(s)).unwrap();
+        assert_eq!(response.metadata, metadata);
+        assert_eq!(response.body, body);
     }
 
     #[test]
-    #[serial]
     fn test_http_server_handle_stream() {
         let request = "POST / HTTP/1.1\r\n\
                 Header: Value\r\n\
                 \r\n\
                 Body";
-        let listener = bind_tcp_listener();
-        thread::spawn(|| {
-            let stream = TcpStream::connect(BIND_ADDRESS).unwrap();
-            BufWriter::new(stream)
-                .write(&request.bytes().collect::<Vec<u8>>())
-                .unwrap();
-        });
-        let stream = listener.unwrap().accept().unwrap().0;
         let router = HttpRouterBuilder::new()
             .add_route(
                 HttpMethod::POST,
@@ -148,7 +927,495 @@ mod tests {
                     Err(HttpError::new(HttpStatus::BadRequest, ""))
                 },
             )
-            .build();
-        HttpServer::handle_incoming_stream(Arc::new(RwLock::new(router)), &stream);
+            .build()
+            .unwrap();
+        let response = serve_raw(Arc::new(HttpServerState::new(router)), request);
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[test]
+    fn test_http_server_handle_stream_writes_access_log() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-http-server-access-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let request = "GET /missing HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                User-Agent: test-agent\r\n\
+                \r\n";
+        let server = HttpServer::new(HttpRouterBuilder::new().build().unwrap()).with_access_log(
+            AccessLog::new(
+                AccessLogFormat::Combined,
+                RotatingFileWriter::new(&path, 0, 0).unwrap(),
+            ),
+        );
+        let response = serve_raw(Arc::new(server.state()), request);
+        assert!(response.starts_with("HTTP/1.1 405"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.contains("\"title\":\"Method Not Allowed\""));
+        let line = std::fs::read_to_string(&path).unwrap();
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.contains(&format!(
+            "\"GET /missing HTTP/1.1\" 405 {} \"-\" \"test-agent\"",
+            body.len()
+        )));
+    }
+
+    #[test]
+    fn test_http_server_handle_stream_serves_metrics() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/pics/:pic", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    None,
+                ))
+            })
+            .build()
+            .unwrap();
+        // A single worker observes every request before serving the next one.
+        let server = TestServer::start(
+            HttpServer::new(router)
+                .with_workers(1)
+                .with_metrics("/metrics"),
+        );
+        for pic in ["/pics/a.png", "/pics/b.png"] {
+            server.get(pic).send().assert_status(HttpStatus::Ok);
+        }
+        server
+            .get("/metrics")
+            .send()
+            .assert_status(HttpStatus::Ok)
+            .assert_header("Content-Type", "text/plain; version=0.0.4")
+            .assert_body_contains(
+                "http_requests_total{route=\"/pics/:pic\",method=\"GET\",status=\"2xx\"} 2\n",
+            );
+        server
+            .get("/metrics?format=text")
+            .send()
+            .assert_body_contains(
+                "http_requests_total{route=\"/metrics\",method=\"GET\",status=\"2xx\"} 1\n",
+            );
+    }
+
+    #[test]
+    fn test_http_server_write_streamed_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writer = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            HttpServer::write_response_to_stream(
+                &stream,
+                HttpResponse::with_stream(
+                    HttpProtocol::Http1_1,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Box::new("streamed body".as_bytes()),
+                ),
+            )
+        });
+        let (mut s, _) = listener.accept().unwrap();
+        let mut response = String::new();
+        s.read_to_string(&mut response).unwrap();
+        assert_eq!(writer.join().unwrap(), "streamed body".len());
+        assert_eq!(response, "HTTP/1.1 200 OK\r\n\r\nstreamed body");
+    }
+
+    #[test]
+    fn test_http_server_handle_stream_refuses_large_body() {
+        let server = TestServer::start(
+            HttpServer::new(HttpRouterBuilder::new().build().unwrap()).with_max_body_size(4),
+        );
+        let response = send_raw(
+            &server,
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+    }
+
+    #[test]
+    fn test_http_server_handle_tls_stream() {
+        use crate::tls::{load_server_config, tests::self_signed_cert};
+        use rustls::{
+            crypto::ring,
+            pki_types::{pem::PemObject, CertificateDer},
+            ClientConfig, ClientConnection, RootCertStore,
+        };
+
+        let (cert, key) = self_signed_cert("server");
+        let server_config = load_server_config(&cert, &key).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/secure", |r, c| {
+                let conn = c.connection().unwrap();
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some(format!("over {}", conn.tls_version.unwrap())),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = TestServer::start_with(HttpServer::new(router), |l| {
+            HttpListener::tls(l, server_config)
+        });
+        let connection =
+            ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(server.addr()).unwrap());
+        stream.write_all(b"GET /secure HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("over TLSv1.3"), "{response}");
+    }
+
+    #[test]
+    fn test_http_server_admin_reload_swaps_router() {
+        let version = |v: &'static str| {
+            HttpRouterBuilder::new()
+                .add_route(HttpMethod::GET, "/version", move |r, _| {
+                    Ok(HttpResponse::new(
+                        r.metadata.protocol,
+                        HttpStatus::Ok,
+                        HttpHeaders::new(),
+                        Some(v.to_string()),
+                    ))
+                })
+                .build()
+                .unwrap()
+        };
+        let server = HttpServer::new(version("v1"))
+            .with_reloader(move || Ok(HttpServerSettings::new(version("v2"))))
+            .with_admin_reload("/-/reload", "s3cret");
+        let in_flight = server.state().settings();
+        let server = TestServer::start(server);
+        server.get("/version").send().assert_body("v1");
+        server
+            .post("/-/reload")
+            .send()
+            .assert_status(HttpStatus::Unauthorized);
+        server
+            .post("/-/reload")
+            .header("Authorization", "Bearer guess")
+            .send()
+            .assert_status(HttpStatus::Unauthorized);
+        server.get("/version").send().assert_body("v1");
+        server
+            .post("/-/reload")
+            .header("Authorization", "Bearer s3cret")
+            .send()
+            .assert_status(HttpStatus::Ok);
+        server.get("/version").send().assert_body("v2");
+        server
+            .post("/-/reload?reason=deploy")
+            .header("Authorization", "Bearer s3cret")
+            .send()
+            .assert_status(HttpStatus::Ok);
+        assert!(in_flight
+            .router
+            .find_route(&HttpRequest {
+                metadata: HttpRequestMetaData::parse("GET /version HTTP/1.1").unwrap(),
+                body: None,
+                stream: None,
+            })
+            .is_some());
+        server
+            .get("/-/reload")
+            .header("Accept", "*/*")
+            .send()
+            .assert_status(HttpStatus::MethodNotAllowed);
+    }
+
+    #[test]
+    fn test_http_server_handler_panic_is_500() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/panic", |_, _| panic!("handler failed"))
+            .add_route(HttpMethod::GET, "/ok", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some("ok".to_string()),
+                ))
+            })
+            .build()
+            .unwrap();
+        let client = TestClient::new(router);
+        client
+            .get("/panic")
+            .header("Accept", "*/*")
+            .send()
+            .assert_status(HttpStatus::InternalServerError);
+        client.get("/ok").send().assert_body("ok");
+    }
+
+    #[test]
+    fn test_http_server_tags_requests_with_ids() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/id", |r, c| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    c.request_id().map(String::from),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = TestServer::start(
+            HttpServer::new(router)
+                .with_workers(1)
+                .with_request_id_format(RequestIdFormat::Ulid),
+        );
+        server
+            .get("/id")
+            .header("x-request-id", "abc-1")
+            .send()
+            .assert_header(REQUEST_ID_HEADER, "abc-1")
+            .assert_body("abc-1");
+
+        let response = server.get("/id").header("Accept", "*/*").send();
+        response.assert_header(REQUEST_ID_HEADER, &response.body);
+        assert_eq!(response.body.len(), 26);
+
+        let response = server.get("/missing").header("Accept", "*/*").send();
+        let id = response
+            .assert_status(HttpStatus::MethodNotAllowed)
+            .header(REQUEST_ID_HEADER)
+            .unwrap();
+        response.assert_body_contains(&format!("\"request_id\":\"{id}\""));
+    }
+
+    #[test]
+    fn test_http_server_expect_continue() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&checks);
+        let router = HttpRouterBuilder::new()
+            .add_guard(move |r, _| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                match r.metadata.header("Authorization") {
+                    Some(_) => Ok(()),
+                    None => Err(HttpError::new(HttpStatus::Unauthorized, "")),
+                }
+            })
+            .add_route(HttpMethod::POST, "/upload", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    r.body.clone(),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = TestServer::start(HttpServer::new(router).with_max_body_size(16));
+        // Sends `head`, then the body only once told to, and returns whether it was.
+        let exchange = |head: &str| {
+            let mut stream = TcpStream::connect(server.addr()).unwrap();
+            stream.write_all(head.as_bytes()).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let continued = line == "HTTP/1.1 100 Continue\r\n";
+            if continued {
+                reader.read_line(&mut line).unwrap();
+                stream.write_all(b"hello").unwrap();
+                line.clear();
+            }
+            reader.read_to_string(&mut line).unwrap();
+            (continued, line)
+        };
+
+        let (continued, response) = exchange(
+            "POST /upload HTTP/1.1\r\nAuthorization: x\r\nContent-Length: 5\r\n\
+            Expect: 100-continue\r\n\r\n",
+        );
+        assert!(continued);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("hello"), "{response}");
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        for (head, status) in [
+            (
+                "POST /upload HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\
+                Expect: 100-continue\r\n\r\n",
+                "401",
+            ),
+            (
+                "POST /upload HTTP/1.1\r\nAuthorization: x\r\nContent-Length: 17\r\n\
+                Expect: 100-continue\r\n\r\n",
+                "413",
+            ),
+            (
+                "POST /other HTTP/1.1\r\nAuthorization: x\r\nContent-Length: 5\r\n\
+                Expect: 100-continue\r\n\r\n",
+                "405",
+            ),
+            (
+                "POST /upload HTTP/1.1\r\nAuthorization: x\r\nContent-Length: 5\r\n\
+                Expect: 200-ok\r\n\r\n",
+                "417",
+            ),
+        ] {
+            let (continued, response) = exchange(head);
+            assert!(!continued, "{head}");
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {status}")),
+                "{response}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_http_server_streams_request_bodies() {
+        let router = HttpRouterBuilder::new()
+            .add_streaming_route(HttpMethod::PUT, "/files/:name", |r, _| {
+                assert!(r.body.is_none());
+                let mut stream = r.stream.clone().unwrap();
+                let mut received = 0;
+                let mut buf = [0u8; 4];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => received += n,
+                        Err(e) => return Err(body_error(e)),
+                    }
+                }
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Created,
+                    HttpHeaders::new(),
+                    Some(received.to_string()),
+                ))
+            })
+            .add_route(HttpMethod::POST, "/echo", |r, _| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    r.body.clone(),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = TestServer::start(HttpServer::new(router).with_max_body_size(32));
+        let request = |raw: &str| send_raw(&server, raw);
+        let response =
+            request("PUT /files/a HTTP/1.1\r\nHost: a\r\nContent-Length: 11\r\n\r\nhello world");
+        assert!(response.starts_with("HTTP/1.1 201"), "{response}");
+        assert!(response.ends_with("11"), "{response}");
+        let response = request(
+            "PUT /files/a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        assert!(response.ends_with("11"), "{response}");
+        let response = request(
+            "PUT /files/a HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            21\r\n0123456789abcdef0123456789abcdefX\r\n0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+        let response = request(
+            "POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n",
+        );
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+    }
+
+    #[test]
+    fn test_http_server_routes_by_host() {
+        let hello = |body: &'static str| {
+            HttpRouterBuilder::new()
+                .add_route(HttpMethod::GET, "/", move |r, _| {
+                    Ok(HttpResponse::new(
+                        r.metadata.protocol,
+                        HttpStatus::Ok,
+                        HttpHeaders::new(),
+                        Some(body.to_string()),
+                    ))
+                })
+                .build()
+                .unwrap()
+        };
+        let hosts = || {
+            HttpServer::new(hello("default"))
+                .with_virtual_host("example.com".parse().unwrap(), hello("exact"))
+                .with_virtual_host("*.example.com".parse().unwrap(), hello("wildcard"))
+        };
+        let server = TestServer::start(hosts());
+        for (raw, body) in [
+            (
+                "GET / HTTP/1.1\r\nHost: Example.com:80\r\nAccept: */*\r\n\r\n",
+                "exact",
+            ),
+            (
+                "GET / HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n",
+                "wildcard",
+            ),
+            (
+                "GET / HTTP/1.1\r\nHost: other.org\r\nAccept: */*\r\n\r\n",
+                "default",
+            ),
+            (
+                "GET http://a.example.com/ HTTP/1.1\r\nHost: other.org\r\nAccept: */*\r\n\r\n",
+                "wildcard",
+            ),
+        ] {
+            assert!(send_raw(&server, raw).ends_with(body), "{raw}");
+        }
+        let strict = TestServer::start(hosts().with_strict_hosts(true));
+        let response: TestResponse = send_raw(
+            &strict,
+            "GET / HTTP/1.1\r\nHost: other.org\r\nAccept: */*\r\n\r\n",
+        )
+        .parse()
+        .unwrap();
+        response.assert_status(HttpStatus::MisdirectedRequest);
+    }
+
+    #[test]
+    fn test_http_server_proxy_protocol_and_trusted_proxies() {
+        let router = HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/ip", |r, c| {
+                let conn = c.connection().unwrap();
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some(format!(
+                        "{} {} {}",
+                        conn.peer_addr.unwrap(),
+                        conn.client_ip.unwrap(),
+                        conn.requests
+                    )),
+                ))
+            })
+            .build()
+            .unwrap();
+        let server = HttpServer::new(router)
+            .with_trusted_proxies(TrustedProxies::new().trust("10.0.0.0/8").unwrap());
+        let server =
+            TestServer::start_with(server, |l| HttpListener::plain(l).with_proxy_protocol(true));
+        let response = send_raw(
+            &server,
+            "PROXY TCP4 10.1.2.3 10.0.0.1 40000 80\r\n\
+            GET /ip HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 203.0.113.9\r\n\r\n",
+        );
+        assert!(
+            response.ends_with("\r\n\r\n10.1.2.3:40000 203.0.113.9 1"),
+            "{response}"
+        );
     }
 }
@@ -0,0 +1,535 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::{debug, error};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde_json::json;
+
+use crate::{
+    common::{HttpError, HttpHeaders, HttpServerContext, HttpStatus},
+    cookie::{add_cookie, is_token, SetCookie},
+    request::HttpRequest,
+    response::HttpResponse,
+    router::HttpRouterFunc,
+};
+
+/// Context key holding the id of the session of the request.
+pub const SESSION_ID_KEY: &str = "session.id";
+
+/// How often [`MemoryStore`] drops the expired sessions nobody loads anymore.
+const MEMORY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type SessionData = BTreeMap<String, String>;
+
+// =========================================================
+// ==================== SessionStore =======================
+// =========================================================
+/// Storage of the session data, keyed by session id. Expired sessions are never loaded.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>>;
+    /// Stores the session data, which expires `ttl` from now.
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()>;
+    fn remove(&self, id: &str) -> io::Result<()>;
+}
+
+#[derive(Default)]
+struct MemorySessions {
+    sessions: HashMap<String, (SystemTime, SessionData)>,
+    next_sweep: Option<SystemTime>,
+}
+
+/// Sessions kept in memory, lost on restart. An expired session is dropped when loaded, and
+/// all of them are swept on a save at most once a minute.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<MemorySessions>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let sessions = &mut self.sessions.lock().unwrap().sessions;
+        match sessions.get(id) {
+            Some((expires, _)) if *expires <= SystemTime::now() => {
+                sessions.remove(id);
+                Ok(None)
+            }
+            session => Ok(session.map(|(_, data)| data.clone())),
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut store = self.sessions.lock().unwrap();
+        if store.next_sweep.is_none_or(|next| next <= now) {
+            store.sessions.retain(|_, (expires, _)| *expires > now);
+            store.next_sweep = Some(now + MEMORY_SWEEP_INTERVAL);
+        }
+        store
+            .sessions
+            .insert(id.to_string(), (now + ttl, data.clone()));
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().sessions.remove(id);
+        Ok(())
+    }
+}
+
+/// Sessions kept as one JSON file per session in a directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    /// Ids are generated hex strings, anything else cannot name a session file.
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        match !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            true => Ok(self.dir.join(format!("{id}.json"))),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad session id",
+            )),
+        }
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionData>> {
+        let path = self.path(id)?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let value: serde_json::Value = serde_json::from_str(&contents)?;
+        let expires = value["expires"].as_u64().unwrap_or(0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if expires <= now {
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(value["data"].clone())?))
+    }
+
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) -> io::Result<()> {
+        let expires = (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        let contents = json!({ "expires": expires.as_secs(), "data": data }).to_string();
+        fs::write(self.path(id)?, contents)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+// =========================================================
+// ======================= Session =========================
+// =========================================================
+fn store_error(e: io::Error) -> HttpError {
+    error!("Session: store failure -> {e}");
+    HttpError::new(HttpStatus::InternalServerError, "session store failure")
+}
+
+fn cookie_error(e: String) -> HttpError {
+    error!("Session: cannot build the cookie -> {e}");
+    HttpError::new(HttpStatus::InternalServerError, "session cookie failure")
+}
+
+/// The session of a request, whose changes are written to the store right away.
+pub struct Session {
+    store: Arc<dyn SessionStore>,
+    id: String,
+    ttl: Duration,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn data(&self) -> Result<SessionData, HttpError> {
+        let data = self.store.load(&self.id).map_err(store_error)?;
+        Ok(data.unwrap_or_default())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, HttpError> {
+        Ok(self.data()?.remove(key))
+    }
+
+    pub fn insert(&self, key: &str, value: &str) -> Result<(), HttpError> {
+        let mut data = self.data()?;
+        data.insert(key.to_string(), value.to_string());
+        self.store
+            .save(&self.id, &data, self.ttl)
+            .map_err(store_error)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<Option<String>, HttpError> {
+        let mut data = self.data()?;
+        let value = data.remove(key);
+        self.store
+            .save(&self.id, &data, self.ttl)
+            .map_err(store_error)?;
+        Ok(value)
+    }
+
+    /// Deletes the session, the client is told to drop its cookie.
+    pub fn destroy(&self) -> Result<(), HttpError> {
+        self.store.remove(&self.id).map_err(store_error)
+    }
+}
+
+// =========================================================
+// ======================= Sessions ========================
+// =========================================================
+/// Middleware tying requests to a server side session through a cookie holding the session
+/// id and its HMAC-SHA256 signature. A session is only created once data is inserted.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    key: hmac::Key,
+    cookie_name: String,
+    secure: bool,
+    ttl: Duration,
+}
+
+impl Sessions {
+    pub fn new<S: SessionStore + 'static>(store: S, secret: &[u8]) -> Self {
+        Sessions {
+            store: Arc::new(store),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            cookie_name: "session".to_string(),
+            secure: false,
+            ttl: Duration::from_secs(24 * 3600),
+        }
+    }
+
+    /// Sets the name of the session cookie, `session` by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid cookie name.
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        assert!(is_token(name), "invalid session cookie name {name:?}");
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Only sends the cookie over https.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// How long a session lives without being used, a day by default.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns the session of the request being handled.
+    pub fn session(&self, params: &HttpServerContext) -> Option<Session> {
+        Some(Session {
+            store: self.store.clone(),
+            id: params.get(SESSION_ID_KEY)?.clone(),
+            ttl: self.ttl,
+        })
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let tag = hmac::sign(&self.key, id.as_bytes());
+        format!("{id}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    /// Returns the id of a correctly signed cookie value.
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, tag) = value.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, id.as_bytes(), &tag).ok()?;
+        Some(id)
+    }
+
+    fn new_id() -> Result<String, HttpError> {
+        let mut id = [0u8; 32];
+        SystemRandom::new().fill(&mut id).map_err(|_| {
+            HttpError::new(HttpStatus::InternalServerError, "no session id available")
+        })?;
+        Ok(id.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    fn cookie(&self, value: &str) -> Result<SetCookie, String> {
+        Ok(SetCookie::new(&self.cookie_name, value)?
+            .path("/")?
+            .http_only(true)
+            .secure(self.secure)
+            .max_age(self.ttl))
+    }
+
+    /// Refreshes a live session and sets its cookie, or drops the cookie of a destroyed one.
+    fn finish(&self, id: &str, known: bool, headers: &mut HttpHeaders) -> Result<(), HttpError> {
+        match self.store.load(id).map_err(store_error)? {
+            Some(data) => {
+                self.store.save(id, &data, self.ttl).map_err(store_error)?;
+                add_cookie(headers, &self.cookie(&self.sign(id)).map_err(cookie_error)?);
+            }
+            None if known => {
+                let removal = SetCookie::removal(&self.cookie_name, "/").map_err(cookie_error)?;
+                add_cookie(headers, &removal);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+        next: &HttpRouterFunc,
+    ) -> Result<HttpResponse, HttpError> {
+        let cookie = req.metadata.cookie(&self.cookie_name);
+        let known = match cookie.as_deref().and_then(|c| self.verify(c)) {
+            Some(id) => self
+                .store
+                .load(id)
+                .map_err(store_error)?
+                .map(|_| id.to_string()),
+            None => {
+                if cookie.is_some() {
+                    debug!("Session: ignoring bad cookie on {}", req.metadata.uri);
+                }
+                None
+            }
+        };
+        let id = match known.clone() {
+            Some(id) => id,
+            None => Sessions::new_id()?,
+        };
+        let mut params = params.clone();
+        params.insert(SESSION_ID_KEY.to_string(), id.clone());
+        match next(req, &params) {
+            Ok(mut response) => {
+                self.finish(&id, known.is_some(), &mut response.metadata.headers)?;
+                Ok(response)
+            }
+            Err(mut e) => {
+                self.finish(&id, known.is_some(), &mut e.headers)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{HttpMethod, HttpProtocol},
+        request::HttpRequestMetaData,
+        router::{HttpRouter, HttpRouterBuilder},
+    };
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn request(uri: &str, cookie: Option<&str>) -> HttpRequest {
+        let mut raw = format!("GET {uri} HTTP/1.1\nHost: localhost\nAccept: */*\n");
+        if let Some(cookie) = cookie {
+            raw.push_str(&format!("Cookie: {cookie}\n"));
+        }
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&raw).unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    fn router(sessions: Sessions) -> HttpRouter {
+        let login = sessions.clone();
+        let logout = sessions.clone();
+        let whoami = sessions.clone();
+        let ok = |body: String| {
+            Ok(HttpResponse::new(
+                HttpProtocol::Http1_1,
+                HttpStatus::Ok,
+                HttpHeaders::new(),
+                Some(body),
+            ))
+        };
+        HttpRouterBuilder::new()
+            .add_middleware(move |r, c, next| sessions.handle(r, c, next))
+            .add_route(HttpMethod::GET, "/login/:user", move |_, c| {
+                login.session(c).unwrap().insert("user", &c["user"])?;
+                ok(String::new())
+            })
+            .add_route(HttpMethod::GET, "/logout", move |_, c| {
+                logout.session(c).unwrap().destroy()?;
+                ok(String::new())
+            })
+            .add_route(HttpMethod::GET, "/whoami", move |_, c| {
+                let user = whoami.session(c).unwrap().get("user")?;
+                ok(user.unwrap_or_default())
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn set_cookie(response: &HttpResponse) -> Option<&str> {
+        response
+            .metadata
+            .headers
+            .get("Set-Cookie")
+            .map(|c| c.as_str())
+    }
+
+    #[test]
+    fn test_sessions() {
+        let router = router(Sessions::new(MemoryStore::new(), SECRET));
+        let call = |uri: &str, cookie: Option<&str>| router.route(&request(uri, cookie)).unwrap();
+
+        let response = call("/whoami", None);
+        assert_eq!(set_cookie(&response), None);
+
+        let response = call("/login/ann", None);
+        let set = set_cookie(&response).unwrap();
+        assert!(set.ends_with("; Path=/; Max-Age=86400; HttpOnly"), "{set}");
+        let cookie = set.split(';').next().unwrap().to_string();
+        assert_eq!(call("/whoami", Some(&cookie)).body.as_deref(), Some("ann"));
+
+        let forged = format!("{}x", &cookie[..cookie.len() - 1]);
+        assert_eq!(call("/whoami", Some(&forged)).body.as_deref(), Some(""));
+
+        let response = call("/logout", Some(&cookie));
+        assert_eq!(set_cookie(&response), Some("session=; Path=/; Max-Age=0"));
+        let response = call("/whoami", Some(&cookie));
+        assert_eq!(response.body.as_deref(), Some(""));
+        assert_eq!(set_cookie(&response), None);
+    }
+
+    #[test]
+    fn test_sessions_refuse_tampered_cookies() {
+        let sessions = Sessions::new(MemoryStore::new(), SECRET);
+        let router = router(sessions.clone());
+        let call = |uri: &str, cookie: Option<&str>| router.route(&request(uri, cookie)).unwrap();
+        let response = call("/login/ann", None);
+        let cookie = set_cookie(&response).unwrap().split(';').next().unwrap();
+        let (id, tag) = cookie["session=".len()..].split_once('.').unwrap();
+        let other_key = Sessions::new(MemoryStore::new(), b"another secret").sign(id);
+        let mut other_id = id.to_string();
+        other_id.replace_range(..1, if id.starts_with('0') { "1" } else { "0" });
+        for value in [
+            format!("{other_id}.{tag}"),
+            other_key,
+            id.to_string(),
+            format!("{id}."),
+            format!("{id}.not base64!"),
+            format!(".{tag}"),
+        ] {
+            assert_eq!(sessions.verify(&value), None, "{value}");
+            let response = call("/whoami", Some(&format!("session={value}")));
+            assert_eq!(response.body.as_deref(), Some(""), "{value}");
+            assert_eq!(set_cookie(&response), None, "{value}");
+        }
+        assert_eq!(sessions.verify(&cookie["session=".len()..]), Some(id));
+
+        // A bad cookie gets a new session rather than the one it names.
+        let response = call("/login/bob", Some(&format!("session={id}.{tag}x")));
+        let renewed = set_cookie(&response).unwrap();
+        assert!(!renewed.starts_with(&format!("session={id}.")), "{renewed}");
+    }
+
+    #[test]
+    fn test_sessions_cookie_attributes() {
+        let sessions = Sessions::new(MemoryStore::new(), SECRET)
+            .cookie_name("sid")
+            .secure(true)
+            .ttl(Duration::from_secs(60));
+        let router = router(sessions);
+        let response = router.route(&request("/login/ann", None)).unwrap();
+        let set = set_cookie(&response).unwrap();
+        assert!(set.starts_with("sid="), "{set}");
+        assert!(
+            set.ends_with("; Path=/; Max-Age=60; Secure; HttpOnly"),
+            "{set}"
+        );
+        let cookie = set.split(';').next().unwrap();
+        let response = router.route(&request("/logout", Some(cookie))).unwrap();
+        assert_eq!(set_cookie(&response), Some("sid=; Path=/; Max-Age=0"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid session cookie name")]
+    fn test_sessions_refuse_invalid_cookie_names() {
+        Sessions::new(MemoryStore::new(), SECRET).cookie_name("my session");
+    }
+
+    #[test]
+    fn test_memory_store_expiry() {
+        let store = MemoryStore::new();
+        let data = SessionData::from([("user".to_string(), "ann".to_string())]);
+        store.save("live", &data, Duration::from_secs(60)).unwrap();
+        store.save("expired", &data, Duration::ZERO).unwrap();
+        assert_eq!(store.load("live").unwrap(), Some(data.clone()));
+        assert_eq!(store.load("expired").unwrap(), None);
+        assert!(!store
+            .sessions
+            .lock()
+            .unwrap()
+            .sessions
+            .contains_key("expired"));
+
+        // Sessions nobody loads are swept by a later save.
+        store.save("stale", &data, Duration::ZERO).unwrap();
+        store.sessions.lock().unwrap().next_sweep = Some(SystemTime::now());
+        store.save("other", &data, Duration::from_secs(60)).unwrap();
+        let sessions = &store.sessions.lock().unwrap().sessions;
+        assert!(!sessions.contains_key("stale"));
+        assert_eq!(sessions.len(), 2);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("sessions-{}", std::process::id()));
+        let store = FileStore::new(&dir).unwrap();
+        let data = SessionData::from([("user".to_string(), "ann".to_string())]);
+        store
+            .save("abc123", &data, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(store.load("abc123").unwrap(), Some(data.clone()));
+        assert!(store.load("../etc/passwd").is_err());
+        store.save("abc123", &data, Duration::ZERO).unwrap();
+        assert_eq!(store.load("abc123").unwrap(), None);
+        assert!(!dir.join("abc123.json").exists());
+        store.remove("abc123").unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
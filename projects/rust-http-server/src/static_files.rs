@@ -0,0 +1,210 @@
+use std::{
+    fs::{self, File},
+    path::{Component, Path, PathBuf},
+};
+
+use log::error;
+
+use crate::{
+    common::{HttpError, HttpHeaders, HttpMethod, HttpServerContext, HttpStatus},
+    request::HttpRequest,
+    response::HttpResponse,
+};
+
+/// Guesses the `Content-Type` of a file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Decodes `%XX` escapes, returning `None` on malformed escapes or non utf-8 output.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Serves the files below `root` for the requests under `prefix`.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: String,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(prefix: &str, root: P) -> Self {
+        StaticFiles {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            root: root.as_ref().to_path_buf(),
+            index: "index.html".to_string(),
+        }
+    }
+
+    /// Resolves the file a request uri points to, refusing paths escaping `root`.
+    fn resolve(&self, uri: &str) -> Option<PathBuf> {
+        let path = uri.split(['?', '#']).next().unwrap_or("");
+        let path = path.strip_prefix(self.prefix.as_str()).unwrap_or(path);
+        let path = percent_decode(path)?;
+        let relative = Path::new(path.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return None;
+        }
+        let resolved = self.root.join(relative);
+        if resolved.is_dir() {
+            Some(resolved.join(&self.index))
+        } else {
+            Some(resolved)
+        }
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        _: &HttpServerContext,
+    ) -> Result<HttpResponse, HttpError> {
+        if req.metadata.method != HttpMethod::GET {
+            return Err(HttpError::new(HttpStatus::MethodNotAllowed, ""));
+        }
+        let path = match self.resolve(&req.metadata.uri) {
+            Some(p) => p,
+            None => return Err(HttpError::new(HttpStatus::NotFound, "")),
+        };
+        let file = match File::open(&path).and_then(|f| f.metadata().map(|m| (f, m))) {
+            Ok((f, m)) if m.is_file() => (f, m.len()),
+            Ok(_) => return Err(HttpError::new(HttpStatus::NotFound, "")),
+            Err(e) => {
+                error!("StaticFiles: error accessing {} {e}", path.display());
+                return Err(HttpError::new(HttpStatus::NotFound, ""));
+            }
+        };
+        let mut headers = HttpHeaders::new();
+        headers.insert("Content-Length".to_string(), file.1.to_string());
+        headers.insert("Content-Type".to_string(), content_type(&path).to_string());
+        Ok(HttpResponse::with_stream(
+            req.metadata.protocol,
+            HttpStatus::Ok,
+            headers,
+            Box::new(file.0),
+        ))
+    }
+
+    /// Returns whether `root` exists and is a directory.
+    pub fn root_exists(&self) -> bool {
+        fs::metadata(&self.root).is_ok_and(|m| m.is_dir())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::HttpRequestMetaData;
+    use std::{env, io::Read};
+
+    fn root() -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust-http-server-static-{}", std::process::id()));
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(dir.join("docs/a b.txt"), "spaced").unwrap();
+        fs::write(dir.join("logo.png"), [0x89u8, 0x50, 0x4e, 0x47, 0xff]).unwrap();
+        dir
+    }
+
+    fn get(files: &StaticFiles, uri: &str) -> Result<HttpResponse, HttpError> {
+        let r = HttpRequest {
+            metadata: HttpRequestMetaData::parse(&format!("GET {uri} HTTP/1.1")).unwrap(),
+            body: None,
+            stream: None,
+        };
+        files.handle(&r, &HttpServerContext::new())
+    }
+
+    fn body(response: HttpResponse) -> Vec<u8> {
+        let mut out = Vec::new();
+        response.stream.unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Path::new("a/b.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("x.png")), "image/png");
+        assert_eq!(content_type(Path::new("noext")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b%2F").unwrap(), "a b/");
+        assert!(percent_decode("%zz").is_none());
+        assert!(percent_decode("%2").is_none());
+    }
+
+    #[test]
+    fn test_static_files_serves_files() {
+        let files = StaticFiles::new("/assets/", root());
+        let r = get(&files, "/assets/docs/a%20b.txt?v=1").unwrap();
+        assert_eq!(r.metadata.headers["Content-Length"], "6");
+        assert_eq!(body(r), b"spaced");
+        let r = get(&files, "/assets/logo.png").unwrap();
+        assert_eq!(r.metadata.headers["Content-Type"], "image/png");
+        assert_eq!(body(r), [0x89u8, 0x50, 0x4e, 0x47, 0xff]);
+    }
+
+    #[test]
+    fn test_static_files_serves_index() {
+        let files = StaticFiles::new("/", root());
+        assert_eq!(body(get(&files, "/").unwrap()), b"<h1>home</h1>");
+        assert_eq!(
+            get(&files, "/docs").err().unwrap().status,
+            HttpStatus::NotFound
+        );
+    }
+
+    #[test]
+    fn test_static_files_refuses_traversal() {
+        let files = StaticFiles::new("/assets", root().join("docs"));
+        for uri in [
+            "/assets/../index.html",
+            "/assets/%2e%2e/index.html",
+            "/assets//etc/passwd",
+        ] {
+            assert_eq!(get(&files, uri).err().unwrap().status, HttpStatus::NotFound);
+        }
+    }
+}
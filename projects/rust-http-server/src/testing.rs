@@ -0,0 +1,450 @@
+use std::{
+    io::{BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+
+use serde_json::Value;
+
+use crate::{
+    chunked::ChunkedReader,
+    client::HttpClient,
+    common::{HttpHeaders, HttpMethod, HttpProtocol, HttpStatus},
+    connection::HttpConnectionInfo,
+    request::{HttpRequest, HttpRequestMetaData},
+    response::{HttpResponse, HttpResponseMetaData},
+    router::HttpRouter,
+    server::{HttpListener, HttpServer, HttpServerState},
+};
+
+/// Sends the requests built by [`TestRequest`], either in process or over a socket.
+trait TestTransport {
+    fn send(&self, request: HttpRequest) -> TestResponse;
+}
+
+// ====== In process ======
+
+/// Dispatches requests through the routing, middlewares and error pages of a server without
+/// opening sockets. Requests come from a loopback peer.
+pub struct TestClient {
+    state: Arc<HttpServerState>,
+    conn: HttpConnectionInfo,
+}
+
+impl TestClient {
+    pub fn new(router: HttpRouter) -> Self {
+        Self::from_server(&HttpServer::new(router))
+    }
+
+    /// Serves requests with the settings, metrics and reload route of `server`.
+    pub fn from_server(server: &HttpServer) -> Self {
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        TestClient {
+            state: Arc::new(server.state()),
+            conn: HttpConnectionInfo {
+                peer_addr: Some(loopback),
+                local_addr: Some(loopback),
+                client_ip: Some(loopback.ip()),
+                requests: 1,
+                ..HttpConnectionInfo::default()
+            },
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::GET, uri)
+    }
+    pub fn post(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::POST, uri)
+    }
+    pub fn put(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::PUT, uri)
+    }
+    pub fn delete(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::DELETE, uri)
+    }
+    pub fn request(&self, method: HttpMethod, uri: &str) -> TestRequest<'_> {
+        TestRequest::new(self, method, uri)
+    }
+}
+
+impl TestTransport for TestClient {
+    fn send(&self, request: HttpRequest) -> TestResponse {
+        TestResponse::from_response(self.state.serve(request, &self.conn))
+    }
+}
+
+// ====== Over a socket ======
+
+/// A server listening on an ephemeral loopback port, serving until the test process exits.
+/// Requests are sent with an [`HttpClient`] that does not follow redirects, each on its own
+/// connection.
+pub struct TestServer {
+    addr: SocketAddr,
+}
+
+impl TestServer {
+    /// Starts serving with `server` on a port picked by the system.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no loopback port can be bound.
+    pub fn start(server: HttpServer) -> Self {
+        Self::start_with(server, HttpListener::plain)
+    }
+
+    /// Starts serving with `server` on the listener built by `listener` around a port picked
+    /// by the system, e.g. to serve TLS or expect PROXY protocol headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no loopback port can be bound.
+    pub fn start_with<F>(server: HttpServer, listener: F) -> Self
+    where
+        F: FnOnce(TcpListener) -> HttpListener,
+    {
+        let tcp = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("bind a loopback port");
+        let addr = tcp.local_addr().expect("read the bound address");
+        let listener = listener(tcp);
+        thread::spawn(move || server.serve_listeners(&[listener]));
+        TestServer { addr }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::GET, uri)
+    }
+    pub fn post(&self, uri: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::POST, uri)
+    }
+    pub fn request(&self, method: HttpMethod, uri: &str) -> TestRequest<'_> {
+        TestRequest::new(self, method, uri)
+    }
+
+    /// Writes `raw` as is on a new connection and returns everything the server answered,
+    /// for requests the builder cannot express.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection fails.
+    pub fn send_raw(&self, raw: &[u8]) -> Vec<u8> {
+        let mut stream = TcpStream::connect(self.addr).expect("connect to the test server");
+        stream.write_all(raw).expect("write the request");
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .expect("read the response");
+        response
+    }
+}
+
+impl TestTransport for TestServer {
+    fn send(&self, request: HttpRequest) -> TestResponse {
+        let client = HttpClient::new()
+            .with_max_redirects(0)
+            .with_max_idle_per_host(0);
+        match client.send_to(&self.addr.to_string(), request) {
+            Ok(response) => TestResponse::from_response(response),
+            Err(e) => panic!("request to the test server failed: {e}"),
+        }
+    }
+}
+
+// ====== Requests ======
+
+/// A request under construction, sent with [`TestRequest::send`].
+pub struct TestRequest<'a> {
+    transport: &'a dyn TestTransport,
+    request: HttpRequest,
+}
+
+impl<'a> TestRequest<'a> {
+    fn new(transport: &'a dyn TestTransport, method: HttpMethod, uri: &str) -> Self {
+        let mut headers = HttpHeaders::new();
+        headers.insert("Host".to_string(), "localhost".to_string());
+        TestRequest {
+            transport,
+            request: HttpRequest {
+                metadata: HttpRequestMetaData {
+                    protocol: HttpProtocol::Http1_1,
+                    uri: uri.to_string(),
+                    method,
+                    headers,
+                },
+                body: None,
+                stream: None,
+            },
+        }
+    }
+
+    /// Sets the header `name`, replacing the one set before whatever its case.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        let headers = &mut self.request.metadata.headers;
+        headers.retain(|k, _| !k.eq_ignore_ascii_case(name));
+        headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Sets the body along with its `Content-Length`.
+    pub fn body(self, body: &str) -> Self {
+        let mut request = self.header("Content-Length", &body.len().to_string());
+        request.request.body = Some(body.to_string());
+        request
+    }
+
+    /// Sets a JSON body along with its `Content-Type`.
+    pub fn json(self, body: &Value) -> Self {
+        self.header("Content-Type", "application/json")
+            .body(&body.to_string())
+    }
+
+    pub fn send(self) -> TestResponse {
+        self.transport.send(self.request)
+    }
+}
+
+// ====== Responses ======
+
+/// A response received by a test, with its body fully read.
+pub struct TestResponse {
+    pub metadata: HttpResponseMetaData,
+    pub body: String,
+}
+
+impl TestResponse {
+    fn from_response(mut response: HttpResponse) -> Self {
+        let mut body = response.body.take().unwrap_or_default();
+        if let Some(mut stream) = response.stream.take() {
+            let mut bytes = Vec::new();
+            stream
+                .read_to_end(&mut bytes)
+                .expect("read the response stream");
+            body.push_str(&String::from_utf8_lossy(&bytes));
+        }
+        TestResponse {
+            metadata: response.metadata,
+            body,
+        }
+    }
+
+    /// Parses a response written by the server, decoding chunked bodies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `raw` is not a well formed response.
+    fn parse(raw: &[u8]) -> Self {
+        let end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("response head");
+        let head = String::from_utf8_lossy(&raw[..end]);
+        let metadata = HttpResponseMetaData::parse(&head).expect("response metadata");
+        let mut body = &raw[end + 4..];
+        let chunked = metadata.headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("Transfer-Encoding") && v.eq_ignore_ascii_case("chunked")
+        });
+        let body = if chunked {
+            let mut decoded = Vec::new();
+            ChunkedReader::new(BufReader::new(&mut body))
+                .read_to_end(&mut decoded)
+                .expect("chunked response body");
+            decoded
+        } else {
+            body.to_vec()
+        };
+        TestResponse {
+            metadata,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+
+    /// Looks up a header value ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.metadata
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Parses the body as JSON.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the body is not valid JSON.
+    #[track_caller]
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body)
+            .unwrap_or_else(|e| panic!("response body is not JSON ({e}): {}", self.body))
+    }
+
+    #[track_caller]
+    pub fn assert_status(&self, status: HttpStatus) -> &Self {
+        assert_eq!(self.metadata.status, status, "body: {}", self.body);
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(
+            self.header(name),
+            Some(value),
+            "header {name} of {:?}",
+            self.metadata.headers
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_no_header(&self, name: &str) -> &Self {
+        assert_eq!(self.header(name), None, "header {name}");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body(&self, body: &str) -> &Self {
+        assert_eq!(self.body, body);
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_body_contains(&self, part: &str) -> &Self {
+        assert!(self.body.contains(part), "{part:?} not in {:?}", self.body);
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_json(&self, json: &Value) -> &Self {
+        assert_eq!(&self.json(), json);
+        self
+    }
+}
+
+impl FromStr for TestResponse {
+    type Err = String;
+
+    /// Parses a raw response, e.g. one returned by [`TestServer::send_raw`].
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if !raw.contains("\r\n\r\n") {
+            return Err(format!("no response head in {raw:?}"));
+        }
+        Ok(TestResponse::parse(raw.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        common::{HttpError, HttpServerContext},
+        request_id::{HttpRequestIdContext, REQUEST_ID_HEADER},
+        router::{HttpPathParams, HttpRouterBuilder},
+    };
+
+    fn router() -> HttpRouter {
+        HttpRouterBuilder::new()
+            .add_middleware(|r, c, next| {
+                let mut response = next(r, c)?;
+                response
+                    .metadata
+                    .headers
+                    .insert("X-Middleware".to_string(), "1".to_string());
+                Ok(response)
+            })
+            .add_route(HttpMethod::GET, "/users/:id", |r, c: &HttpServerContext| {
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Ok,
+                    HttpHeaders::new(),
+                    Some(format!("user {}", c.parse::<u32>("id")?)),
+                ))
+            })
+            .add_route(HttpMethod::POST, "/echo", |r, c| {
+                let mut headers = HttpHeaders::new();
+                if let Some(id) = c.request_id() {
+                    headers.insert("X-Seen-ID".to_string(), id.to_string());
+                }
+                Ok(HttpResponse::new(
+                    r.metadata.protocol,
+                    HttpStatus::Created,
+                    headers,
+                    r.body.clone(),
+                ))
+            })
+            .add_route(HttpMethod::DELETE, "/forbidden", |_, _| {
+                Err(HttpError::new(HttpStatus::Forbidden, "no"))
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_client_dispatches_through_the_stack() {
+        let client = TestClient::new(router());
+        client
+            .get("/users/7?x=1")
+            .send()
+            .assert_status(HttpStatus::Ok)
+            .assert_header("x-middleware", "1")
+            .assert_body("user 7");
+        let response = client
+            .post("/echo")
+            .header("X-Request-ID", "abc")
+            .json(&json!({"a": 1}))
+            .send();
+        response
+            .assert_status(HttpStatus::Created)
+            .assert_header(REQUEST_ID_HEADER, "abc")
+            .assert_header("X-Seen-ID", "abc")
+            .assert_json(&json!({"a": 1}));
+        client
+            .delete("/forbidden")
+            .header("Accept", "text/plain")
+            .send()
+            .assert_status(HttpStatus::Forbidden)
+            .assert_no_header("X-Seen-ID");
+        client
+            .put("/users/7")
+            .send()
+            .assert_status(HttpStatus::MethodNotAllowed);
+    }
+
+    #[test]
+    fn test_server_serves_on_an_ephemeral_port() {
+        let first = TestServer::start(HttpServer::new(router()).with_workers(2));
+        let second = TestServer::start(HttpServer::new(router()).with_workers(2));
+        assert_ne!(first.addr().port(), second.addr().port());
+        first
+            .get("/users/1")
+            .send()
+            .assert_status(HttpStatus::Ok)
+            .assert_header("X-Middleware", "1")
+            .assert_body("user 1");
+        second
+            .post("/echo")
+            .body("hello")
+            .send()
+            .assert_status(HttpStatus::Created)
+            .assert_body("hello");
+
+        let raw = first.send_raw(b"BREW /pot HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response: TestResponse = String::from_utf8(raw).unwrap().parse().unwrap();
+        response.assert_status(HttpStatus::BadRequest);
+    }
+
+    #[test]
+    fn test_response_decodes_chunked_bodies() {
+        let raw =
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let response: TestResponse = raw.parse().unwrap();
+        response.assert_status(HttpStatus::Ok).assert_body("abcde");
+        assert!("no head".parse::<TestResponse>().is_err());
+    }
+}
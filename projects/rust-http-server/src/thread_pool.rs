@@ -0,0 +1,132 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use log::{debug, error};
+
+use crate::server::panic_message;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
+        let thread = thread::spawn(move || loop {
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(job) => {
+                    if let Err(p) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!(
+                            "ThreadPool: job panicked on worker {id}: {}",
+                            panic_message(&*p)
+                        );
+                    }
+                }
+                Err(_) => {
+                    debug!("ThreadPool: worker {id} disconnected, shutting down");
+                    break;
+                }
+            }
+        });
+        Worker {
+            id,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A fixed size pool of worker threads executing jobs sent through a shared channel.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    /// Creates a pool of `size` workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0, "thread pool size must be positive");
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .collect();
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = self.sender.as_ref() {
+            if sender.send(Box::new(f)).is_err() {
+                error!("ThreadPool: cannot dispatch job, all workers are gone");
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    error!("ThreadPool: worker {} panicked", worker.id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    #[should_panic]
+    fn test_thread_pool_zero_size_panics() {
+        ThreadPool::new(0);
+    }
+
+    #[test]
+    fn test_thread_pool_executes_jobs() {
+        let pool = ThreadPool::new(4);
+        assert_eq!(pool.size(), 4);
+        let (tx, rx) = channel();
+        for i in 0..16 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap());
+        }
+        drop(tx);
+        let mut results = rx.iter().collect::<Vec<usize>>();
+        results.sort();
+        assert_eq!(results, (0..16).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_thread_pool_survives_panicking_jobs() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        let (tx, rx) = channel();
+        pool.execute(move || tx.send("done").unwrap());
+        assert_eq!(rx.recv().unwrap(), "done");
+    }
+}
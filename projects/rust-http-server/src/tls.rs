@@ -0,0 +1,72 @@
+use std::{io, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+
+fn invalid_data<E: ToString>(path: &Path, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e.to_string()),
+    )
+}
+
+/// Builds a TLS server configuration from a PEM certificate chain and a PEM private key.
+///
+/// # Errors
+///
+/// Returns an error if either file cannot be read or parsed, or if the key does not match
+/// the certificate.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| invalid_data(cert_path, e))?
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+        .map_err(|e| invalid_data(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(invalid_data(cert_path, "no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(key_path, e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_data(cert_path, e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(key_path, e))?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    /// Writes a self-signed certificate for `localhost` and its key into a temporary
+    /// directory and returns their paths.
+    pub(crate) fn self_signed_cert(name: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("rust-http-server-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join(format!("{name}.crt"));
+        let key_path = dir.join(format!("{name}.key"));
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn test_load_server_config() {
+        let (cert, key) = self_signed_cert("load");
+        assert!(load_server_config(&cert, &key).is_ok());
+    }
+
+    #[test]
+    fn test_load_server_config_errors() {
+        let (cert, key) = self_signed_cert("errors");
+        assert!(load_server_config(&cert, Path::new("/nonexistent/key.pem")).is_err());
+        assert!(load_server_config(&key, &key).is_err());
+        let (_, other_key) = self_signed_cert("other");
+        assert!(load_server_config(&cert, &other_key).is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+
+use crate::{
+    common::{HttpError, HttpServerContext, HttpStatus, HttpStatusType},
+    proxy::ProxyHandler,
+    request::HttpRequest,
+    response::{read_response_head, HttpResponse, HttpResponseMetaData},
+};
+
+/// Number of points each backend gets on the consistent hash ring.
+const VIRTUAL_NODES: usize = 64;
+
+const DEFAULT_MAX_FAILURES: usize = 3;
+const DEFAULT_EJECTION_TIME: Duration = Duration::from_secs(30);
+
+// =========================================================
+// =============== LoadBalanceStrategy =====================
+// =========================================================
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadBalanceStrategy {
+    RoundRobin,
+    LeastConnections,
+    /// Hashes the value of `header`, or the request uri when the header is missing or not
+    /// configured, so the same key keeps reaching the same backend.
+    ConsistentHash {
+        header: Option<String>,
+    },
+}
+
+// =========================================================
+// ===================== Backend ===========================
+// =========================================================
+#[derive(Debug)]
+pub struct Backend {
+    address: String,
+    active: AtomicUsize,
+    healthy: AtomicBool,
+    failures: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Backend {
+    fn new(address: &str) -> Self {
+        Backend {
+            address: address.to_string(),
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            failures: AtomicUsize::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Number of requests currently being forwarded to this backend.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        match *self.ejected_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+}
+
+/// Keeps the active connection count of a backend up until the streamed response body is
+/// fully written and dropped.
+struct BackendStream {
+    inner: Box<dyn Read + Send>,
+    backend: Arc<Backend>,
+}
+
+impl Read for BackendStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Drop for BackendStream {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// =========================================================
+// ==================== HealthCheck ========================
+// =========================================================
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failed checks before a backend is marked unhealthy.
+    pub unhealthy_threshold: usize,
+}
+
+impl HealthCheck {
+    pub fn new(path: &str) -> Self {
+        HealthCheck {
+            path: path.to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 2,
+        }
+    }
+
+    /// Sends `GET path` to `address` and reports whether it answered with a 2xx status.
+    fn probe(&self, address: &str) -> bool {
+        let result = (|| -> Result<HttpStatus, HttpError> {
+            let to_err = |e: io::Error| HttpError::new(HttpStatus::BadGateway, e.to_string());
+            let addr = address
+                .to_socket_addrs()
+                .map_err(to_err)?
+                .next()
+                .ok_or_else(|| HttpError::new(HttpStatus::BadGateway, "unresolved address"))?;
+            let mut stream = TcpStream::connect_timeout(&addr, self.timeout).map_err(to_err)?;
+            stream
+                .set_read_timeout(Some(self.timeout))
+                .map_err(to_err)?;
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                self.path, address
+            )
+            .map_err(to_err)?;
+            let head = read_response_head(&mut BufReader::new(stream)).map_err(to_err)?;
+            Ok(HttpResponseMetaData::parse(&head)?.status)
+        })();
+        match result {
+            Ok(status) => status.status_type() == HttpStatusType::Success,
+            Err(e) => {
+                debug!("HealthCheck: probe of {address} failed -> {e}");
+                false
+            }
+        }
+    }
+}
+
+// =========================================================
+// ==================== UpstreamPool =======================
+// =========================================================
+
+/// A named group of backends a request can be forwarded to.
+///
+/// Backends failing `max_failures` requests in a row are ejected for `ejection_time`
+/// (passive checks), and with [`UpstreamPool::start_health_checks`] they are also probed
+/// periodically (active checks). Requests only go to backends that are healthy and not
+/// ejected.
+pub struct UpstreamPool {
+    name: String,
+    backends: Vec<Arc<Backend>>,
+    strategy: LoadBalanceStrategy,
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+    max_failures: usize,
+    ejection_time: Duration,
+    proxy: ProxyHandler,
+}
+
+impl UpstreamPool {
+    pub fn new(name: &str, addresses: &[&str], strategy: LoadBalanceStrategy) -> Self {
+        let backends = addresses
+            .iter()
+            .map(|a| Arc::new(Backend::new(a)))
+            .collect::<Vec<Arc<Backend>>>();
+        let mut ring = backends
+            .iter()
+            .enumerate()
+            .flat_map(|(i, b)| {
+                (0..VIRTUAL_NODES).map(move |v| (stable_hash(&format!("{}#{v}", b.address)), i))
+            })
+            .collect::<Vec<(u64, usize)>>();
+        ring.sort();
+        UpstreamPool {
+            name: name.to_string(),
+            backends,
+            strategy,
+            ring,
+            next: AtomicUsize::new(0),
+            max_failures: DEFAULT_MAX_FAILURES,
+            ejection_time: DEFAULT_EJECTION_TIME,
+            proxy: ProxyHandler::new(name),
+        }
+    }
+
+    /// Ejects a backend for `ejection_time` once it fails `max_failures` requests in a row.
+    pub fn passive_ejection(mut self, max_failures: usize, ejection_time: Duration) -> Self {
+        self.max_failures = max_failures;
+        self.ejection_time = ejection_time;
+        self
+    }
+
+    /// Sets the handler used to forward requests, e.g. to configure timeouts or prefix
+    /// stripping. Its own upstream address is ignored.
+    pub fn proxy(mut self, proxy: ProxyHandler) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    fn hash_key(&self, req: &HttpRequest, header: Option<&str>) -> u64 {
+        let key = header
+            .and_then(|h| req.metadata.header(h))
+            .unwrap_or(req.metadata.uri.as_str());
+        stable_hash(key)
+    }
+
+    /// Picks the backend the request should be forwarded to, `None` when no backend is
+    /// available.
+    pub fn select(&self, req: &HttpRequest) -> Option<Arc<Backend>> {
+        let available = |i: &usize| self.backends[*i].is_available();
+        let len = self.backends.len();
+        if len == 0 {
+            return None;
+        }
+        let index = match &self.strategy {
+            LoadBalanceStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst);
+                (0..len).map(|i| (start + i) % len).find(available)
+            }
+            LoadBalanceStrategy::LeastConnections => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst);
+                (0..len)
+                    .map(|i| (start + i) % len)
+                    .filter(available)
+                    .min_by_key(|i| self.backends[*i].active_connections())
+            }
+            LoadBalanceStrategy::ConsistentHash { header } => {
+                let hash = self.hash_key(req, header.as_deref());
+                let start = self.ring.partition_point(|(point, _)| *point < hash);
+                (0..self.ring.len())
+                    .map(|i| self.ring[(start + i) % self.ring.len()].1)
+                    .find(available)
+            }
+        };
+        index.map(|i| Arc::clone(&self.backends[i]))
+    }
+
+    fn record_success(&self, backend: &Backend) {
+        backend.failures.store(0, Ordering::SeqCst);
+    }
+
+    fn record_failure(&self, backend: &Backend) {
+        let failures = backend.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_failures > 0 && failures >= self.max_failures {
+            error!(
+                "UpstreamPool {}: ejecting {} after {failures} consecutive failures",
+                self.name, backend.address
+            );
+            *backend.ejected_until.lock().unwrap() = Some(Instant::now() + self.ejection_time);
+            backend.failures.store(0, Ordering::SeqCst);
+        }
+    }
+
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        params: &HttpServerContext,
+    ) -> Result<HttpResponse, HttpError> {
+        let backend = match self.select(req) {
+            Some(b) => b,
+            None => {
+                error!("UpstreamPool {}: no backend available", self.name);
+                return Err(HttpError::new(
+                    HttpStatus::ServiceUnavailable,
+                    format!("no backend available in upstream pool {}", self.name),
+                ));
+            }
+        };
+        backend.active.fetch_add(1, Ordering::SeqCst);
+        match self.proxy.forward(&backend.address, req, params) {
+            Ok(mut response) => {
+                self.record_success(&backend);
+                match response.stream.take() {
+                    Some(inner) => {
+                        response.stream = Some(Box::new(BackendStream {
+                            inner,
+                            backend: Arc::clone(&backend),
+                        }))
+                    }
+                    None => {
+                        backend.active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                Ok(response)
+            }
+            Err(e) => {
+                backend.active.fetch_sub(1, Ordering::SeqCst);
+                if matches!(
+                    e.status,
+                    HttpStatus::BadGateway | HttpStatus::GatewayTimeout
+                ) {
+                    self.record_failure(&backend);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs one round of active health checks.
+    pub fn check_health(&self, check: &HealthCheck, failures: &mut [usize]) {
+        for (backend, failed) in self.backends.iter().zip(failures.iter_mut()) {
+            if check.probe(&backend.address) {
+                *failed = 0;
+                if !backend.healthy.swap(true, Ordering::SeqCst) {
+                    info!(
+                        "UpstreamPool {}: backend {} is healthy again",
+                        self.name, backend.address
+                    );
+                }
+            } else {
+                *failed += 1;
+                if *failed >= check.unhealthy_threshold.max(1)
+                    && backend.healthy.swap(false, Ordering::SeqCst)
+                {
+                    error!(
+                        "UpstreamPool {}: backend {} failed its health check",
+                        self.name, backend.address
+                    );
+                }
+            }
+        }
+    }
+
+    /// Probes every backend with `check` on a background thread until the pool is dropped.
+    pub fn start_health_checks(self: &Arc<Self>, check: HealthCheck) -> thread::JoinHandle<()> {
+        let pool: Weak<UpstreamPool> = Arc::downgrade(self);
+        thread::spawn(move || {
+            let mut failures = Vec::new();
+            while let Some(pool) = pool.upgrade() {
+                failures.resize(pool.backends.len(), 0);
+                pool.check_health(&check, &mut failures);
+                drop(pool);
+                thread::sleep(check.interval);
+            }
+        })
+    }
+}
+
+/// 64 bit FNV-1a followed by the murmur3 finalizer to spread similar keys over the ring.
+/// Unlike the std hasher it is stable across runs.
+fn stable_hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{parse_http_request, HttpRequestMetaData};
+    use std::net::TcpListener;
+
+    fn request(uri: &str) -> HttpRequest {
+        HttpRequest {
+            metadata: HttpRequestMetaData::parse(&format!(
+                "GET {uri} HTTP/1.1\nHost: localhost\nX-User: {uri}"
+            ))
+            .unwrap(),
+            body: None,
+            stream: None,
+        }
+    }
+
+    /// Starts a backend answering every connection with `status` and `name` as body.
+    fn stand_in_backend(status: &'static str, name: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = parse_http_request(&mut BufReader::new(&stream));
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n{name}",
+                    name.len()
+                );
+            }
+        });
+        addr
+    }
+
+    fn closed_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn body(response: HttpResponse) -> String {
+        let mut out = String::new();
+        response.stream.unwrap().read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_round_robin_rotates_backends() {
+        let pool = UpstreamPool::new(
+            "rr",
+            &["a:1", "b:1", "c:1"],
+            LoadBalanceStrategy::RoundRobin,
+        );
+        let picked = (0..6)
+            .map(|_| pool.select(&request("/")).unwrap().address().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(picked, ["a:1", "b:1", "c:1", "a:1", "b:1", "c:1"]);
+    }
+
+    #[test]
+    fn test_round_robin_skips_unavailable_backends() {
+        let pool = UpstreamPool::new(
+            "rr",
+            &["a:1", "b:1", "c:1"],
+            LoadBalanceStrategy::RoundRobin,
+        );
+        pool.backends[1].healthy.store(false, Ordering::SeqCst);
+        *pool.backends[2].ejected_until.lock().unwrap() =
+            Some(Instant::now() + Duration::from_secs(60));
+        for _ in 0..4 {
+            assert_eq!(pool.select(&request("/")).unwrap().address(), "a:1");
+        }
+        pool.backends[0].healthy.store(false, Ordering::SeqCst);
+        assert!(pool.select(&request("/")).is_none());
+    }
+
+    #[test]
+    fn test_least_connections_picks_idle_backend() {
+        let pool = UpstreamPool::new(
+            "lc",
+            &["a:1", "b:1", "c:1"],
+            LoadBalanceStrategy::LeastConnections,
+        );
+        pool.backends[0].active.store(3, Ordering::SeqCst);
+        pool.backends[1].active.store(1, Ordering::SeqCst);
+        pool.backends[2].active.store(2, Ordering::SeqCst);
+        for _ in 0..3 {
+            assert_eq!(pool.select(&request("/")).unwrap().address(), "b:1");
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky() {
+        let pool = UpstreamPool::new(
+            "ch",
+            &["a:1", "b:1", "c:1", "d:1"],
+            LoadBalanceStrategy::ConsistentHash {
+                header: Some("X-User".to_string()),
+            },
+        );
+        let keys = (0..50)
+            .map(|i| format!("/user/{i}"))
+            .collect::<Vec<String>>();
+        let first = keys
+            .iter()
+            .map(|k| pool.select(&request(k)).unwrap().address().to_string())
+            .collect::<Vec<String>>();
+        let again = keys
+            .iter()
+            .map(|k| pool.select(&request(k)).unwrap().address().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(first, again);
+        assert!(pool.backends.iter().all(|b| first.contains(&b.address)));
+
+        // Only the keys of the removed backend move elsewhere.
+        pool.backends[0].healthy.store(false, Ordering::SeqCst);
+        for (k, before) in keys.iter().zip(first.iter()) {
+            let after = pool.select(&request(k)).unwrap();
+            if before != "a:1" {
+                assert_eq!(after.address(), before);
+            } else {
+                assert_ne!(after.address(), "a:1");
+            }
+        }
+    }
+
+    #[test]
+    fn test_pool_forwards_and_tracks_active_connections() {
+        let addr = stand_in_backend("200 OK", "one");
+        let pool = UpstreamPool::new("fw", &[addr.as_str()], LoadBalanceStrategy::RoundRobin);
+        let response = pool
+            .handle(&request("/"), &HttpServerContext::new())
+            .unwrap();
+        assert_eq!(pool.backends[0].active_connections(), 1);
+        assert_eq!(body(response), "one");
+        assert_eq!(pool.backends[0].active_connections(), 0);
+    }
+
+    #[test]
+    fn test_pool_passive_ejection() {
+        let down = closed_address();
+        let up = stand_in_backend("200 OK", "up");
+        let pool = UpstreamPool::new(
+            "ej",
+            &[down.as_str(), up.as_str()],
+            LoadBalanceStrategy::RoundRobin,
+        )
+        .passive_ejection(2, Duration::from_secs(60));
+        let statuses = (0..6)
+            .map(
+                |_| match pool.handle(&request("/"), &HttpServerContext::new()) {
+                    Ok(r) => {
+                        body(r);
+                        HttpStatus::Ok
+                    }
+                    Err(e) => e.status,
+                },
+            )
+            .collect::<Vec<HttpStatus>>();
+        assert_eq!(
+            statuses,
+            [
+                HttpStatus::BadGateway,
+                HttpStatus::Ok,
+                HttpStatus::BadGateway,
+                HttpStatus::Ok,
+                HttpStatus::Ok,
+                HttpStatus::Ok
+            ]
+        );
+        assert!(pool.backends[0].is_ejected());
+        assert!(!pool.backends[1].is_ejected());
+    }
+
+    #[test]
+    fn test_pool_without_available_backend_is_unavailable() {
+        let pool = UpstreamPool::new("none", &[], LoadBalanceStrategy::RoundRobin);
+        let err = pool
+            .handle(&request("/"), &HttpServerContext::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.status, HttpStatus::ServiceUnavailable);
+    }
+
+    #[test]
+    fn test_active_health_checks() {
+        let ok = stand_in_backend("200 OK", "ok");
+        let failing = stand_in_backend("500 Internal Server Error", "ko");
+        let down = closed_address();
+        let pool = UpstreamPool::new(
+            "hc",
+            &[ok.as_str(), failing.as_str(), down.as_str()],
+            LoadBalanceStrategy::RoundRobin,
+        );
+        let mut check = HealthCheck::new("/health");
+        check.timeout = Duration::from_millis(500);
+        check.unhealthy_threshold = 2;
+        let mut failures = vec![0; 3];
+        pool.check_health(&check, &mut failures);
+        assert!(pool.backends.iter().all(|b| b.is_healthy()));
+        pool.check_health(&check, &mut failures);
+        assert!(pool.backends[0].is_healthy());
+        assert!(!pool.backends[1].is_healthy());
+        assert!(!pool.backends[2].is_healthy());
+        assert_eq!(pool.select(&request("/")).unwrap().address(), ok);
+    }
+
+    #[test]
+    fn test_health_check_thread_stops_with_pool() {
+        let pool = Arc::new(UpstreamPool::new(
+            "stop",
+            &[],
+            LoadBalanceStrategy::RoundRobin,
+        ));
+        let mut check = HealthCheck::new("/health");
+        check.interval = Duration::from_millis(10);
+        let handle = pool.start_health_checks(check);
+        drop(pool);
+        handle.join().unwrap();
+    }
+}
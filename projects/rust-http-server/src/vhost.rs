@@ -0,0 +1,244 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr};
+
+use crate::router::HttpRouter;
+
+/// The host names served by a virtual host, either an exact name (`example.com`) or a
+/// wildcard matching every subdomain (`*.example.com`) but not the domain itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostPattern {
+    Exact(String),
+    /// Holds the suffix including its leading dot, `.example.com` for `*.example.com`.
+    Wildcard(String),
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim_end_matches('.').to_ascii_lowercase();
+        let (wildcard, name) = match pattern.strip_prefix("*.") {
+            Some(name) => (true, name),
+            None => (false, pattern.as_str()),
+        };
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if name.is_empty() || !name.split('.').all(valid_label) {
+            return Err(format!(
+                "`{s}` is not a host name or a `*.` wildcard host name"
+            ));
+        }
+        Ok(match wildcard {
+            true => HostPattern::Wildcard(format!(".{name}")),
+            false => HostPattern::Exact(name.to_string()),
+        })
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(name) => write!(f, "{name}"),
+            HostPattern::Wildcard(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+/// Reduces a `Host` header value to the host name: lowercased, without port or trailing dot.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        // IPv6 literal, keep the brackets and drop the port after them.
+        &host[..=host.find(']')?]
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            Some(_) => return None,
+            None => host,
+        }
+    };
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    match name.is_empty() {
+        true => None,
+        false => Some(name),
+    }
+}
+
+/// Routers of the virtual hosts of a server, keyed by host name.
+#[derive(Clone, Default)]
+pub struct VirtualHosts {
+    exact: HashMap<String, HttpRouter>,
+    /// Sorted by decreasing suffix length so the most specific wildcard wins.
+    wildcards: Vec<(String, HttpRouter)>,
+    strict: bool,
+}
+
+impl VirtualHosts {
+    pub fn new() -> Self {
+        VirtualHosts::default()
+    }
+
+    /// Serves the hosts matching `pattern` with `router`, replacing any router previously
+    /// registered for the same pattern.
+    pub fn add(&mut self, pattern: HostPattern, router: HttpRouter) -> &mut Self {
+        match pattern {
+            HostPattern::Exact(name) => {
+                self.exact.insert(name, router);
+            }
+            HostPattern::Wildcard(suffix) => {
+                self.wildcards.retain(|(s, _)| *s != suffix);
+                self.wildcards.push((suffix, router));
+                self.wildcards
+                    .sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+            }
+        }
+        self
+    }
+
+    /// When strict, requests for unknown hosts are refused instead of served by the default
+    /// router.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Picks the router for `host`: an exact match first, then the longest matching wildcard,
+    /// then `default` unless strict.
+    pub fn select<'a>(
+        &'a self,
+        host: Option<&str>,
+        default: &'a HttpRouter,
+    ) -> Option<&'a HttpRouter> {
+        let found = host.and_then(|host| {
+            self.exact.get(host).or_else(|| {
+                self.wildcards
+                    .iter()
+                    .find(|(suffix, _)| {
+                        host.len() > suffix.len() && host.ends_with(suffix.as_str())
+                    })
+                    .map(|(_, router)| router)
+            })
+        });
+        match (found, self.strict) {
+            (Some(router), _) => Some(router),
+            (None, true) => None,
+            (None, false) => Some(default),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{HttpError, HttpMethod, HttpStatus},
+        request::{HttpRequest, HttpRequestMetaData},
+        router::HttpRouterBuilder,
+    };
+
+    fn router(status: HttpStatus) -> HttpRouter {
+        HttpRouterBuilder::new()
+            .add_route(HttpMethod::GET, "/", move |_, _| {
+                Err(HttpError::new(status, ""))
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn served_by(router: Option<&HttpRouter>) -> Option<HttpStatus> {
+        let r = HttpRequest {
+            metadata: HttpRequestMetaData::parse("GET / HTTP/1.1").unwrap(),
+            body: None,
+            stream: None,
+        };
+        router.map(|router| router.route(&r).err().unwrap().status)
+    }
+
+    #[test]
+    fn test_host_pattern_parse() {
+        assert_eq!(
+            "Example.COM.".parse::<HostPattern>().unwrap(),
+            HostPattern::Exact("example.com".to_string())
+        );
+        assert_eq!(
+            "*.example.com".parse::<HostPattern>().unwrap(),
+            HostPattern::Wildcard(".example.com".to_string())
+        );
+        assert_eq!(
+            "*.example.com".parse::<HostPattern>().unwrap().to_string(),
+            "*.example.com"
+        );
+        for invalid in [
+            "",
+            "*",
+            "*.",
+            "a.*.com",
+            "exa mple.com",
+            "a..b",
+            "example.com:80",
+        ] {
+            assert!(invalid.parse::<HostPattern>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Example.com").unwrap(), "example.com");
+        assert_eq!(normalize_host("example.com.:8080").unwrap(), "example.com");
+        assert_eq!(normalize_host("[::1]:8080").unwrap(), "[::1]");
+        assert_eq!(normalize_host("127.0.0.1").unwrap(), "127.0.0.1");
+        assert!(normalize_host("").is_none());
+        assert!(normalize_host(":80").is_none());
+        assert!(normalize_host("example.com:http").is_none());
+        assert!(normalize_host("[::1").is_none());
+    }
+
+    #[test]
+    fn test_virtual_hosts_select() {
+        let default = router(HttpStatus::ImATeapot);
+        let mut hosts = VirtualHosts::new();
+        hosts
+            .add("example.com".parse().unwrap(), router(HttpStatus::Ok))
+            .add(
+                "*.example.com".parse().unwrap(),
+                router(HttpStatus::Accepted),
+            )
+            .add(
+                "*.api.example.com".parse().unwrap(),
+                router(HttpStatus::Created),
+            )
+            .add(
+                "www.example.com".parse().unwrap(),
+                router(HttpStatus::NoContent),
+            );
+        let select = |host| served_by(hosts.select(host, &default));
+        assert_eq!(select(Some("example.com")), Some(HttpStatus::Ok));
+        assert_eq!(select(Some("www.example.com")), Some(HttpStatus::NoContent));
+        assert_eq!(select(Some("a.b.example.com")), Some(HttpStatus::Accepted));
+        assert_eq!(
+            select(Some("v1.api.example.com")),
+            Some(HttpStatus::Created)
+        );
+        assert_eq!(select(Some("api.example.com")), Some(HttpStatus::Accepted));
+        assert_eq!(select(Some("badexample.com")), Some(HttpStatus::ImATeapot));
+        assert_eq!(select(None), Some(HttpStatus::ImATeapot));
+    }
+
+    #[test]
+    fn test_virtual_hosts_strict() {
+        let default = router(HttpStatus::ImATeapot);
+        let mut hosts = VirtualHosts::new();
+        hosts
+            .add("example.com".parse().unwrap(), router(HttpStatus::Ok))
+            .strict(true);
+        assert_eq!(
+            served_by(hosts.select(Some("example.com"), &default)),
+            Some(HttpStatus::Ok)
+        );
+        assert!(hosts.select(Some("other.com"), &default).is_none());
+        assert!(hosts.select(None, &default).is_none());
+    }
+}